@@ -0,0 +1,173 @@
+#![deny(warnings)]
+
+use bytes::{BufMut, BytesMut};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use tokio_util::codec::{Decoder, Encoder};
+
+use tokio_scgi::client::{SCGICodec as ClientCodec, SCGIRequest as ClientRequest};
+use tokio_scgi::server::{SCGICodec as ServerCodec, SCGIRequest as ServerRequest};
+
+/// Returns headers like those sent by nginx with the default `scgi_params`, for a browser
+/// request: 24 headers of about 1 KiB in total.
+fn nginx_headers(body_len: usize) -> Vec<(String, String)> {
+    [
+        ("CONTENT_LENGTH", body_len.to_string().as_str()),
+        ("SCGI", "1"),
+        ("REQUEST_METHOD", "POST"),
+        ("REQUEST_URI", "/api/v1/orders?page=2&sort=created_at"),
+        ("QUERY_STRING", "page=2&sort=created_at"),
+        ("CONTENT_TYPE", "application/json"),
+        ("DOCUMENT_URI", "/api/v1/orders"),
+        ("DOCUMENT_ROOT", "/var/www/html"),
+        ("SERVER_PROTOCOL", "HTTP/1.1"),
+        ("REQUEST_SCHEME", "https"),
+        ("HTTPS", "on"),
+        ("REMOTE_ADDR", "203.0.113.42"),
+        ("REMOTE_PORT", "51234"),
+        ("SERVER_PORT", "443"),
+        ("SERVER_NAME", "shop.example.com"),
+        ("HTTP_HOST", "shop.example.com"),
+        (
+            "HTTP_USER_AGENT",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0",
+        ),
+        (
+            "HTTP_ACCEPT",
+            "text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8",
+        ),
+        ("HTTP_ACCEPT_LANGUAGE", "en-US,en;q=0.5"),
+        ("HTTP_ACCEPT_ENCODING", "gzip, deflate, br, zstd"),
+        ("HTTP_REFERER", "https://shop.example.com/orders"),
+        (
+            "HTTP_COOKIE",
+            "session=2f9c1e7ab84d4c6e9a0b3d5f7e1c2a4b; theme=dark; consent=analytics%2Cmarketing; \
+             _ga=GA1.1.1234567890.1700000000; cart=8c2d4e6f",
+        ),
+        ("HTTP_X_REQUEST_ID", "6c0f7a9e2b4d4f1a8e3c5b7d9f1a3c5e"),
+        ("HTTP_X_FORWARDED_FOR", "198.51.100.7, 203.0.113.42"),
+    ]
+    .iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect()
+}
+
+fn encode(headers: Vec<(String, String)>, body: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    ClientCodec::new()
+        .encode(ClientRequest::Request(headers, body.into()), &mut buf)
+        .unwrap();
+    buf
+}
+
+/// Decodes a request that's entirely in the buffer, and checks that it was complete.
+fn decode_whole(mut buf: BytesMut) {
+    match ServerCodec::new().decode(&mut buf) {
+        Ok(Some(ServerRequest::Request(_, _))) => {}
+        other => panic!("Unexpected decode result: {:?}", other),
+    }
+}
+
+fn decode(c: &mut Criterion) {
+    let body = br#"{"item":"widget","quantity":3}"#;
+    let nginx = encode(nginx_headers(body.len()), body);
+    // Requests at the maximum header size of 256 KiB, with a few values near the maximum string
+    // size of 32 KiB, or with many tiny headers.
+    let large_values = encode(
+        (0..8)
+            .map(|i| (format!("HTTP_X_LARGE_{}", i), "v".repeat(32 * 1024 - 32)))
+            .collect(),
+        b"",
+    );
+    // A large header set, such as from a gateway which forwards many of its own headers: 128
+    // headers with 128 byte values.
+    let gateway = encode(
+        (0..128)
+            .map(|i| (format!("HTTP_X_GATEWAY_{}", i), "g".repeat(128)))
+            .collect(),
+        b"",
+    );
+    let many_headers = encode(
+        (0..30_000)
+            .map(|i| (format!("H{}", i), "v".to_string()))
+            .collect(),
+        b"",
+    );
+
+    let mut group = c.benchmark_group("decode");
+    for (name, data) in [
+        ("nginx", &nginx),
+        ("gateway_18k", &gateway),
+        ("large_values_256k", &large_values),
+        ("many_headers_256k", &many_headers),
+    ] {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter_batched(|| data.clone(), decode_whole, BatchSize::SmallInput)
+        });
+    }
+
+    // The whole nginx request again, but delivered and decoded one byte at a time.
+    group.throughput(Throughput::Bytes(nginx.len() as u64));
+    group.bench_function("nginx_bytewise", |b| {
+        b.iter(|| {
+            let mut decoder = ServerCodec::new();
+            let mut buf = BytesMut::with_capacity(nginx.len());
+            let mut complete = false;
+            for byte in &nginx {
+                buf.put_u8(*byte);
+                if let Some(ServerRequest::Request(_, _)) = decoder.decode(&mut buf).unwrap() {
+                    complete = true;
+                }
+            }
+            assert!(complete);
+        })
+    });
+
+    // The large header sets delivered in 4 KiB reads, as they might arrive from a socket.
+    for (name, data) in [
+        ("gateway_18k_4k_reads", &gateway),
+        ("large_values_256k_4k_reads", &large_values),
+        ("many_headers_256k_4k_reads", &many_headers),
+    ] {
+        group.throughput(Throughput::Bytes(data.len() as u64));
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                let mut decoder = ServerCodec::new();
+                let mut buf = BytesMut::with_capacity(data.len());
+                let mut complete = false;
+                for chunk in data.chunks(4096) {
+                    buf.put_slice(chunk);
+                    if let Some(ServerRequest::Request(_, _)) = decoder.decode(&mut buf).unwrap() {
+                        complete = true;
+                    }
+                }
+                assert!(complete);
+            })
+        });
+    }
+    group.finish();
+}
+
+fn encode_bodies(c: &mut Criterion) {
+    let mut group = c.benchmark_group("encode");
+    for (name, len) in [("body_64k", 64 * 1024), ("body_1m", 1024 * 1024)] {
+        let body = BytesMut::from(&vec![b'x'; len][..]);
+        let headers = nginx_headers(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || ClientRequest::Request(headers.clone(), body.clone()),
+                |request| {
+                    let mut buf = BytesMut::new();
+                    ClientCodec::new().encode(request, &mut buf).unwrap();
+                    buf
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decode, encode_bodies);
+criterion_main!(benches);
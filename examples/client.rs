@@ -14,7 +14,7 @@ use tokio_util::codec::Framed;
 fn syntax() -> Error {
     println!(
         "Syntax: {} </path/to/unix.sock or tcp-host:1234>",
-        env::args().nth(0).unwrap()
+        env::args().next().unwrap()
     );
     Error::new(ErrorKind::InvalidInput, "Missing required argument")
 }
@@ -39,7 +39,7 @@ async fn main() -> Result<(), Error> {
         // Probably a TCP endpoint, try to resolve it in case it's a hostname
         let addr = endpoint
             .to_socket_addrs()
-            .expect(format!("Invalid TCP endpoint '{}'", endpoint).as_str())
+            .unwrap_or_else(|_| panic!("Invalid TCP endpoint '{}'", endpoint))
             .next()
             .unwrap();
         println!("Connecting to {}", addr);
@@ -58,27 +58,22 @@ where
     // Send request
     framed.send(build_request()).await?;
 
-    // Consume response(s): loop until error or empty data returned
+    // Consume response(s): loop until error or the server closes the connection
     loop {
         match framed.next().await {
             None => {
-                // SCGI response not ready: loop for more rx data
-                // Shouldn't happen for response data, but this is how it would work...
-                println!("Response data is incomplete, resuming read");
+                // Server closed the connection: end of response.
+                return Ok(());
             }
             Some(Err(e)) => {
                 // RX error: return error and abort
-                return Err(Error::new(
-                    ErrorKind::Other,
-                    format!("Error when waiting for response: {}", e),
-                ));
+                return Err(Error::other(format!(
+                    "Error when waiting for response: {}",
+                    e
+                )));
             }
             Some(Ok(response)) => {
-                // Got SCGI response: if empty, treat as end of response.
-                if response.len() == 0 {
-                    return Ok(());
-                }
-                // Otherwise 'handle' by printing content, then resume read for more
+                // Got SCGI response: 'handle' by printing content, then resume read for more
                 match String::from_utf8(response.to_vec()) {
                     Ok(s) => println!("Got {} bytes:\n{}", response.len(), s),
                     Err(e) => println!(
@@ -98,11 +93,13 @@ fn build_request() -> SCGIRequest {
     let mut content = BytesMut::with_capacity(content_str.len());
     content.put_slice(content_str);
 
-    let mut headers = Vec::new();
-    headers.push(("Content-Length".to_string(), content_str.len().to_string()));
-    headers.push(("SCGI".to_string(), "1".to_string()));
-    headers.push(("Content-Type".to_string(), "application/json".to_string()));
-    headers.push(("X-Username".to_string(), "bort".to_string()));
+    // Per the SCGI spec, CONTENT_LENGTH must be the first header and SCGI must be present.
+    let headers = vec![
+        ("CONTENT_LENGTH".to_string(), content_str.len().to_string()),
+        ("SCGI".to_string(), "1".to_string()),
+        ("CONTENT_TYPE".to_string(), "application/json".to_string()),
+        ("HTTP_X_USERNAME".to_string(), "bort".to_string()),
+    ];
 
     SCGIRequest::Request(headers, content)
 }
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio_scgi::traffic::Schedule;
+
+// Arbitrary bytes must never panic the decoder, and the outcome mustn't depend on how the bytes
+// are split into reads.
+fuzz_target!(|input: (Vec<u8>, Schedule)| {
+    let (data, schedule) = input;
+    let whole = tokio_scgi_fuzz::decode(&data, &Schedule::default());
+    let split = tokio_scgi_fuzz::decode(&data, &schedule);
+    assert_eq!(whole, split);
+});
@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio_scgi::server::SCGIErrorKind;
+use tokio_scgi::traffic::{NearValidRequest, Schedule};
+
+// Mutated requests must fail with the mutation's expected error, and must never decode to the
+// original request.
+fuzz_target!(|input: (NearValidRequest, Schedule)| {
+    let (request, schedule) = input;
+    let result = tokio_scgi_fuzz::decode(&request.encode(), &schedule);
+    match request.mutation.expected_error() {
+        // Only the runtime checks CONTENT_LENGTH.
+        Some(SCGIErrorKind::InvalidContentLength) => assert!(result.is_ok()),
+        Some(kind) => assert_eq!(Err(kind), result),
+        None => {
+            let original = (
+                request.request.headers().to_vec(),
+                request.request.body().to_vec(),
+            );
+            assert_ne!(Ok(Some(original)), result);
+        }
+    }
+});
@@ -0,0 +1,39 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_scgi::client::{SCGICodec, SCGIRequest};
+use tokio_scgi::server::SCGIErrorKind;
+use tokio_scgi::traffic::Schedule;
+use tokio_util::codec::Encoder;
+
+// Anything that `client::SCGICodec` encodes must decode to the same headers and body, whether the
+// body is sent with the headers or as a separate fragment.
+fuzz_target!(|input: (Vec<(String, String)>, Vec<u8>, usize, Schedule)| {
+    let (headers, body, body_split, schedule) = input;
+    let (first, second) = body.split_at(body_split % (body.len() + 1));
+    let mut encoder = SCGICodec::new();
+    let mut buf = BytesMut::new();
+    if encoder
+        .encode(
+            SCGIRequest::Request(headers.clone(), first.into()),
+            &mut buf,
+        )
+        .is_err()
+    {
+        assert!(headers
+            .iter()
+            .any(|(k, v)| k.is_empty() || k.contains('\0') || v.contains('\0')));
+        return;
+    }
+    encoder
+        .encode(SCGIRequest::BodyFragment(second.into()), &mut buf)
+        .unwrap();
+
+    match tokio_scgi_fuzz::decode(&buf, &schedule) {
+        Ok(decoded) => assert_eq!(Some((headers, body)), decoded),
+        // Encoded fine, but beyond the decoder's size limits.
+        Err(SCGIErrorKind::HeaderTooLarge) | Err(SCGIErrorKind::HeaderStringTooLarge) => {}
+        Err(kind) => panic!("Round trip failed: {:?}", kind),
+    }
+});
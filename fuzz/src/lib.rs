@@ -0,0 +1,45 @@
+#![deny(warnings)]
+
+use bytes::BytesMut;
+use tokio_util::codec::Decoder;
+
+use tokio_scgi::server::{SCGICodec, SCGIError, SCGIErrorKind, SCGIRequest};
+use tokio_scgi::traffic::Schedule;
+
+/// The headers and body of a decoded request.
+pub type Decoded = (Vec<(String, String)>, Vec<u8>);
+
+/// Feeds the data into `server::SCGICodec` in chunks according to the schedule, decoding after
+/// each chunk until the decoder needs more data. Returns the headers and body, `None` if the
+/// headers weren't complete, or the kind of the first error. Panics if the decoder returns
+/// headers more than once, or returns an error that isn't an `SCGIError`.
+pub fn decode(data: &[u8], schedule: &Schedule) -> Result<Option<Decoded>, SCGIErrorKind> {
+    let mut decoder = SCGICodec::new();
+    let mut buf = BytesMut::new();
+    let mut headers = None;
+    let mut body = Vec::new();
+    for chunk in schedule.chunks(data) {
+        buf.extend_from_slice(chunk);
+        loop {
+            match decoder.decode(&mut buf) {
+                Ok(Some(SCGIRequest::Request(h, b))) => {
+                    assert!(headers.is_none(), "Headers decoded twice");
+                    headers = Some(h);
+                    body.extend_from_slice(&b);
+                }
+                Ok(Some(SCGIRequest::BodyFragment(b))) => {
+                    assert!(headers.is_some(), "Body decoded before headers");
+                    assert!(!b.is_empty(), "Empty body fragment");
+                    body.extend_from_slice(&b);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    return Err(SCGIError::from_io(&e)
+                        .unwrap_or_else(|| panic!("Decode error without a kind: {}", e))
+                        .kind())
+                }
+            }
+        }
+    }
+    Ok(headers.map(|headers| (headers, body)))
+}
@@ -0,0 +1,383 @@
+#![deny(warnings)]
+
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+#[cfg(unix)]
+use tokio::task::JoinHandle;
+
+use crate::runtime::{self, Handler, HandlerFuture, Request};
+use crate::writer_thread::WriterThread;
+
+/// The format of access log lines.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// NCSA Combined Log Format, as used by Apache and NGINX:
+    /// `host - user [time] "method uri protocol" status bytes "referer" "user-agent"`
+    Combined,
+
+    /// One JSON object per line, with the same fields as `Combined` plus `request_id` and
+    /// `duration_ms`. Missing fields are `null`.
+    Json,
+}
+
+/// Where access log lines are written. Shared between an `AccessLog` and anything which reopens
+/// the log, such as `reopen_on_sighup()`.
+///
+/// Lines are written by a dedicated thread, so that slow disks don't hold up request handling.
+/// If the thread falls more than `LOG_QUEUE` lines behind, further lines are dropped with a
+/// warning until it catches up.
+pub struct LogSink {
+    /// The path of the log file, for sinks created with `file()`.
+    path: Option<PathBuf>,
+    writer: WriterThread<Target>,
+}
+
+/// The number of lines which may wait for the writer thread before further lines are dropped.
+const LOG_QUEUE: usize = 1024;
+
+enum Target {
+    Writer(Box<dyn Write + Send>),
+    File(PathBuf, File),
+}
+
+impl Write for Target {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Target::Writer(writer) => writer.write(buf),
+            Target::File(_, file) => file.write(buf),
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Target::Writer(writer) => writer.write_all(buf),
+            Target::File(_, file) => file.write_all(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Target::Writer(writer) => writer.flush(),
+            Target::File(_, file) => file.flush(),
+        }
+    }
+}
+
+impl LogSink {
+    /// Returns a `LogSink` which writes to the provided writer, for example `io::stdout()`. Each
+    /// line is written with a single `write_all()` call. Fails if the writer thread can't be
+    /// started.
+    pub fn writer<W: Write + Send + 'static>(writer: W) -> Result<LogSink, io::Error> {
+        LogSink::spawn(None, Target::Writer(Box::new(writer)))
+    }
+
+    /// Returns a `LogSink` which appends to the file at the provided path, creating it if needed.
+    /// The file can be rotated by renaming it and then calling `reopen()`.
+    pub fn file<P: AsRef<Path>>(path: P) -> Result<LogSink, io::Error> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path)?;
+        LogSink::spawn(Some(path.clone()), Target::File(path, file))
+    }
+
+    fn spawn(path: Option<PathBuf>, target: Target) -> Result<LogSink, io::Error> {
+        Ok(LogSink {
+            path,
+            writer: WriterThread::spawn("scgi-access-log", target, LOG_QUEUE)?,
+        })
+    }
+
+    /// Reopens the log file at its original path, so that new lines go to a new file after the
+    /// previous one has been renamed by a log rotation tool. Lines queued before the call are
+    /// written to the previous file. If reopening fails, lines continue to go to the previous
+    /// file. Does nothing for sinks created with `writer()`.
+    ///
+    /// Blocks until the writer thread has reopened the file, so async code should call this via
+    /// `tokio::task::spawn_blocking()`.
+    pub fn reopen(&self) -> Result<(), io::Error> {
+        self.writer.call(|target| {
+            if let Target::File(path, file) = target {
+                *file = open_append(path)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Waits for the lines logged so far to be written and flushed, and returns the first error
+    /// writing them since the last call. Blocks the calling thread.
+    pub fn flush(&self) -> Result<(), io::Error> {
+        self.writer.flush()
+    }
+
+    fn write_line(&self, line: String) -> Result<(), io::Error> {
+        self.writer.write(line.into_bytes())
+    }
+}
+
+impl std::fmt::Debug for LogSink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.path {
+            None => f.write_str("LogSink::Writer"),
+            Some(path) => write!(f, "LogSink::File({:?})", path),
+        }
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, io::Error> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Starts a task which calls `reopen()` on the sink whenever the process receives `SIGHUP`, which
+/// is how tools like logrotate ask services to reopen their logs. Must be called from within a
+/// Tokio runtime. Only available on Unix platforms.
+#[cfg(unix)]
+pub fn reopen_on_sighup(sink: Arc<LogSink>) -> Result<JoinHandle<()>, io::Error> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    Ok(tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            let reopen = sink.clone();
+            let result = tokio::task::spawn_blocking(move || reopen.reopen())
+                .await
+                .map_err(io::Error::from)
+                .and_then(|result| result);
+            if let Err(_e) = result {
+                event!(error, error = %_e, "Failed to reopen access log");
+            }
+        }
+    }))
+}
+
+/// A `Handler` which writes an access log line for each request that it passes to the wrapped
+/// handler. The line includes the end user's details from the request headers provided by the
+/// frontend web server, along with the status and body size of the response. Requests which are
+/// rejected before reaching the handler, such as malformed or timed out requests, aren't logged.
+#[derive(Debug)]
+pub struct AccessLog<H> {
+    handler: H,
+    format: LogFormat,
+    sink: Arc<LogSink>,
+}
+
+impl<H: Handler> AccessLog<H> {
+    /// Returns an `AccessLog` which passes requests to `handler` and logs them to `sink`.
+    pub fn new(handler: H, format: LogFormat, sink: Arc<LogSink>) -> AccessLog<H> {
+        AccessLog {
+            handler,
+            format,
+            sink,
+        }
+    }
+}
+
+impl<H: Handler> Handler for AccessLog<H> {
+    fn call(&self, request: Request) -> HandlerFuture {
+        let entry = Entry::new(&request);
+        let response = self.handler.call(request);
+        let format = self.format;
+        let sink = self.sink.clone();
+        Box::pin(async move {
+            let result = response.await;
+            let (status, bytes) = match &result {
+                Ok(response) => (runtime::response_status(response), Some(body_len(response))),
+                // The runtime responds to handler errors with a 500.
+                Err(_) => (500, None),
+            };
+            let line = match format {
+                LogFormat::Combined => entry.combined(status, bytes),
+                LogFormat::Json => entry.json(status, bytes),
+            };
+            // A broken or slow log shouldn't break requests. Write errors are reported by the
+            // writer thread.
+            if let Err(_e) = sink.write_line(line) {
+                event!(warn, error = %_e, "Failed to write access log");
+            }
+            result
+        })
+    }
+}
+
+/// The details of a request which are logged once its response is ready.
+struct Entry {
+    received: SystemTime,
+    started: Instant,
+    remote_addr: Option<String>,
+    remote_user: Option<String>,
+    method: Option<String>,
+    uri: Option<String>,
+    protocol: Option<String>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+}
+
+impl Entry {
+    fn new(request: &Request) -> Entry {
+        let header = |key| request.header(key).map(str::to_string);
+        Entry {
+            received: SystemTime::now(),
+            started: Instant::now(),
+            remote_addr: header("REMOTE_ADDR"),
+            remote_user: header("REMOTE_USER").filter(|user| !user.is_empty()),
+            method: header("REQUEST_METHOD"),
+            uri: header("REQUEST_URI"),
+            protocol: header("SERVER_PROTOCOL"),
+            referer: header("HTTP_REFERER"),
+            user_agent: header("HTTP_USER_AGENT"),
+            request_id: request.request_id.clone(),
+        }
+    }
+
+    fn combined(&self, status: u16, bytes: Option<usize>) -> String {
+        let (year, month, day, hour, min, sec) = civil_time(self.received);
+        let request_line = [&self.method, &self.uri, &self.protocol]
+            .iter()
+            .filter_map(|field| field.as_deref())
+            .collect::<Vec<_>>()
+            .join(" ");
+        let request_line = if request_line.is_empty() {
+            "-".to_string()
+        } else {
+            request_line
+        };
+        format!(
+            "{} - {} [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{}\" {} {} \"{}\" \"{}\"\n",
+            clf_escape(self.remote_addr.as_deref().unwrap_or("-")),
+            clf_escape(self.remote_user.as_deref().unwrap_or("-")),
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            hour,
+            min,
+            sec,
+            clf_escape(&request_line),
+            status,
+            bytes.map_or("-".to_string(), |b| b.to_string()),
+            clf_escape(self.referer.as_deref().unwrap_or("-")),
+            clf_escape(self.user_agent.as_deref().unwrap_or("-")),
+        )
+    }
+
+    fn json(&self, status: u16, bytes: Option<usize>) -> String {
+        let (year, month, day, hour, min, sec) = civil_time(self.received);
+        let mut line = format!(
+            "{{\"time\":\"{}-{:02}-{:02}T{:02}:{:02}:{:02}Z\"",
+            year, month, day, hour, min, sec
+        );
+        let fields = [
+            ("remote_addr", &self.remote_addr),
+            ("remote_user", &self.remote_user),
+            ("method", &self.method),
+            ("uri", &self.uri),
+            ("protocol", &self.protocol),
+            ("referer", &self.referer),
+            ("user_agent", &self.user_agent),
+            ("request_id", &self.request_id),
+        ];
+        for (key, value) in fields.iter() {
+            match value {
+                Some(value) => write!(line, ",\"{}\":\"{}\"", key, json_escape(value)),
+                None => write!(line, ",\"{}\":null", key),
+            }
+            .expect("Writing to a String can't fail");
+        }
+        writeln!(
+            line,
+            ",\"status\":{},\"bytes\":{},\"duration_ms\":{}}}",
+            status,
+            bytes.map_or("null".to_string(), |b| b.to_string()),
+            self.started.elapsed().as_millis()
+        )
+        .expect("Writing to a String can't fail");
+        line
+    }
+}
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Returns the UTC (year, month, day, hour, minute, second) of the provided time.
+fn civil_time(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Converts days since 1970-01-01 to a date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (
+        year,
+        month,
+        day,
+        (secs_of_day / 3600) as u32,
+        (secs_of_day / 60 % 60) as u32,
+        (secs_of_day % 60) as u32,
+    )
+}
+
+/// Returns the size of the response body, after the CGI or HTTP headers. If the response doesn't
+/// have a blank line ending the headers, then it's all counted as body.
+pub(crate) fn body_len(response: &[u8]) -> usize {
+    let crlf = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| i + 4);
+    let lf = response
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map(|i| i + 2);
+    let header_len = match (crlf, lf) {
+        (Some(crlf), Some(lf)) => crlf.min(lf),
+        (crlf, lf) => crlf.or(lf).unwrap_or(0),
+    };
+    response.len() - header_len
+}
+
+/// Escapes a value for a quoted Combined Log Format field in the same way as Apache: quotes and
+/// backslashes are backslash-escaped, and non-printable bytes are written as `\xHH`.
+fn clf_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for b in value.bytes() {
+        match b {
+            b'"' | b'\\' => {
+                escaped.push('\\');
+                escaped.push(b as char);
+            }
+            b' '..=b'~' => escaped.push(b as char),
+            _ => write!(escaped, "\\x{:02x}", b).expect("Writing to a String can't fail"),
+        }
+    }
+    escaped
+}
+
+/// Escapes a value for a JSON string.
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(escaped, "\\u{:04x}", c as u32).expect("Writing to a String can't fail")
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
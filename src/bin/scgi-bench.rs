@@ -0,0 +1,492 @@
+#![deny(warnings)]
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{Error, ErrorKind};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use tokio_scgi::client::{SCGICodec, SCGIRequest};
+use tokio_scgi::listener::Address;
+use tokio_util::codec::Framed;
+
+const USAGE: &str = "\
+Usage: scgi-bench [options] <host:port>
+       scgi-bench [options] --unix <path>
+
+Sends SCGI requests to a backend from many concurrent connections, then reports
+latency percentiles, throughput, response statuses and errors. Each request uses
+its own connection, as SCGI servers close the connection after responding.
+
+Options:
+  --unix PATH               Connects to the Unix socket at PATH instead of host:port
+  -c, --connections N       Number of concurrent connections (default 10)
+  -t, --threads N           Number of threads (default: number of CPUs, at most N
+                            connections)
+  -n, --requests N          Stops after N requests
+  -d, --duration TIME       Stops after TIME, like 30s, 500ms or 2m (default 10s,
+                            unless --requests is set)
+  --timeout TIME            Counts requests as failed after TIME (default 30s)
+  -X, --request METHOD      Sets REQUEST_METHOD (default GET, or POST with a body)
+  -u, --uri TEMPLATE        Adds a REQUEST_URI template. Requests cycle through the
+                            templates, replacing {n} with the request number and
+                            {rand} with a random number (default /)
+  -b, --body-size N[-M]     Sends a body of N bytes, or between N and M bytes
+  --headers N[-M]           Adds N, or between N and M, HTTP_X_BENCH_* headers
+  -H, --header 'NAME: VAL'  Adds a request header, sent as HTTP_NAME
+  -h, --help                Shows this help";
+
+fn syntax(msg: &str) -> Error {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    Error::new(ErrorKind::InvalidInput, msg.to_string())
+}
+
+/// What to send, and for how long.
+struct Plan {
+    target: Address,
+    connections: usize,
+    threads: usize,
+    requests: Option<u64>,
+    duration: Option<Duration>,
+    timeout: Duration,
+    method: Option<String>,
+    uris: Vec<String>,
+    body_size: (usize, usize),
+    extra_headers: (usize, usize),
+    headers: Vec<(String, String)>,
+}
+
+/// What happened, for one thread or for the whole run.
+#[derive(Default)]
+struct Stats {
+    latencies_us: Vec<u64>,
+    statuses: BTreeMap<u16, u64>,
+    /// Failed requests by what failed, such as `"connect: Connection refused"`.
+    errors: BTreeMap<String, u64>,
+    connect_errors: u64,
+    sent_bytes: u64,
+    received_bytes: u64,
+}
+
+impl Stats {
+    fn merge(&mut self, other: Stats) {
+        self.latencies_us.extend(other.latencies_us);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        for (error, count) in other.errors {
+            *self.errors.entry(error).or_default() += count;
+        }
+        self.connect_errors += other.connect_errors;
+        self.sent_bytes += other.sent_bytes;
+        self.received_bytes += other.received_bytes;
+    }
+
+    fn error(&mut self, phase: &str, e: &Error) {
+        *self.errors.entry(format!("{}: {}", phase, e)).or_default() += 1;
+    }
+}
+
+fn main() -> Result<(), Error> {
+    let plan = Arc::new(parse_args(env::args().skip(1))?);
+    println!(
+        "Benchmarking {} with {} connections on {} threads for {}",
+        plan.target,
+        plan.connections,
+        plan.threads,
+        match (plan.requests, plan.duration) {
+            (Some(requests), Some(duration)) => format!("{} requests or {:?}", requests, duration),
+            (Some(requests), None) => format!("{} requests", requests),
+            (None, Some(duration)) => format!("{:?}", duration),
+            (None, None) => unreachable!("Set by parse_args"),
+        }
+    );
+
+    let issued = Arc::new(AtomicU64::new(0));
+    let started = Instant::now();
+    let deadline = plan.duration.map(|duration| started + duration);
+    let threads = (0..plan.threads)
+        .map(|i| {
+            // Spread the connections as evenly as possible across the threads.
+            let connections =
+                plan.connections / plan.threads + usize::from(i < plan.connections % plan.threads);
+            let plan = plan.clone();
+            let issued = issued.clone();
+            thread::spawn(move || -> Result<Stats, Error> {
+                let rt = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?;
+                Ok(rt.block_on(run_thread(&plan, connections, &issued, deadline)))
+            })
+        })
+        .collect::<Vec<_>>();
+    let mut stats = Stats::default();
+    for thread in threads {
+        stats.merge(thread.join().expect("Benchmark thread panicked")?);
+    }
+    report(&stats, started.elapsed());
+    Ok(())
+}
+
+async fn run_thread(
+    plan: &Plan,
+    connections: usize,
+    issued: &AtomicU64,
+    deadline: Option<Instant>,
+) -> Stats {
+    let workers = (0..connections).map(|_| async {
+        let mut stats = Stats::default();
+        let mut rng = Rng::new();
+        loop {
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                break;
+            }
+            let n = issued.fetch_add(1, Ordering::Relaxed);
+            if plan.requests.is_some_and(|requests| n >= requests) {
+                break;
+            }
+            let request = build_request(plan, n, &mut rng);
+            let started = Instant::now();
+            // Stop waiting at the deadline rather than running over, and don't count the request.
+            let remaining = deadline.map_or(plan.timeout, |deadline| {
+                deadline
+                    .saturating_duration_since(started)
+                    .min(plan.timeout)
+            });
+            match time::timeout(remaining, send(&plan.target, request, &mut stats)).await {
+                Ok(Ok(response)) => {
+                    stats
+                        .latencies_us
+                        .push(started.elapsed().as_micros() as u64);
+                    *stats
+                        .statuses
+                        .entry(response_status(&response))
+                        .or_default() += 1;
+                }
+                Ok(Err(_)) => {}
+                Err(_) if remaining < plan.timeout => break,
+                Err(_) => stats.error("response", &Error::new(ErrorKind::TimedOut, "Timed out")),
+            }
+        }
+        stats
+    });
+    let mut stats = Stats::default();
+    for worker in futures::future::join_all(workers).await {
+        stats.merge(worker);
+    }
+    stats
+}
+
+/// Sends a request on a new connection and returns the response. Errors are counted in `stats`.
+async fn send(target: &Address, request: SCGIRequest, stats: &mut Stats) -> Result<Vec<u8>, Error> {
+    let conn = target.connect().await.inspect_err(|e| {
+        stats.connect_errors += 1;
+        stats.error("connect", e);
+    })?;
+    let mut framed = Framed::new(conn, SCGICodec::new());
+    framed
+        .feed(request)
+        .await
+        .inspect_err(|e| stats.error("send", e))?;
+    stats.sent_bytes += framed.write_buffer().len() as u64;
+    framed
+        .flush()
+        .await
+        .inspect_err(|e| stats.error("send", e))?;
+    let mut response = Vec::new();
+    while let Some(chunk) = framed.next().await {
+        let chunk = chunk.inspect_err(|e| stats.error("receive", e))?;
+        stats.received_bytes += chunk.len() as u64;
+        response.extend_from_slice(&chunk);
+    }
+    if response.is_empty() {
+        let e = Error::new(
+            ErrorKind::UnexpectedEof,
+            "Connection closed without a response",
+        );
+        stats.error("receive", &e);
+        return Err(e);
+    }
+    Ok(response)
+}
+
+fn build_request(plan: &Plan, n: u64, rng: &mut Rng) -> SCGIRequest {
+    let body_len = rng.between(plan.body_size);
+    let template = &plan.uris[(n % plan.uris.len() as u64) as usize];
+    let uri = template
+        .replace("{n}", &n.to_string())
+        .replace("{rand}", &rng.next().to_string());
+    let (path, query) = uri.split_once('?').unwrap_or((&uri, ""));
+    let method = plan
+        .method
+        .clone()
+        .unwrap_or_else(|| if body_len > 0 { "POST" } else { "GET" }.to_string());
+    let mut headers = vec![
+        ("CONTENT_LENGTH".to_string(), body_len.to_string()),
+        ("SCGI".to_string(), "1".to_string()),
+        ("REQUEST_METHOD".to_string(), method),
+        ("SERVER_PROTOCOL".to_string(), "HTTP/1.1".to_string()),
+        ("PATH_INFO".to_string(), path.to_string()),
+        ("QUERY_STRING".to_string(), query.to_string()),
+        ("REQUEST_URI".to_string(), uri.clone()),
+    ];
+    headers.extend(plan.headers.iter().cloned());
+    for i in 0..rng.between(plan.extra_headers) {
+        headers.push((format!("HTTP_X_BENCH_{}", i), format!("value-{}", i)));
+    }
+    SCGIRequest::Request(headers, BytesMut::from(&vec![b'x'; body_len][..]))
+}
+
+fn report(stats: &Stats, elapsed: Duration) {
+    let completed = stats.latencies_us.len() as u64;
+    let failed: u64 = stats.errors.values().sum();
+    let secs = elapsed.as_secs_f64();
+    println!(
+        "Requests:    {} completed, {} failed in {:.2}s, {:.1} req/s",
+        completed,
+        failed,
+        secs,
+        completed as f64 / secs
+    );
+    println!(
+        "Transfer:    {} sent, {} received, {}/s received",
+        bytes(stats.sent_bytes as f64),
+        bytes(stats.received_bytes as f64),
+        bytes(stats.received_bytes as f64 / secs)
+    );
+    let mut latencies = stats.latencies_us.clone();
+    latencies.sort_unstable();
+    if !latencies.is_empty() {
+        let percentile = |q: f64| latencies[((latencies.len() - 1) as f64 * q).round() as usize];
+        println!(
+            "Latency:     min {}  p50 {}  p90 {}  p99 {}  p99.9 {}  max {}",
+            millis(latencies[0]),
+            millis(percentile(0.5)),
+            millis(percentile(0.9)),
+            millis(percentile(0.99)),
+            millis(percentile(0.999)),
+            millis(latencies[latencies.len() - 1])
+        );
+    }
+    if !stats.statuses.is_empty() {
+        let statuses = stats
+            .statuses
+            .iter()
+            .map(|(status, count)| format!("{}: {}", status, count))
+            .collect::<Vec<_>>();
+        println!("Statuses:    {}", statuses.join(", "));
+    }
+    println!("Conn errors: {}", stats.connect_errors);
+    for (error, count) in &stats.errors {
+        println!("  {:>8}  {}", count, error);
+    }
+}
+
+/// Returns the status code from the response's `Status:` header or HTTP status line, or 200 if
+/// there's neither, as CGI assumes.
+fn response_status(response: &[u8]) -> u16 {
+    let response = String::from_utf8_lossy(response);
+    // `lines()` also strips the '\r' from "\r\n" line endings.
+    for (i, line) in response.lines().enumerate() {
+        if line.is_empty() {
+            // End of the headers.
+            break;
+        }
+        let code = if i == 0 && line.starts_with("HTTP/") {
+            // "HTTP/1.1 404 Not Found"
+            line.split(' ').nth(1)
+        } else {
+            // "Status: 404 Not Found"
+            line.split_once(':')
+                .filter(|(name, _)| name.eq_ignore_ascii_case("status"))
+                .and_then(|(_, value)| value.split_whitespace().next())
+        };
+        if let Some(code) = code.and_then(|code| code.parse().ok()) {
+            return code;
+        }
+    }
+    200
+}
+
+fn millis(us: u64) -> String {
+    format!("{:.2}ms", us as f64 / 1000.0)
+}
+
+fn bytes(n: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB"];
+    let mut n = n;
+    let mut unit = 0;
+    while n >= 1024.0 && unit < units.len() - 1 {
+        n /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", n, units[unit])
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Plan, Error> {
+    let mut positional = Vec::new();
+    let mut unix = None;
+    let mut connections = 10;
+    let mut threads = None;
+    let mut requests = None;
+    let mut duration = None;
+    let mut timeout = Duration::from_secs(30);
+    let mut method = None;
+    let mut uris = Vec::new();
+    let mut body_size = (0, 0);
+    let mut extra_headers = (0, 0);
+    let mut headers = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| syntax(&format!("Missing value for {}", name)))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "--unix" => unix = Some(PathBuf::from(value(&arg)?)),
+            "-c" | "--connections" => connections = parse_count(&arg, &value(&arg)?)?,
+            "-t" | "--threads" => threads = Some(parse_count(&arg, &value(&arg)?)?),
+            "-n" | "--requests" => requests = Some(parse_count(&arg, &value(&arg)?)? as u64),
+            "-d" | "--duration" => {
+                duration =
+                    Some(parse_duration(&value(&arg)?).ok_or_else(|| syntax("Invalid duration"))?)
+            }
+            "--timeout" => {
+                timeout = parse_duration(&value(&arg)?).ok_or_else(|| syntax("Invalid timeout"))?
+            }
+            "-X" | "--request" => method = Some(value(&arg)?),
+            "-u" | "--uri" => {
+                let uri = value(&arg)?;
+                if !uri.starts_with('/') {
+                    return Err(syntax(&format!("Invalid URI template '{}'", uri)));
+                }
+                uris.push(uri);
+            }
+            "-b" | "--body-size" => {
+                body_size = parse_range(&value(&arg)?).ok_or_else(|| syntax("Invalid body size"))?
+            }
+            "--headers" => {
+                extra_headers =
+                    parse_range(&value(&arg)?).ok_or_else(|| syntax("Invalid header count"))?
+            }
+            "-H" | "--header" => {
+                let header = value(&arg)?;
+                match header.split_once(':') {
+                    Some((name, val)) if !name.trim().is_empty() => headers.push((
+                        format!(
+                            "HTTP_{}",
+                            name.trim().to_ascii_uppercase().replace('-', "_")
+                        ),
+                        val.trim().to_string(),
+                    )),
+                    _ => return Err(syntax(&format!("Invalid header '{}'", header))),
+                }
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(syntax(&format!("Unknown option '{}'", arg)))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let target = match (unix, &positional[..]) {
+        (Some(path), []) => Address::Unix(path),
+        (None, [target]) => target.parse()?,
+        _ => return Err(syntax("Expected a single target")),
+    };
+    if uris.is_empty() {
+        uris.push("/".to_string());
+    }
+    if requests.is_none() && duration.is_none() {
+        duration = Some(Duration::from_secs(10));
+    }
+    let threads = threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get))
+        .min(connections);
+    Ok(Plan {
+        target,
+        connections,
+        threads,
+        requests,
+        duration,
+        timeout,
+        method,
+        uris,
+        body_size,
+        extra_headers,
+        headers,
+    })
+}
+
+/// Parses a count of at least one.
+fn parse_count(name: &str, value: &str) -> Result<usize, Error> {
+    value
+        .parse::<NonZeroUsize>()
+        .map(NonZeroUsize::get)
+        .map_err(|_| syntax(&format!("Invalid value for {}", name)))
+}
+
+/// Parses `N` or `N-M` into an inclusive range.
+fn parse_range(s: &str) -> Option<(usize, usize)> {
+    let (min, max) = s.split_once('-').unwrap_or((s, s));
+    let (min, max) = (min.parse().ok()?, max.parse().ok()?);
+    if min > max {
+        return None;
+    }
+    Some((min, max))
+}
+
+/// Parses a duration like `30s`, `500ms` or `2m`. Plain numbers are seconds.
+fn parse_duration(s: &str) -> Option<Duration> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let number: f64 = number.parse().ok()?;
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        "m" => number * 60.0,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(secs)
+        .ok()
+        .filter(|d| !d.is_zero())
+}
+
+/// A xorshift generator for request templates. Not for anything which needs real randomness.
+struct Rng(u64);
+
+impl Rng {
+    fn new() -> Rng {
+        static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64)
+            ^ SEQUENCE.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed);
+        Rng(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Returns a number in the inclusive range.
+    fn between(&mut self, (min, max): (usize, usize)) -> usize {
+        if min == max {
+            return min;
+        }
+        min + (self.next() % (max - min + 1) as u64) as usize
+    }
+}
@@ -0,0 +1,320 @@
+#![deny(warnings)]
+
+use bytes::BytesMut;
+use std::env;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Error, ErrorKind, Read, Write};
+use std::path::PathBuf;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_scgi::client::{SCGICodec, SCGIRequest};
+use tokio_scgi::listener::Address;
+use tokio_util::codec::Encoder;
+
+const USAGE: &str = "\
+Usage: scgi-curl [options] <host:port> <url>
+       scgi-curl [options] --unix <path> <url>
+
+Sends a single SCGI request and prints the response. The URL may be a path like
+/search?q=x, or a full URL like https://example.com/search?q=x which also sets the
+host, port and scheme headers.
+
+Options:
+  --unix PATH               Connects to the Unix socket at PATH instead of host:port
+  -X, --request METHOD      Sets REQUEST_METHOD (default GET, or POST with a body)
+  -H, --header 'NAME: VAL'  Adds a request header, sent as HTTP_NAME
+  -e, --env KEY=VAL         Sets a raw SCGI header, replacing any existing value
+  -d, --data DATA           Adds form data to the body, joined with '&'. @FILE reads
+                            FILE without its newlines, @- reads stdin
+  --data-binary DATA        Adds DATA to the body as-is. @FILE reads FILE
+  --raw                     Writes the exact request sent to stderr, and the response
+                            to stdout without parsing it
+  -v, --verbose             Describes the connection and the request netstring, with
+                            escapes for unprintable bytes, on stderr
+  -h, --help                Shows this help";
+
+fn syntax(msg: &str) -> Error {
+    eprintln!("{}\n\n{}", msg, USAGE);
+    Error::new(ErrorKind::InvalidInput, msg.to_string())
+}
+
+#[derive(Default)]
+struct Options {
+    target: Option<Address>,
+    url: String,
+    method: Option<String>,
+    headers: Vec<(String, String)>,
+    env: Vec<(String, String)>,
+    body: Option<Vec<u8>>,
+    form: bool,
+    raw: bool,
+    verbose: bool,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<(), Error> {
+    let options = parse_args(env::args().skip(1))?;
+    let target = options.target.clone().expect("Checked by parse_args");
+    let body = options.body.clone().unwrap_or_default();
+    let headers = build_headers(&options, body.len())?;
+
+    let mut request = BytesMut::new();
+    SCGICodec::new().encode(
+        SCGIRequest::Request(headers, BytesMut::from(&body[..])),
+        &mut request,
+    )?;
+    if options.verbose {
+        eprintln!("* Connecting to {}", target);
+    }
+    let mut conn = target.connect().await?;
+    if options.raw {
+        io::stderr().write_all(&request)?;
+    } else if options.verbose {
+        let netstring_len = request.len() - body.len();
+        eprintln!("> {}", escape(&request[..netstring_len]));
+        eprintln!("> ({} body bytes)", body.len());
+    }
+    conn.write_all(&request).await?;
+
+    let mut response = Vec::new();
+    conn.read_to_end(&mut response).await?;
+    if options.verbose {
+        eprintln!("* Received {} bytes", response.len());
+    }
+    let mut stdout = io::stdout();
+    if options.raw {
+        stdout.write_all(&response)?;
+    } else {
+        print_response(&response, &mut stdout)?;
+    }
+    stdout.flush()
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, Error> {
+    let mut options = Options::default();
+    let mut positional = Vec::new();
+    let mut unix = None;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| {
+            args.next()
+                .ok_or_else(|| syntax(&format!("Missing value for {}", name)))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            "--unix" => unix = Some(PathBuf::from(value(&arg)?)),
+            "-X" | "--request" => options.method = Some(value(&arg)?),
+            "-H" | "--header" => {
+                let header = value(&arg)?;
+                match header.split_once(':') {
+                    Some((name, val)) if !name.trim().is_empty() => options
+                        .headers
+                        .push((name.trim().to_string(), val.trim().to_string())),
+                    _ => return Err(syntax(&format!("Invalid header '{}'", header))),
+                }
+            }
+            "-e" | "--env" => {
+                let var = value(&arg)?;
+                match var.split_once('=') {
+                    Some((key, val)) if !key.is_empty() => {
+                        options.env.push((key.to_string(), val.to_string()))
+                    }
+                    _ => return Err(syntax(&format!("Invalid env override '{}'", var))),
+                }
+            }
+            "-d" | "--data" => {
+                let value = value(&arg)?;
+                let mut data = read_data(&value)?;
+                if value.starts_with('@') {
+                    // Like curl, form data from files is sent without its line breaks.
+                    data.retain(|b| *b != b'\r' && *b != b'\n');
+                }
+                let body = options.body.get_or_insert_with(Vec::new);
+                if !body.is_empty() {
+                    body.push(b'&');
+                }
+                body.extend_from_slice(&data);
+                options.form = true;
+            }
+            "--data-binary" => {
+                let data = read_data(&value(&arg)?)?;
+                options
+                    .body
+                    .get_or_insert_with(Vec::new)
+                    .extend_from_slice(&data);
+            }
+            "--raw" => options.raw = true,
+            "-v" | "--verbose" => options.verbose = true,
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(syntax(&format!("Unknown option '{}'", arg)))
+            }
+            _ => positional.push(arg),
+        }
+    }
+    let url = match (unix, &mut positional[..]) {
+        (Some(path), [url]) => {
+            options.target = Some(Address::Unix(path));
+            url
+        }
+        (None, [target, url]) => {
+            options.target = Some(target.parse()?);
+            url
+        }
+        _ => return Err(syntax("Expected a target and a URL")),
+    };
+    options.url = std::mem::take(url);
+    Ok(options)
+}
+
+/// Returns the contents of a `-d` or `--data-binary` value: the value itself, or the contents of
+/// a file for `@FILE`, or stdin for `@-`.
+fn read_data(value: &str) -> Result<Vec<u8>, Error> {
+    match value.strip_prefix('@') {
+        Some("-") => {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            Ok(data)
+        }
+        Some(path) => fs::read(path)
+            .map_err(|e| Error::new(e.kind(), format!("Failed to read {}: {}", path, e))),
+        None => Ok(value.as_bytes().to_vec()),
+    }
+}
+
+/// Returns the SCGI headers for a request, following the CGI conventions that a web server would
+/// use for the same HTTP request.
+fn build_headers(options: &Options, body_len: usize) -> Result<Vec<(String, String)>, Error> {
+    let method = options.method.clone().unwrap_or_else(|| {
+        if options.body.is_some() {
+            "POST"
+        } else {
+            "GET"
+        }
+        .to_string()
+    });
+    let mut headers = vec![
+        ("CONTENT_LENGTH".to_string(), body_len.to_string()),
+        ("SCGI".to_string(), "1".to_string()),
+        ("REQUEST_METHOD".to_string(), method),
+        ("SERVER_PROTOCOL".to_string(), "HTTP/1.1".to_string()),
+    ];
+
+    let (scheme, authority, path) = match options.url.split_once("://") {
+        Some((scheme, rest)) => {
+            let (authority, path) = match rest.find(['/', '?']) {
+                Some(i) => rest.split_at(i),
+                None => (rest, "/"),
+            };
+            (Some(scheme.to_ascii_lowercase()), Some(authority), path)
+        }
+        None => (None, None, options.url.as_str()),
+    };
+    if !path.starts_with('/') {
+        return Err(syntax(&format!("Invalid URL '{}'", options.url)));
+    }
+    let (path_info, query) = path.split_once('?').unwrap_or((path, ""));
+    headers.push(("REQUEST_URI".to_string(), path.to_string()));
+    headers.push(("SCRIPT_NAME".to_string(), String::new()));
+    headers.push(("PATH_INFO".to_string(), path_info.to_string()));
+    headers.push(("QUERY_STRING".to_string(), query.to_string()));
+    if let (Some(scheme), Some(authority)) = (scheme, authority) {
+        let https = scheme == "https";
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.to_string()),
+            _ => (authority, if https { "443" } else { "80" }.to_string()),
+        };
+        headers.push(("REQUEST_SCHEME".to_string(), scheme));
+        if https {
+            headers.push(("HTTPS".to_string(), "on".to_string()));
+        }
+        headers.push(("SERVER_NAME".to_string(), host.to_string()));
+        headers.push(("SERVER_PORT".to_string(), port));
+        headers.push(("HTTP_HOST".to_string(), authority.to_string()));
+    }
+
+    if options.form {
+        headers.push((
+            "CONTENT_TYPE".to_string(),
+            "application/x-www-form-urlencoded".to_string(),
+        ));
+    }
+    for (name, value) in &options.headers {
+        let key = match name.to_ascii_uppercase().replace('-', "_").as_str() {
+            // Like the body length, this comes from the body rather than the headers.
+            "CONTENT_LENGTH" => continue,
+            "CONTENT_TYPE" => "CONTENT_TYPE".to_string(),
+            key => format!("HTTP_{}", key),
+        };
+        set_header(&mut headers, key, value.clone(), true);
+    }
+    for (key, value) in &options.env {
+        set_header(&mut headers, key.clone(), value.clone(), false);
+    }
+    Ok(headers)
+}
+
+/// Sets a header, replacing any existing value, or joining it with the existing value as a web
+/// server would for an HTTP header sent more than once.
+fn set_header(headers: &mut Vec<(String, String)>, key: String, value: String, join: bool) {
+    match headers.iter_mut().find(|(k, _)| *k == key) {
+        Some((_, existing)) if join && key != "CONTENT_TYPE" && key != "HTTP_HOST" => {
+            existing.push_str(", ");
+            existing.push_str(&value);
+        }
+        Some((_, existing)) => *existing = value,
+        None => headers.push((key, value)),
+    }
+}
+
+/// Prints a CGI or HTTP response with its status first, then its other headers, then its body.
+fn print_response<W: Write>(response: &[u8], out: &mut W) -> Result<(), Error> {
+    let header_end = [&b"\r\n\r\n"[..], &b"\n\n"[..]]
+        .iter()
+        .filter_map(|sep| {
+            response
+                .windows(sep.len())
+                .position(|w| w == *sep)
+                .map(|i| (i, i + sep.len()))
+        })
+        .min();
+    let (head, body) = match header_end {
+        Some((end, body_start)) => (&response[..end], &response[body_start..]),
+        // No blank line, so it's all headers.
+        None => (response, &b""[..]),
+    };
+    let head = String::from_utf8_lossy(head);
+    let mut status = "200 OK".to_string();
+    let mut headers = String::new();
+    for (i, line) in head.lines().enumerate() {
+        if i == 0 && line.starts_with("HTTP/") {
+            status = line.split_once(' ').map_or("", |(_, s)| s).to_string();
+            continue;
+        }
+        match line.split_once(':') {
+            Some((name, value)) if name.trim().eq_ignore_ascii_case("status") => {
+                status = value.trim().to_string()
+            }
+            Some((name, value)) => writeln!(headers, "{}: {}", name.trim(), value.trim())
+                .expect("Writing to a String can't fail"),
+            None => writeln!(headers, "{}", line).expect("Writing to a String can't fail"),
+        }
+    }
+    writeln!(out, "Status: {}\n{}", status, headers)?;
+    out.write_all(body)
+}
+
+/// Shows bytes as text, with escapes for NULs, backslashes and any other unprintable bytes.
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for b in bytes {
+        match b {
+            0 => escaped.push_str("\\0"),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(*b as char),
+            _ => write!(escaped, "\\x{:02x}", b).expect("Writing to a String can't fail"),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,74 @@
+#![deny(warnings)]
+
+use std::env;
+use std::io::{Error, ErrorKind};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_scgi::listener::Listener;
+use tokio_scgi::proxy::{Config, Proxy};
+
+fn syntax() -> Error {
+    println!(
+        "Syntax: {} </path/to/scgi-proxy.toml>",
+        env::args().next().unwrap()
+    );
+    Error::new(ErrorKind::InvalidInput, "Missing required argument")
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let path = match env::args().nth(1) {
+        // Probably a commandline argument like '-h'/'--help', avoid reading it as a path
+        Some(path) if !path.starts_with('-') => path,
+        _ => return Err(syntax()),
+    };
+    let config = Config::load(&path)?;
+    // Listener handoff is only supported on Unix platforms.
+    #[cfg(unix)]
+    let inherited = Listener::from_env()?;
+    #[cfg(not(unix))]
+    let inherited: Option<Listener> = None;
+    let listener = match inherited {
+        Some(listener) => {
+            // We were started by a previous instance which is handing off its listener to us
+            println!("Resuming with inherited listener {:?}", listener);
+            listener
+        }
+        None => {
+            let listener = config.listen.bind().await?;
+            println!("Listening on {}", config.listen);
+            listener
+        }
+    };
+    for backend in &config.backends {
+        println!("Forwarding to {}", backend);
+    }
+
+    let proxy = Proxy::new(config)?;
+    proxy
+        .serve_with_shutdown(&listener, shutdown_signal())
+        .await?;
+    println!("Finished forwarding in-flight requests, exiting");
+    Ok(())
+}
+
+/// Waits for a SIGINT or SIGTERM, after which we stop accepting and exit once in-flight requests
+/// have been forwarded.
+#[cfg(unix)]
+async fn shutdown_signal() {
+    let mut sigint = signal(SignalKind::interrupt()).expect("Failed to register SIGINT");
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to register SIGTERM");
+    tokio::select! {
+        _ = sigint.recv() => {}
+        _ = sigterm.recv() => {}
+    }
+}
+
+/// Waits for Ctrl-C, after which we stop accepting and exit once in-flight requests have been
+/// forwarded.
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("Failed to register Ctrl-C handler");
+}
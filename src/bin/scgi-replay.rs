@@ -0,0 +1,90 @@
+#![deny(warnings)]
+
+use std::env;
+use std::io::{Error, ErrorKind};
+use tokio_scgi::capture::{Pace, Reader, Replay};
+use tokio_scgi::listener::Address;
+
+fn syntax() -> Error {
+    println!(
+        "Syntax: {} [--speed FACTOR | --unpaced] [--concurrency N] [--ignore-header NAME]... \
+         <capture file> </path/to/unix.sock or tcp-host:1234>",
+        env::args().next().unwrap()
+    );
+    Error::new(ErrorKind::InvalidInput, "Invalid arguments")
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    let mut pace = Pace::Speed(1.0);
+    let mut concurrency = None;
+    let mut ignored_headers = Vec::new();
+    let mut positional = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--speed" => match args.next().and_then(|speed| speed.parse::<f64>().ok()) {
+                Some(speed) if speed > 0.0 => pace = Pace::Speed(speed),
+                _ => return Err(syntax()),
+            },
+            "--unpaced" => pace = Pace::Unpaced,
+            "--concurrency" => match args.next().and_then(|max| max.parse::<usize>().ok()) {
+                Some(max) if max > 0 => concurrency = Some(max),
+                _ => return Err(syntax()),
+            },
+            "--ignore-header" => match args.next() {
+                Some(name) => ignored_headers.push(name),
+                None => return Err(syntax()),
+            },
+            _ if arg.starts_with('-') => return Err(syntax()),
+            _ => positional.push(arg),
+        }
+    }
+    let (capture, target) = match &positional[..] {
+        [capture, target] => (capture, target.parse::<Address>()?),
+        _ => return Err(syntax()),
+    };
+
+    let records = Reader::open(capture)?.collect::<Result<Vec<_>, _>>()?;
+    println!("Replaying {} requests to {}", records.len(), target);
+    let mut replay = Replay::new(target).pace(pace);
+    if let Some(max) = concurrency {
+        replay = replay.concurrency(max);
+    }
+    for name in &ignored_headers {
+        replay = replay.ignore_header(name);
+    }
+
+    let (mut matched, mut differed, mut failed) = (0, 0, 0);
+    for (i, replayed) in replay.run(records).await.into_iter().enumerate() {
+        let request = format!(
+            "#{} {} {}",
+            i + 1,
+            replayed.record.header("REQUEST_METHOD").unwrap_or("-"),
+            replayed.record.header("REQUEST_URI").unwrap_or("-")
+        );
+        match (&replayed.response, &replayed.diff) {
+            (Err(e), _) => {
+                failed += 1;
+                println!("{}: {}", request, e);
+            }
+            (Ok(_), Some(diff)) => {
+                differed += 1;
+                println!("{}: {}", request, diff);
+            }
+            (Ok(_), None) => matched += 1,
+        }
+    }
+    println!(
+        "{} matched, {} differed, {} failed",
+        matched, differed, failed
+    );
+    if differed + failed > 0 {
+        return Err(Error::other(format!(
+            "{} of {} responses didn't match",
+            differed + failed,
+            matched + differed + failed
+        )));
+    }
+    Ok(())
+}
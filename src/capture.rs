@@ -0,0 +1,672 @@
+#![deny(warnings)]
+
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, PAD_INDIFFERENT};
+use base64::Engine;
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+use tokio::time;
+use tokio_util::codec::Framed;
+
+use crate::listener::Address;
+use crate::proto::SCGIRequest;
+use crate::runtime::{self, Handler, HandlerFuture, Request};
+use crate::writer_thread::WriterThread;
+use crate::{access_log, client};
+
+/// The first bytes of a capture file in the binary format.
+const MAGIC: &[u8; 8] = b"SCGICAP1";
+
+/// The number of records which may wait to be written by a `Recorder`'s writer thread. Once it's
+/// full, further records are dropped rather than holding up requests.
+const RECORD_QUEUE: usize = 1024;
+
+/// The format of a capture file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    /// One JSON object per line, with the bodies encoded as base64:
+    /// `{"time_us":...,"peer":"...","headers":[["CONTENT_LENGTH","5"],...],"body":"aGVsbG8=",...}`
+    JsonLines,
+
+    /// A compact binary format: `SCGICAP1`, followed by each record's time, peer, headers, body,
+    /// duration and response. Integers are LEB128 varints, strings and bodies are prefixed with
+    /// their length, and optional fields are prefixed with their length plus one, or zero if
+    /// they're missing.
+    Binary,
+}
+
+/// A captured request, along with the response it got if that was captured too.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Record {
+    /// When the request was received, in microseconds since the Unix epoch.
+    pub time_us: u64,
+
+    /// The address of the SCGI client which sent the request, if known.
+    pub peer: Option<String>,
+
+    /// The request headers, in the order they were sent.
+    pub headers: Vec<(String, String)>,
+
+    /// The request body.
+    #[serde(
+        serialize_with = "serialize_base64",
+        deserialize_with = "deserialize_base64"
+    )]
+    pub body: Vec<u8>,
+
+    /// How long the server took to produce the response, in microseconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration_us: Option<u64>,
+
+    /// The response sent back for the request.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_base64_option",
+        deserialize_with = "deserialize_base64_option"
+    )]
+    pub response: Option<Vec<u8>>,
+}
+
+impl Record {
+    /// Returns a record of a request received now, such as one just decoded by
+    /// `server::SCGICodec`.
+    pub fn new(headers: Vec<(String, String)>, body: Vec<u8>) -> Record {
+        Record {
+            time_us: micros_since_epoch(SystemTime::now()),
+            headers,
+            body,
+            ..Record::default()
+        }
+    }
+
+    /// Returns the value of the first header with the provided key, or `None` if it's missing.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Writes records to a capture file. Records are encoded by the caller and written by a dedicated
+/// thread, so that `record()` never blocks on the file. Each record is written with a single
+/// `write_all()` call, and the file is flushed once the queued records have been written, rather
+/// than after each one. Dropping the `Recorder` waits for any queued records to be written.
+///
+/// Captures include everything in the requests, such as cookies and passwords, and should be
+/// protected accordingly.
+pub struct Recorder {
+    format: Format,
+    writer: WriterThread<Box<dyn Write + Send>>,
+}
+
+impl Recorder {
+    /// Returns a `Recorder` which writes to the provided writer. For the binary format, the file
+    /// header is written immediately.
+    pub fn new<W: Write + Send + 'static>(mut writer: W, format: Format) -> io::Result<Recorder> {
+        if format == Format::Binary {
+            writer.write_all(MAGIC)?;
+        }
+        let writer: Box<dyn Write + Send> = Box::new(writer);
+        Ok(Recorder {
+            format,
+            writer: WriterThread::spawn("scgi-capture", writer, RECORD_QUEUE)?,
+        })
+    }
+
+    /// Returns a `Recorder` which writes to a new file at the provided path, replacing any
+    /// existing file.
+    pub fn create<P: AsRef<Path>>(path: P, format: Format) -> io::Result<Recorder> {
+        Recorder::new(File::create(path)?, format)
+    }
+
+    /// Queues a record to be written to the capture. Returns an error with kind `WouldBlock`,
+    /// dropping the record, if the writer has fallen too far behind. Errors writing the record are
+    /// reported by `flush()`.
+    pub fn record(&self, record: &Record) -> io::Result<()> {
+        let encoded = match self.format {
+            Format::JsonLines => {
+                let mut line = serde_json::to_vec(record)?;
+                line.push(b'\n');
+                line
+            }
+            Format::Binary => encode_binary(record),
+        };
+        self.writer.write(encoded)
+    }
+
+    /// Waits for every record queued so far to be written and flushed, and returns the first error
+    /// writing them since the last call. This blocks, so async code should call it from
+    /// `tokio::task::spawn_blocking()`.
+    pub fn flush(&self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recorder")
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+/// A `Handler` which records each request that it passes to the wrapped handler, along with the
+/// response. Requests which are rejected before reaching the handler, such as malformed or timed
+/// out requests, aren't recorded. Failing to write a record doesn't affect the request, and neither
+/// does a slow capture file: records are dropped if the `Recorder` falls too far behind.
+#[derive(Debug)]
+pub struct Capture<H> {
+    handler: H,
+    recorder: Arc<Recorder>,
+}
+
+impl<H: Handler> Capture<H> {
+    /// Returns a `Capture` which passes requests to `handler` and records them with `recorder`.
+    pub fn new(handler: H, recorder: Arc<Recorder>) -> Capture<H> {
+        Capture { handler, recorder }
+    }
+}
+
+impl<H: Handler> Handler for Capture<H> {
+    fn call(&self, request: Request) -> HandlerFuture {
+        let mut record = Record::new(request.headers.clone(), request.body.to_vec());
+        record.peer = Some(request.peer.to_string());
+        let started = Instant::now();
+        let response = self.handler.call(request);
+        let recorder = self.recorder.clone();
+        Box::pin(async move {
+            let result = response.await;
+            if let Ok(response) = &result {
+                record.duration_us = Some(started.elapsed().as_micros() as u64);
+                record.response = Some(response.clone());
+            }
+            if let Err(_e) = recorder.record(&record) {
+                event!(warn, error = %_e, "Failed to queue capture record");
+            }
+            result
+        })
+    }
+}
+
+/// Reads records from a capture file in either format. The format is detected from the start of
+/// the file.
+#[derive(Debug)]
+pub struct Reader<R> {
+    reader: R,
+    format: Format,
+    /// The current line, for JSON Lines errors.
+    line: usize,
+    done: bool,
+}
+
+impl Reader<BufReader<File>> {
+    /// Opens the capture file at the provided path.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Reader<BufReader<File>>> {
+        Reader::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: BufRead> Reader<R> {
+    /// Returns a `Reader` for the capture in the provided reader.
+    pub fn new(mut reader: R) -> io::Result<Reader<R>> {
+        let format = if reader.fill_buf()?.starts_with(MAGIC) {
+            reader.consume(MAGIC.len());
+            Format::Binary
+        } else {
+            Format::JsonLines
+        };
+        Ok(Reader {
+            reader,
+            format,
+            line: 0,
+            done: false,
+        })
+    }
+
+    /// Returns the format of the capture.
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    fn read_json(&mut self) -> io::Result<Option<Record>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line += 1;
+            if !line.trim().is_empty() {
+                break;
+            }
+        }
+        serde_json::from_str(&line).map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid capture record on line {}: {}", self.line, e),
+            )
+        })
+    }
+
+    fn read_binary(&mut self) -> io::Result<Option<Record>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let reader = &mut self.reader;
+        let time_us = read_varint(reader)?;
+        let peer = read_option(reader)?.map(to_string).transpose()?;
+        let mut headers = Vec::new();
+        for _ in 0..read_varint(reader)? {
+            let len = read_varint(reader)?;
+            let key = to_string(read_bytes(reader, len)?)?;
+            let len = read_varint(reader)?;
+            headers.push((key, to_string(read_bytes(reader, len)?)?));
+        }
+        let len = read_varint(reader)?;
+        let body = read_bytes(reader, len)?;
+        let duration_us = read_varint(reader)?.checked_sub(1);
+        let response = read_option(reader)?;
+        Ok(Some(Record {
+            time_us,
+            peer,
+            headers,
+            body,
+            duration_us,
+            response,
+        }))
+    }
+}
+
+impl<R: BufRead> Iterator for Reader<R> {
+    type Item = io::Result<Record>;
+
+    /// Returns the next record, or `None` at the end of the capture. Stops after the first error.
+    fn next(&mut self) -> Option<io::Result<Record>> {
+        if self.done {
+            return None;
+        }
+        let result = match self.format {
+            Format::JsonLines => self.read_json(),
+            Format::Binary => self.read_binary(),
+        };
+        match result {
+            Ok(Some(record)) => Some(Ok(record)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// How quickly `Replay` sends the captured requests.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pace {
+    /// Sends each request at its captured time relative to the first request, sped up by the
+    /// provided factor: `1.0` is the original pace, and `10.0` is ten times as fast. Requests
+    /// which overlapped when captured are sent concurrently.
+    Speed(f64),
+
+    /// Sends each request as soon as the response to the previous one has been received.
+    Unpaced,
+}
+
+/// The result of replaying a captured request.
+#[derive(Debug)]
+pub struct Replayed {
+    /// The captured request.
+    pub record: Record,
+
+    /// The response from the target, or the error sending the request to it.
+    pub response: Result<Vec<u8>, io::Error>,
+
+    /// How the response differs from the captured response, or `None` if it matches. Also `None`
+    /// if the response wasn't captured, as there's nothing to compare against.
+    pub diff: Option<String>,
+}
+
+/// Replays captured requests against an SCGI server, and compares the responses with the
+/// captured ones. Useful for reproducing a production bug, or checking that an upgrade doesn't
+/// change any responses.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), std::io::Error> {
+/// use tokio_scgi::capture::{Pace, Reader, Replay};
+///
+/// let records = Reader::open("requests.jsonl")?.collect::<Result<Vec<_>, _>>()?;
+/// let replay = Replay::new("127.0.0.1:4000".parse()?)
+///     .pace(Pace::Speed(10.0))
+///     .ignore_header("Date");
+/// for replayed in replay.run(records).await {
+///     if let Some(diff) = replayed.diff {
+///         println!("{:?}: {}", replayed.record.header("REQUEST_URI"), diff);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Replay {
+    target: Address,
+    pace: Pace,
+    ignored_headers: Vec<String>,
+    timeout: Duration,
+    concurrency: usize,
+}
+
+impl Replay {
+    /// Returns a `Replay` which sends requests to the provided SCGI server at the original pace.
+    pub fn new(target: Address) -> Replay {
+        Replay {
+            target,
+            pace: Pace::Speed(1.0),
+            ignored_headers: Vec::new(),
+            timeout: Duration::from_secs(30),
+            concurrency: 64,
+        }
+    }
+
+    /// Sets how quickly the requests are sent. Speeds which aren't positive are treated as
+    /// `Pace::Unpaced`.
+    pub fn pace(mut self, pace: Pace) -> Replay {
+        self.pace = pace;
+        self
+    }
+
+    /// Ignores a response header when comparing responses, such as `Date` which is expected to
+    /// differ. The name isn't case sensitive.
+    pub fn ignore_header(mut self, name: &str) -> Replay {
+        self.ignored_headers.push(name.to_string());
+        self
+    }
+
+    /// Sets how long each request may take, from connecting to receiving the end of the response.
+    /// Defaults to 30 seconds.
+    pub fn timeout(mut self, timeout: Duration) -> Replay {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum number of requests in flight at once when replaying at a `Pace::Speed`.
+    /// Requests which are due while the limit is reached are sent late, once earlier requests have
+    /// finished. Defaults to 64, and values below 1 are treated as 1.
+    pub fn concurrency(mut self, max: usize) -> Replay {
+        self.concurrency = max.max(1);
+        self
+    }
+
+    /// Sends each record's request to the target according to the pace, and returns the results in
+    /// the same order as the records.
+    pub async fn run(&self, records: Vec<Record>) -> Vec<Replayed> {
+        let speed = match self.pace {
+            Pace::Speed(speed) if speed > 0.0 => speed,
+            _ => {
+                let mut results = Vec::with_capacity(records.len());
+                for record in records {
+                    results.push(self.replay(record).await);
+                }
+                return results;
+            }
+        };
+        let first = records.iter().map(|r| r.time_us).min().unwrap_or(0);
+        let start = time::Instant::now();
+        let in_flight = Semaphore::new(self.concurrency);
+        let in_flight = &in_flight;
+        futures::future::join_all(records.into_iter().map(|record| {
+            let offset = Duration::from_micros(record.time_us - first).div_f64(speed);
+            async move {
+                time::sleep_until(start + offset).await;
+                let _permit = in_flight
+                    .acquire()
+                    .await
+                    .expect("Semaphore is never closed");
+                self.replay(record).await
+            }
+        }))
+        .await
+    }
+
+    async fn replay(&self, record: Record) -> Replayed {
+        let response = self.send(&record).await;
+        let diff = match (&record.response, &response) {
+            (Some(expected), Ok(actual)) => self.diff(expected, actual),
+            (Some(_), Err(e)) => Some(format!("Request failed: {}", e)),
+            (None, _) => None,
+        };
+        Replayed {
+            record,
+            response,
+            diff,
+        }
+    }
+
+    /// Sends a record's request to the target and returns the response.
+    pub async fn send(&self, record: &Record) -> Result<Vec<u8>, io::Error> {
+        let exchange = async {
+            let mut target = Framed::new(self.target.connect().await?, client::SCGICodec::new());
+            target
+                .send(SCGIRequest::Request(
+                    record.headers.clone(),
+                    BytesMut::from(&record.body[..]),
+                ))
+                .await?;
+            let mut response = Vec::new();
+            while let Some(chunk) = target.next().await {
+                response.extend_from_slice(&chunk?);
+            }
+            Ok(response)
+        };
+        time::timeout(self.timeout, exchange).await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Timed out waiting for a response from {}", self.target),
+            )
+        })?
+    }
+
+    /// Describes the first difference between two responses, or returns `None` if they match
+    /// apart from any ignored headers.
+    pub fn diff(&self, expected: &[u8], actual: &[u8]) -> Option<String> {
+        let (expected_status, actual_status) = (
+            runtime::response_status(expected),
+            runtime::response_status(actual),
+        );
+        if expected_status != actual_status {
+            return Some(format!(
+                "Status {}, expected {}",
+                actual_status, expected_status
+            ));
+        }
+        let (expected_head, expected_body) = split_response(expected);
+        let (actual_head, actual_body) = split_response(actual);
+        let (expected_head, actual_head) = (self.headers(expected_head), self.headers(actual_head));
+        for i in 0..expected_head.len().max(actual_head.len()) {
+            let (expected, actual) = (expected_head.get(i), actual_head.get(i));
+            if expected != actual {
+                return Some(format!(
+                    "Header line {} is {}, expected {}",
+                    i + 1,
+                    actual.map_or("missing".to_string(), |h| format!("{:?}", h)),
+                    expected.map_or("missing".to_string(), |h| format!("{:?}", h)),
+                ));
+            }
+        }
+        if expected_body != actual_body {
+            let at = expected_body
+                .iter()
+                .zip(actual_body)
+                .position(|(e, a)| e != a)
+                .unwrap_or_else(|| expected_body.len().min(actual_body.len()));
+            return Some(format!(
+                "Body differs at byte {}: got {} bytes, expected {}",
+                at,
+                actual_body.len(),
+                expected_body.len()
+            ));
+        }
+        None
+    }
+
+    /// Returns the header lines of a response, without any ignored headers.
+    fn headers(&self, head: &[u8]) -> Vec<String> {
+        String::from_utf8_lossy(head)
+            .lines()
+            .filter(|line| !line.is_empty())
+            .filter(|line| {
+                let name = line.split(':').next().unwrap_or("").trim();
+                !self
+                    .ignored_headers
+                    .iter()
+                    .any(|ignored| name.eq_ignore_ascii_case(ignored))
+            })
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+/// Splits a CGI or HTTP response into its headers and body.
+fn split_response(response: &[u8]) -> (&[u8], &[u8]) {
+    response.split_at(response.len() - access_log::body_len(response))
+}
+
+fn micros_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_micros() as u64)
+}
+
+fn encode_binary(record: &Record) -> Vec<u8> {
+    let mut out = Vec::with_capacity(64 + record.body.len());
+    put_varint(&mut out, record.time_us);
+    put_option(&mut out, record.peer.as_ref().map(String::as_bytes));
+    put_varint(&mut out, record.headers.len() as u64);
+    for (k, v) in &record.headers {
+        put_bytes(&mut out, k.as_bytes());
+        put_bytes(&mut out, v.as_bytes());
+    }
+    put_bytes(&mut out, &record.body);
+    put_varint(
+        &mut out,
+        record.duration_us.map_or(0, |d| d.saturating_add(1)),
+    );
+    put_option(&mut out, record.response.as_deref());
+    out
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn put_option(out: &mut Vec<u8>, bytes: Option<&[u8]>) {
+    match bytes {
+        Some(bytes) => {
+            put_varint(out, bytes.len() as u64 + 1);
+            out.extend_from_slice(bytes);
+        }
+        None => put_varint(out, 0),
+    }
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte)?;
+        n |= u64::from(byte[0] & 0x7f) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Capture record has an integer which is too long",
+    ))
+}
+
+/// Reads an optional field written by `put_option()`.
+fn read_option<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    match read_varint(reader)? {
+        0 => Ok(None),
+        len => read_bytes(reader, len - 1).map(Some),
+    }
+}
+
+/// Reads `len` bytes, without trusting `len` enough to allocate it up front.
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Capture ended in the middle of a record",
+        ));
+    }
+    Ok(bytes)
+}
+
+fn to_string(bytes: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Capture record has a string which isn't UTF-8",
+        )
+    })
+}
+
+/// Standard base64, which accepts encodings with or without padding.
+const BASE64: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, PAD_INDIFFERENT);
+
+fn serialize_base64<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&BASE64.encode(data))
+}
+
+fn deserialize_base64<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let encoded = String::deserialize(deserializer)?;
+    BASE64
+        .decode(encoded)
+        .map_err(|e| de::Error::custom(format!("invalid base64: {}", e)))
+}
+
+fn serialize_base64_option<S: Serializer>(
+    data: &Option<Vec<u8>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match data {
+        Some(data) => serialize_base64(data, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_base64_option<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<u8>>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(encoded) => BASE64
+            .decode(encoded)
+            .map(Some)
+            .map_err(|e| de::Error::custom(format!("invalid base64: {}", e))),
+        None => Ok(None),
+    }
+}
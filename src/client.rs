@@ -2,99 +2,93 @@
 
 use bytes::{BufMut, BytesMut};
 use std::io;
-use tokio_util::codec::{Decoder, Encoder};
 
-const NUL: u8 = b'\0';
+use crate::{proto, request_id};
 
-/// A parsed SCGI request header with key/value header data, and/or bytes from the raw request body.
-#[derive(Clone, Debug, Eq, PartialEq)]
-pub enum SCGIRequest {
-    /// The Vec contains the headers. The BytesMut optionally contains raw byte data from
-    /// the request body, which may be followed by additional `BodyFragment`s in later calls.
-    /// The `Content-Length` header, required by SCGI, can be used to detect whether to wait for
-    /// additional `BodyFragment`s.
-    Request(Vec<(String, String)>, BytesMut),
-
-    /// Additional body fragment(s), used for streaming fragmented request body data. These should
-    /// only be relevant in cases where the leading `Request` value doesn't contain all of the body.
-    BodyFragment(BytesMut),
-}
+pub use crate::proto::SCGIRequest;
 
 /// A `Codec` implementation that creates SCGI requests for SCGI clients like web servers.
 /// The Encoder accepts `SCGIRequest` objects containing header/body request data and encodes them for
 /// sending to an SCGI server. The Decoder passes through the raw response returned by the SCGI server.
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct SCGICodec {}
+pub struct SCGICodec {
+    /// The header to add a generated request ID to, if the request doesn't already have it.
+    request_id_header: Option<String>,
+
+    /// Whether to add `CONTENT_LENGTH` and `SCGI` headers to requests which don't have them.
+    required_headers: bool,
+}
+
+impl Default for SCGICodec {
+    fn default() -> Self {
+        SCGICodec::new()
+    }
+}
 
 impl SCGICodec {
     /// Returns a client `SCGICodec` for creating SCGI-format requests for use by SCGI clients
     /// like web servers.
     pub fn new() -> SCGICodec {
-        SCGICodec {}
+        SCGICodec {
+            request_id_header: None,
+            required_headers: false,
+        }
     }
-}
-
-/// Passes through any response data as-is. To be handled by the requesting client.
-impl Decoder for SCGICodec {
-    type Item = BytesMut;
-    type Error = io::Error;
 
-    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, io::Error> {
-        // Forward content (HTTP response, typically?) as-is
-        Ok(Some(buf.split_to(buf.len())))
+    /// Returns a client `SCGICodec` which adds a generated request ID to each request that doesn't
+    /// already have the provided header, typically `request_id::DEFAULT_HEADER`. To keep the same
+    /// ID across SCGI hops, copy the ID of the request being served into the outgoing headers.
+    pub fn with_request_id(header: &str) -> SCGICodec {
+        SCGICodec {
+            request_id_header: Some(header.to_string()),
+            required_headers: false,
+        }
     }
-}
 
-/// Creates and produces SCGI requests. Invoke once with `Request`, followed by zero or more calls
-/// with `BodyFragment`.
-impl Encoder<SCGIRequest> for SCGICodec {
-    type Error = io::Error;
+    /// Adds `CONTENT_LENGTH` and `SCGI` headers at the start of each request which doesn't already
+    /// have them, as the SCGI spec requires. `CONTENT_LENGTH` is the length of the body passed
+    /// alongside the headers, so requests with further `BodyFragment`s must set it themselves.
+    pub fn required_headers(mut self) -> SCGICodec {
+        self.required_headers = true;
+        self
+    }
 
-    fn encode(&mut self, data: SCGIRequest, buf: &mut BytesMut) -> Result<(), io::Error> {
+    /// Encodes the headers and start of the body of a request, or a later body fragment.
+    fn encode_request(&mut self, data: SCGIRequest, buf: &mut BytesMut) -> Result<(), io::Error> {
         match data {
-            SCGIRequest::Request(env_map, body) => {
-                // Calculate size needed for header netstring
-                let mut sum_header_size: usize = 0;
-                for (k, v) in &env_map {
-                    // While we're iterating over the keys/values, do some basic validation per the
-                    // SCGI protocol spec.
-                    if k.len() == 0 {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            format!("Keys in request header cannot be empty"),
-                        ));
-                    }
-                    if k.as_bytes().contains(&NUL) || v.as_bytes().contains(&NUL) {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidInput,
-                            format!("Keys/values in request header cannot contain NUL character"),
-                        ));
+            SCGIRequest::Request(mut env_map, body) => {
+                if self.required_headers {
+                    env_map = with_required_headers(env_map, body.len());
+                }
+                if let Some(header) = &self.request_id_header {
+                    if !env_map.iter().any(|(k, _)| k == header) {
+                        env_map.push((header.clone(), request_id::generate()));
                     }
-                    // Include 2 x NUL in size:
-                    sum_header_size += k.len() + 1/*NUL*/ + v.len() + 1/*NUL*/;
                 }
-                let netstring_size_str = sum_header_size.to_string();
-                // Include ':' and ',' in buffer, not included in netstring size:
-                buf.reserve(
-                    netstring_size_str.len() + 1/*:*/ + sum_header_size + 1/*,*/ + body.len(),
+                let header_size = proto::headers_len(&env_map)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                // Include the size, ':' and ',' in buffer, not included in netstring size:
+                buf.reserve(20 + 1/*:*/ + header_size + 1/*,*/ + body.len());
+                proto::encode_headers(&env_map, buf)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                event!(
+                    trace,
+                    headers = env_map.len(),
+                    header_bytes = header_size,
+                    body_bytes = body.len(),
+                    "Encoded SCGI request"
                 );
 
-                // Insert the header content into the reserved buffer.
-                buf.put_slice(netstring_size_str.as_bytes());
-                buf.put_u8(b':');
-                for (k, v) in &env_map {
-                    buf.put(k.as_bytes());
-                    buf.put_u8(NUL);
-                    buf.put(v.as_bytes());
-                    buf.put_u8(NUL);
-                }
-                buf.put_u8(b',');
-
                 // Add any body content after the header
                 buf.put(body);
             }
             SCGIRequest::BodyFragment(fragment) => {
                 // Forward content as-is
+                event!(
+                    trace,
+                    body_bytes = fragment.len(),
+                    "Encoded SCGI body fragment"
+                );
                 buf.reserve(fragment.len());
                 buf.put(fragment);
             }
@@ -102,3 +96,75 @@ impl Encoder<SCGIRequest> for SCGICodec {
         Ok(())
     }
 }
+
+/// Adds `CONTENT_LENGTH` and `SCGI` headers at the start of the headers if they're missing.
+fn with_required_headers(headers: Vec<(String, String)>, body_len: usize) -> Vec<(String, String)> {
+    let mut required = Vec::new();
+    if !headers.iter().any(|(k, _)| k == "CONTENT_LENGTH") {
+        required.push(("CONTENT_LENGTH".to_string(), body_len.to_string()));
+    }
+    if !headers.iter().any(|(k, _)| k == "SCGI") {
+        required.push(("SCGI".to_string(), "1".to_string()));
+    }
+    required.extend(headers);
+    required
+}
+
+/// Passes through any response data as-is. To be handled by the requesting client.
+///
+/// When the buffer is empty this returns `None`, so `Framed` reads more of the response, and the
+/// stream ends when the server closes the connection. Earlier versions returned an empty chunk,
+/// which callers had to treat as the end of the response.
+#[cfg(feature = "codec")]
+impl tokio_util::codec::Decoder for SCGICodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, io::Error> {
+        Ok(decode_response(buf))
+    }
+}
+
+/// Creates and produces SCGI requests. Invoke once with `Request`, followed by zero or more calls
+/// with `BodyFragment`.
+#[cfg(feature = "codec")]
+impl tokio_util::codec::Encoder<SCGIRequest> for SCGICodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, data: SCGIRequest, buf: &mut BytesMut) -> Result<(), io::Error> {
+        self.encode_request(data, buf)
+    }
+}
+
+/// Passes through any response data from a `futures::io::AsyncRead` as-is, the same as the Tokio
+/// `Decoder`.
+#[cfg(feature = "futures-io")]
+impl asynchronous_codec::Decoder for SCGICodec {
+    type Item = BytesMut;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<BytesMut>, io::Error> {
+        Ok(decode_response(buf))
+    }
+}
+
+/// Creates SCGI requests for a `futures::io::AsyncWrite`, the same as the Tokio `Encoder`.
+#[cfg(feature = "futures-io")]
+impl asynchronous_codec::Encoder for SCGICodec {
+    type Item<'a> = SCGIRequest;
+    type Error = io::Error;
+
+    fn encode(&mut self, data: SCGIRequest, buf: &mut BytesMut) -> Result<(), io::Error> {
+        self.encode_request(data, buf)
+    }
+}
+
+/// Returns whatever response data has been received, or `None` if there's nothing new. Returning
+/// an empty chunk would make `Framed` keep decoding instead of reading more.
+fn decode_response(buf: &mut BytesMut) -> Option<BytesMut> {
+    if buf.is_empty() {
+        return None;
+    }
+    // Forward content (HTTP response, typically?) as-is
+    Some(buf.split_to(buf.len()))
+}
@@ -0,0 +1,221 @@
+#![deny(warnings)]
+
+//! Low-level file descriptor helpers for passing sockets between processes.
+
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The address family of a socket, as reported by `getsockname()`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum SocketFamily {
+    Inet,
+    Unix,
+}
+
+/// Returns whether the provided socket is an IPv4/IPv6 socket or a Unix domain socket.
+pub(crate) fn socket_family(fd: RawFd) -> io::Result<SocketFamily> {
+    let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockname(
+            fd,
+            &mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET | libc::AF_INET6 => Ok(SocketFamily::Inet),
+        libc::AF_UNIX => Ok(SocketFamily::Unix),
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Unsupported socket address family: {}", family),
+        )),
+    }
+}
+
+/// Clears `FD_CLOEXEC` on the provided fd so that it is inherited across `exec()`. This only uses
+/// async-signal-safe calls, so it may be invoked between `fork()` and `exec()`.
+pub(crate) fn clear_cloexec(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Takes ownership of an fd that was passed to this process by its parent, with the fd number in the
+/// environment variable `var`. Returns `None` if the variable isn't set.
+///
+/// The variable is removed as soon as it's read, so that processes spawned later don't inherit a
+/// stale fd number, and the fd is marked close-on-exec so that they don't inherit the fd either.
+/// `taken` ensures the fd is only owned once per process, even if the variable is set again.
+pub(crate) fn take_env_fd(var: &str, taken: &AtomicBool) -> io::Result<Option<OwnedFd>> {
+    let value = match env::var(var) {
+        Ok(value) => value,
+        Err(env::VarError::NotPresent) => return Ok(None),
+        Err(e) => {
+            env::remove_var(var);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid {}: {}", var, e),
+            ));
+        }
+    };
+    env::remove_var(var);
+    let fd: RawFd = value.parse().ok().filter(|fd| *fd >= 0).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not an fd number: '{}'", var, value),
+        )
+    })?;
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(
+            e.kind(),
+            format!("{} is not an open fd: {}: {}", var, fd, e),
+        ));
+    }
+    if taken.swap(true, Ordering::SeqCst) {
+        return Err(io::Error::other(format!(
+            "The fd passed in {} was already taken",
+            var
+        )));
+    }
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The fd is open, was explicitly handed to us by our parent, and `taken` ensures that nothing
+    // else in this process takes ownership of it.
+    Ok(Some(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// Ancillary data space for a single fd, aligned so that it can hold a `cmsghdr`.
+#[repr(C, align(8))]
+struct CmsgBuffer([u8; 64]);
+
+impl CmsgBuffer {
+    fn new() -> CmsgBuffer {
+        CmsgBuffer([0; 64])
+    }
+
+    /// Returns the space needed for a single fd, which fits in the buffer on all supported
+    /// platforms.
+    fn space(&self) -> usize {
+        let space = unsafe { libc::CMSG_SPACE(mem::size_of::<RawFd>() as u32) } as usize;
+        assert!(space <= self.0.len(), "CMSG_SPACE larger than expected");
+        space
+    }
+}
+
+/// Sends `fd` over the Unix socket `sock` as `SCM_RIGHTS` ancillary data, alongside a single
+/// payload byte. The receiver gets its own copy of the fd, and the caller's copy remains open.
+pub(crate) fn send_fd<S: AsRawFd>(sock: &S, fd: RawFd) -> io::Result<()> {
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut cmsg_buf = CmsgBuffer::new();
+    let space = cmsg_buf.space();
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.0.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<RawFd>() as u32) as _;
+        ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+    }
+
+    loop {
+        let ret = unsafe { libc::sendmsg(sock.as_raw_fd(), &msg, 0) };
+        if ret >= 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Receives an fd that was sent over the Unix socket `sock` by `send_fd()`. Returns an
+/// `UnexpectedEof` error if the other end closed the socket, or an `InvalidData` error if a
+/// message arrived without an fd attached or with more ancillary data than expected.
+pub(crate) fn recv_fd<S: AsRawFd>(sock: &S) -> io::Result<OwnedFd> {
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut cmsg_buf = CmsgBuffer::new();
+    let space = cmsg_buf.space();
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = cmsg_buf.0.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = space as _;
+
+    let received = loop {
+        let ret = unsafe { libc::recvmsg(sock.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+        if ret >= 0 {
+            break ret;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    };
+    if received == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Socket closed while waiting for fd",
+        ));
+    }
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        if cmsg.is_null()
+            || (*cmsg).cmsg_level != libc::SOL_SOCKET
+            || (*cmsg).cmsg_type != libc::SCM_RIGHTS
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Received message without an fd attached",
+            ));
+        }
+        // Take ownership of every fd that arrived, so that any unexpected extras are closed.
+        let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+        let count =
+            ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / mem::size_of::<RawFd>();
+        let mut fds: Vec<OwnedFd> = (0..count)
+            .map(|i| OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))))
+            .collect();
+        // If more fds were sent than fit in the buffer, the kernel closed the rest and set
+        // MSG_CTRUNC.
+        if fds.len() != 1 || msg.msg_flags & libc::MSG_CTRUNC != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected one fd, received {} or more", fds.len()),
+            ));
+        }
+        Ok(fds.remove(0))
+    }
+}
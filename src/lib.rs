@@ -1,12 +1,101 @@
 #![deny(warnings)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! SCGI request codec for Tokio.
 //!
 //! This crate provides codecs for creating and parsing SCGI requests. Web servers can use this to query SCGI services as clients. Backend services can use this to serve SCGI endpoints to web servers. For example, you can build a backend service in Rust that serves responses over SCGI to a frontend NGINX server. Check the NGINX documentation for info on how to configure SCGI.
 //! Working examples of Tokio-based SCGI servers and clients are provided in the project examples. Tests meanwhile provide examples of invoking the codecs directly.
+//!
+//! The Tokio codecs need the `codec` feature, or the `futures-io` feature for use with `asynchronous-codec`, and the server runtime needs the `runtime` feature, which is enabled by default. Unix sockets, listener handoff and the `prefork` module are only available on Unix platforms. With `default-features = false`, only the `proto` module is built, without the standard library.
+
+extern crate alloc;
+
+#[macro_use]
+mod trace;
 
-/// Codec for SCGI servers, such as backend services: Parses SCGI requests and sends back raw byte responses to forward back to querying clients.
+/// Sans-IO core for SCGI: A push parser for requests and an encoder for request headers, using
+/// only `core` and `alloc`, for use with any IO or async runtime. The codecs wrap this.
+pub mod proto;
+
+/// Codec for SCGI servers, such as backend services: Parses SCGI requests and sends back raw byte responses to forward back to querying clients. Requires the `codec` or `futures-io` feature.
+#[cfg(any(feature = "codec", feature = "futures-io"))]
 pub mod server;
 
-/// Codec for SCGI clients, such as web servers: Builds SCGI requests and receives raw byte responses to forward back to querying clients.
+/// Codec for SCGI clients, such as web servers: Builds SCGI requests and receives raw byte responses to forward back to querying clients. Requires the `codec` or `futures-io` feature.
+#[cfg(any(feature = "codec", feature = "futures-io"))]
 pub mod client;
+
+/// Metrics hooks for SCGI servers: Request counts, sizes and latencies reported by the server
+/// runtime and codec. Requires the `codec` or `futures-io` feature.
+#[cfg(any(feature = "codec", feature = "futures-io"))]
+pub mod metrics;
+
+/// Request IDs which follow a request across SCGI hops, using the `X-Request-Id` header. Requires
+/// the `codec` or `futures-io` feature.
+#[cfg(any(feature = "codec", feature = "futures-io"))]
+pub mod request_id;
+
+/// Blocking SCGI servers and clients using `std::io`, for code which doesn't use async: Reads and
+/// writes requests on any `Read`/`Write` stream, and serves requests with a pool of threads.
+/// Requires the `std` feature.
+#[cfg(feature = "std")]
+pub mod sync;
+
+/// Listening sockets for SCGI servers, including handing off a listener to a replacement process.
+/// Requires the `runtime` feature.
+#[cfg(feature = "runtime")]
+pub mod listener;
+
+/// Server runtime for SCGI services: Accepts connections, parses requests, and passes them to a
+/// handler. Requires the `runtime` feature.
+#[cfg(feature = "runtime")]
+pub mod runtime;
+
+/// Relaying SCGI requests to a backend SCGI server and streaming back its response, with hooks for
+/// rewriting the request headers. Requires the `runtime` feature.
+#[cfg(feature = "runtime")]
+pub mod relay;
+
+/// Access logging for SCGI services: Writes a Combined Log Format or JSON line for each request.
+/// Requires the `runtime` feature.
+#[cfg(feature = "runtime")]
+pub mod access_log;
+
+/// Capturing SCGI requests to a file, and replaying them against a server to compare the
+/// responses. Requires the `capture` feature.
+#[cfg(feature = "capture")]
+pub mod capture;
+
+/// Pre-fork worker process pool for SCGI services: Passes each connection to a separate worker
+/// process, for handlers which need process isolation. Requires the `runtime` feature, and is only
+/// available on Unix platforms.
+#[cfg(all(feature = "runtime", unix))]
+pub mod prefork;
+
+/// Load balancing across SCGI backends: Forwards each request to one of several backend SCGI
+/// servers, with health checks and retries, as run by the `scgi-proxy` binary. Requires the
+/// `proxy` feature.
+#[cfg(feature = "proxy")]
+pub mod proxy;
+
+/// TLS transport for SCGI over TCP, using rustls. Requires the `rustls` feature.
+#[cfg(feature = "rustls")]
+pub mod tls;
+
+/// Generators for valid and malformed SCGI requests, and for how they're split into fragments, for
+/// fuzzing and property testing. Requires the `arbitrary` or `proptest` feature.
+#[cfg(any(feature = "arbitrary", feature = "proptest"))]
+pub mod traffic;
+
+/// In-memory test harness for SCGI handlers. Requires the `testing` feature.
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(all(feature = "runtime", unix))]
+mod fd;
+
+#[cfg(feature = "runtime")]
+mod writer_thread;
+
+#[cfg(feature = "std")]
+mod respond;
@@ -0,0 +1,542 @@
+#![deny(warnings)]
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use {
+    crate::fd::{self, SocketFamily},
+    std::fs,
+    std::os::unix::fs::PermissionsExt,
+    std::os::unix::io::{AsRawFd, OwnedFd, RawFd},
+    std::os::unix::net::UnixStream as StdUnixStream,
+    std::os::unix::process::CommandExt,
+    std::path::Path,
+    std::process::{self, Command},
+    std::sync::atomic::AtomicBool,
+    tokio::net::{UnixListener, UnixStream},
+};
+
+/// Environment variable used to pass an inherited listening socket to a newly exec'd process. The
+/// value is the number of the inherited fd.
+pub const LISTEN_FD_ENV: &str = "SCGI_LISTEN_FD";
+
+/// A listening TCP or Unix socket which accepts connections from SCGI clients.
+///
+/// A `Listener` can be handed off to another process without closing it, either by exec'ing a new
+/// binary that inherits the socket (see `inherit_into` and `from_env`), or by sending it over a Unix
+/// control socket (see `send_to` and `recv_from`). In both cases the socket stays open the whole
+/// time, so clients connecting during an upgrade are queued rather than refused. Unix sockets and
+/// handoffs are only available on Unix platforms.
+#[derive(Debug)]
+pub enum Listener {
+    /// A TCP listener, typically used when the SCGI client is on another host.
+    Tcp(TcpListener),
+
+    /// A Unix socket listener, typically used when the SCGI client is on the same host.
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// An accepted connection from an SCGI client.
+#[derive(Debug)]
+pub enum Connection {
+    /// A connection accepted by a TCP listener.
+    Tcp(TcpStream),
+
+    /// A connection accepted by a Unix socket listener.
+    #[cfg(unix)]
+    Unix(UnixStream),
+
+    /// A connection accepted by a TCP listener, after completing a TLS handshake.
+    #[cfg(feature = "rustls")]
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+}
+
+/// The address of a connected SCGI client.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PeerAddr {
+    /// The client's IP address and port.
+    Tcp(SocketAddr),
+
+    /// The client's socket path, if it was bound to one. Clients are typically unnamed.
+    Unix(Option<PathBuf>),
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            PeerAddr::Unix(None) => f.write_str("unix"),
+        }
+    }
+}
+
+/// The address of a TCP or Unix socket. Addresses containing a `/` are Unix socket paths, and
+/// anything else is a TCP `host:port`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "proxy",
+    derive(serde::Deserialize),
+    serde(try_from = "String")
+)]
+pub enum Address {
+    /// A TCP `host:port`, where the host may be a name which is resolved on each connection.
+    Tcp(String),
+
+    /// The path of a Unix socket.
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// Binds a listener at this address, replacing any existing Unix socket file. Unix socket
+    /// addresses return an `Unsupported` error on other platforms.
+    pub async fn bind(&self) -> io::Result<Listener> {
+        match self {
+            Address::Tcp(addr) => Listener::bind_tcp(addr.as_str()).await,
+            #[cfg(unix)]
+            Address::Unix(path) => Listener::bind_unix(path),
+            #[cfg(not(unix))]
+            Address::Unix(path) => Err(unix_unsupported(path)),
+        }
+    }
+
+    /// Opens a connection to an SCGI server at this address. Unix socket addresses return an
+    /// `Unsupported` error on other platforms.
+    pub async fn connect(&self) -> io::Result<Connection> {
+        match self {
+            Address::Tcp(addr) => Ok(Connection::Tcp(TcpStream::connect(addr.as_str()).await?)),
+            #[cfg(unix)]
+            Address::Unix(path) => Ok(Connection::Unix(UnixStream::connect(path).await?)),
+            #[cfg(not(unix))]
+            Address::Unix(path) => Err(unix_unsupported(path)),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_unsupported(path: &std::path::Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Unix sockets aren't supported on this platform: {}",
+            path.display()
+        ),
+    )
+}
+
+impl FromStr for Address {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Address> {
+        if s.contains('/') {
+            Ok(Address::Unix(PathBuf::from(s)))
+        } else if s
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+        {
+            Ok(Address::Tcp(s.to_string()))
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid address '{}': expected a Unix socket path or a TCP host:port",
+                    s
+                ),
+            ))
+        }
+    }
+}
+
+impl TryFrom<String> for Address {
+    type Error = io::Error;
+
+    fn try_from(s: String) -> io::Result<Address> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => f.write_str(addr),
+            Address::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A range of IPv4 or IPv6 addresses in CIDR notation, such as `10.0.0.0/8` or `::1/128`. Used to
+/// restrict which TCP clients may connect. IPv4 addresses are also matched when they arrive as
+/// IPv4-mapped IPv6 addresses, such as `::ffff:10.1.2.3` on a dual-stack listener.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(
+    feature = "proxy",
+    derive(serde::Deserialize),
+    serde(try_from = "String")
+)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// Returns a `Cidr` covering addresses which match the first `prefix_len` bits of `addr`.
+    /// Returns an error if `prefix_len` is longer than the address.
+    pub fn new(addr: IpAddr, prefix_len: u8) -> io::Result<Cidr> {
+        let max_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        if prefix_len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("CIDR prefix length {} exceeds {} bits", prefix_len, max_len),
+            ));
+        }
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    /// Returns whether the provided address is within this range.
+    pub fn contains(&self, addr: &IpAddr) -> bool {
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(*addr, IpAddr::V4),
+            IpAddr::V4(_) => *addr,
+        };
+        match (self.addr, addr) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => prefix_matches(
+                u32::from(net) as u128,
+                u32::from(addr) as u128,
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                prefix_matches(u128::from(net), u128::from(addr), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, addr: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = bits - prefix_len;
+    (net >> shift) == (addr >> shift)
+}
+
+/// Parses `addr/prefix_len`, or a bare address which is treated as a single-address range.
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Cidr> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid CIDR range: '{}'", s),
+            )
+        };
+        match s.split_once('/') {
+            Some((addr, prefix_len)) => Cidr::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix_len.parse().map_err(|_| invalid())?,
+            ),
+            None => {
+                let addr: IpAddr = s.parse().map_err(|_| invalid())?;
+                let prefix_len = if addr.is_ipv4() { 32 } else { 128 };
+                Cidr::new(addr, prefix_len)
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = io::Error;
+
+    fn try_from(s: String) -> io::Result<Cidr> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// The credentials of the process on the other end of a Unix socket connection, as reported by the
+/// kernel via `SO_PEERCRED`. Unlike request headers, these cannot be forged by the client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PeerCredentials {
+    /// The process ID of the client, if available on this platform.
+    pub pid: Option<i32>,
+
+    /// The effective user ID of the client.
+    pub uid: u32,
+
+    /// The effective group ID of the client.
+    pub gid: u32,
+}
+
+impl Listener {
+    /// Binds a new TCP listener to the provided address.
+    pub async fn bind_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<Listener> {
+        Ok(Listener::Tcp(TcpListener::bind(addr).await?))
+    }
+
+    /// Binds a new Unix socket listener at the provided path. If a socket file already exists at
+    /// the path, it is atomically replaced: the new socket is bound at a temporary path and then
+    /// renamed over the original. The path is therefore never missing, and clients never see a
+    /// `NotFound` error while a new server is starting.
+    ///
+    /// The socket file gets the default permissions for the process umask. Use `bind_unix_with_mode`
+    /// to restrict who may connect.
+    #[cfg(unix)]
+    pub fn bind_unix<P: AsRef<Path>>(path: P) -> io::Result<Listener> {
+        Listener::bind_unix_at(path.as_ref(), None)
+    }
+
+    /// Like `bind_unix`, but sets the permissions of the socket file to `mode`, for example `0o660`
+    /// to only allow the owner and group to connect. The permissions are set before the socket is
+    /// renamed into place, so there's no window in which other users could connect.
+    #[cfg(unix)]
+    pub fn bind_unix_with_mode<P: AsRef<Path>>(path: P, mode: u32) -> io::Result<Listener> {
+        Listener::bind_unix_at(path.as_ref(), Some(mode))
+    }
+
+    #[cfg(unix)]
+    fn bind_unix_at(path: &Path, mode: Option<u32>) -> io::Result<Listener> {
+        let file_name = path.file_name().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Unix socket path has no file name: {}", path.display()),
+            )
+        })?;
+        let mut tmp_name = file_name.to_os_string();
+        tmp_name.push(format!(".{}.tmp", process::id()));
+        let tmp_path = path.with_file_name(tmp_name);
+
+        // Clean up any leftover from an earlier attempt by this pid. No-op if already missing.
+        if let Err(e) = fs::remove_file(&tmp_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        let listener = UnixListener::bind(&tmp_path)?;
+        let result = match mode {
+            Some(mode) => fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode)),
+            None => Ok(()),
+        };
+        if let Err(e) = result.and_then(|()| fs::rename(&tmp_path, path)) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        Ok(Listener::Unix(listener))
+    }
+
+    /// Returns the listener that was passed to this process via `LISTEN_FD_ENV`, or `None` if the
+    /// variable isn't set. The variable is removed from the environment so that it isn't passed on
+    /// to any processes spawned later, and the listener can only be taken once: later calls return
+    /// `None`, or an error if the variable was set again. Should be called at startup, before any
+    /// other threads read the environment. Must be called from within a Tokio runtime.
+    #[cfg(unix)]
+    pub fn from_env() -> io::Result<Option<Listener>> {
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+        match fd::take_env_fd(LISTEN_FD_ENV, &TAKEN)? {
+            Some(fd) => Listener::from_fd(fd).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns a listener for an already-bound and listening TCP or Unix socket. The type of
+    /// listener is detected from the socket. Must be called from within a Tokio runtime.
+    #[cfg(unix)]
+    pub fn from_fd(fd: OwnedFd) -> io::Result<Listener> {
+        match fd::socket_family(fd.as_raw_fd())? {
+            SocketFamily::Inet => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+            }
+            SocketFamily::Unix => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Unix(UnixListener::from_std(listener)?))
+            }
+        }
+    }
+
+    /// Configures the provided `Command` to inherit this listener, with `LISTEN_FD_ENV` pointing to
+    /// it. The spawned process can then pick up the listener using `from_env()`. The listener is
+    /// only inherited by this command and not by any other processes spawned by the caller.
+    #[cfg(unix)]
+    pub fn inherit_into<'a>(&self, command: &'a mut Command) -> &'a mut Command {
+        let fd = self.as_raw_fd();
+        command.env(LISTEN_FD_ENV, fd.to_string());
+        unsafe { command.pre_exec(move || fd::clear_cloexec(fd)) }
+    }
+
+    /// Sends this listener to another process over a Unix control socket using `SCM_RIGHTS`. The
+    /// other process can then pick up the listener using `recv_from()`. The listener remains open
+    /// in this process as well, so this process should stop accepting once the other process is
+    /// ready, then drain any in-flight requests.
+    #[cfg(unix)]
+    pub fn send_to(&self, control: &StdUnixStream) -> io::Result<()> {
+        fd::send_fd(control, self.as_raw_fd())
+    }
+
+    /// Receives a listener that was sent over a Unix control socket by `send_to()`. This blocks
+    /// until the listener is received. Must be called from within a Tokio runtime.
+    #[cfg(unix)]
+    pub fn recv_from(control: &StdUnixStream) -> io::Result<Listener> {
+        Listener::from_fd(fd::recv_fd(control)?)
+    }
+
+    /// Waits for and returns the next connection from an SCGI client.
+    pub async fn accept(&self) -> io::Result<(Connection, PeerAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (conn, addr) = listener.accept().await?;
+                Ok((Connection::Tcp(conn), PeerAddr::Tcp(addr)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                let (conn, addr) = listener.accept().await?;
+                Ok((
+                    Connection::Unix(conn),
+                    PeerAddr::Unix(addr.as_pathname().map(Path::to_path_buf)),
+                ))
+            }
+        }
+    }
+}
+
+impl Connection {
+    /// Returns a connection for an already-connected TCP or Unix socket, along with the address of
+    /// its peer. The type of connection is detected from the socket. Must be called from within a
+    /// Tokio runtime.
+    #[cfg(unix)]
+    pub fn from_fd(fd: OwnedFd) -> io::Result<(Connection, PeerAddr)> {
+        match fd::socket_family(fd.as_raw_fd())? {
+            SocketFamily::Inet => {
+                let conn = std::net::TcpStream::from(fd);
+                conn.set_nonblocking(true)?;
+                let addr = conn.peer_addr()?;
+                Ok((
+                    Connection::Tcp(TcpStream::from_std(conn)?),
+                    PeerAddr::Tcp(addr),
+                ))
+            }
+            SocketFamily::Unix => {
+                let conn = StdUnixStream::from(fd);
+                conn.set_nonblocking(true)?;
+                let addr = conn.peer_addr()?;
+                Ok((
+                    Connection::Unix(UnixStream::from_std(conn)?),
+                    PeerAddr::Unix(addr.as_pathname().map(Path::to_path_buf)),
+                ))
+            }
+        }
+    }
+
+    /// Returns the credentials of the client process for Unix socket connections, or `None` for TCP
+    /// connections.
+    pub fn peer_credentials(&self) -> io::Result<Option<PeerCredentials>> {
+        match self {
+            Connection::Tcp(_) => Ok(None),
+            #[cfg(feature = "rustls")]
+            Connection::Tls(_) => Ok(None),
+            #[cfg(unix)]
+            Connection::Unix(conn) => {
+                let cred = conn.peer_cred()?;
+                Ok(Some(PeerCredentials {
+                    pid: cred.pid(),
+                    uid: cred.uid(),
+                    gid: cred.gid(),
+                }))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Connection {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Connection::Tcp(conn) => conn.as_raw_fd(),
+            Connection::Unix(conn) => conn.as_raw_fd(),
+            #[cfg(feature = "rustls")]
+            Connection::Tls(conn) => conn.get_ref().0.as_raw_fd(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(conn) => Pin::new(conn).poll_read(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(conn) => Pin::new(conn).poll_read(cx, buf),
+            #[cfg(feature = "rustls")]
+            Connection::Tls(conn) => Pin::new(conn).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(conn) => Pin::new(conn).poll_write(cx, buf),
+            #[cfg(unix)]
+            Connection::Unix(conn) => Pin::new(conn).poll_write(cx, buf),
+            #[cfg(feature = "rustls")]
+            Connection::Tls(conn) => Pin::new(conn).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(conn) => Pin::new(conn).poll_flush(cx),
+            #[cfg(unix)]
+            Connection::Unix(conn) => Pin::new(conn).poll_flush(cx),
+            #[cfg(feature = "rustls")]
+            Connection::Tls(conn) => Pin::new(conn).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(conn) => Pin::new(conn).poll_shutdown(cx),
+            #[cfg(unix)]
+            Connection::Unix(conn) => Pin::new(conn).poll_shutdown(cx),
+            #[cfg(feature = "rustls")]
+            Connection::Tls(conn) => Pin::new(conn).poll_shutdown(cx),
+        }
+    }
+}
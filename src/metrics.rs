@@ -0,0 +1,119 @@
+#![deny(warnings)]
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::server::SCGIErrorKind;
+
+/// Receives measurements from `runtime::Server` and `server::SCGICodec`, for exporting to a
+/// metrics system such as Prometheus. All methods default to doing nothing, so implementations
+/// only need to provide the ones they're interested in. Methods are called inline while serving
+/// requests, so they should be quick.
+pub trait ScgiMetrics: Send + Sync + 'static {
+    /// A connection has been accepted and is now being served.
+    fn connection_opened(&self) {}
+
+    /// A connection which was reported to `connection_opened()` has been closed, whatever the
+    /// outcome.
+    fn connection_closed(&self) {}
+
+    /// A connection was disconnected because its address or credentials weren't allowed.
+    fn connection_rejected(&self) {}
+
+    /// A connection was turned away because the server was at its concurrency limit.
+    fn connection_shed(&self) {}
+
+    /// A response has been sent with the provided status code. The status is taken from the
+    /// response's `Status:` header or HTTP status line, or is 200 if the response has neither,
+    /// which is what CGI assumes.
+    fn request_completed(&self, _status: u16) {}
+
+    /// A malformed request was received.
+    fn decode_error(&self, _kind: SCGIErrorKind) {}
+
+    /// Request headers were received, with the provided netstring size in bytes.
+    fn header_size(&self, _bytes: usize) {}
+
+    /// A complete request body was received, with the provided size in bytes.
+    fn body_size(&self, _bytes: usize) {}
+
+    /// A response is about to be written, after the provided time since the connection was
+    /// accepted.
+    fn time_to_first_byte(&self, _elapsed: Duration) {}
+}
+
+/// Wraps a shared `ScgiMetrics` so that the codecs can still derive `Clone`, `Debug` and `Eq`.
+/// Handles are equal if they point to the same `ScgiMetrics`.
+#[derive(Clone)]
+pub(crate) struct MetricsHandle(pub(crate) Arc<dyn ScgiMetrics>);
+
+impl fmt::Debug for MetricsHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MetricsHandle")
+    }
+}
+
+impl PartialEq for MetricsHandle {
+    fn eq(&self, other: &MetricsHandle) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for MetricsHandle {}
+
+/// Reports to the `metrics` crate, for use with any `metrics` exporter. Requires the `metrics`
+/// feature. Reports the following:
+///
+/// - `scgi_connections_active`: Gauge of connections being served.
+/// - `scgi_connections_rejected_total`: Counter of connections whose address or credentials
+///   weren't allowed.
+/// - `scgi_connections_shed_total`: Counter of connections turned away by the concurrency limit.
+/// - `scgi_requests_total`: Counter of responses, labeled by `status`.
+/// - `scgi_decode_errors_total`: Counter of malformed requests, labeled by `kind`.
+/// - `scgi_header_bytes`: Histogram of header netstring sizes.
+/// - `scgi_body_bytes`: Histogram of request body sizes.
+/// - `scgi_time_to_first_byte_seconds`: Histogram of the time from accepting a connection to
+///   writing the response.
+#[cfg(feature = "metrics")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MetricsFacade;
+
+#[cfg(feature = "metrics")]
+impl ScgiMetrics for MetricsFacade {
+    fn connection_opened(&self) {
+        ::metrics::gauge!("scgi_connections_active").increment(1.0);
+    }
+
+    fn connection_closed(&self) {
+        ::metrics::gauge!("scgi_connections_active").decrement(1.0);
+    }
+
+    fn connection_rejected(&self) {
+        ::metrics::counter!("scgi_connections_rejected_total").increment(1);
+    }
+
+    fn connection_shed(&self) {
+        ::metrics::counter!("scgi_connections_shed_total").increment(1);
+    }
+
+    fn request_completed(&self, status: u16) {
+        ::metrics::counter!("scgi_requests_total", "status" => status.to_string()).increment(1);
+    }
+
+    fn decode_error(&self, kind: SCGIErrorKind) {
+        ::metrics::counter!("scgi_decode_errors_total", "kind" => kind.as_str()).increment(1);
+    }
+
+    fn header_size(&self, bytes: usize) {
+        ::metrics::histogram!("scgi_header_bytes").record(bytes as f64);
+    }
+
+    fn body_size(&self, bytes: usize) {
+        ::metrics::histogram!("scgi_body_bytes").record(bytes as f64);
+    }
+
+    fn time_to_first_byte(&self, elapsed: Duration) {
+        ::metrics::histogram!("scgi_time_to_first_byte_seconds").record(elapsed.as_secs_f64());
+    }
+}
@@ -0,0 +1,247 @@
+#![deny(warnings)]
+
+use futures::future;
+use std::env;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::Command as StdCommand;
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, Interest};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+use tokio::time;
+
+use crate::fd;
+use crate::listener::{Connection, Listener};
+use crate::respond::is_transient;
+use crate::runtime::{Handler, Server};
+
+/// Environment variable set on worker processes, containing the fd of the worker's control socket.
+pub const WORKER_FD_ENV: &str = "SCGI_PREFORK_WORKER_FD";
+
+/// Environment variable set on worker processes, containing the number of requests to serve before
+/// exiting. Missing if workers are never recycled.
+pub const WORKER_MAX_REQUESTS_ENV: &str = "SCGI_PREFORK_MAX_REQUESTS";
+
+/// How long to wait before restarting a worker which exited with an error or without serving any
+/// requests, to avoid spinning if the worker is failing at startup.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+
+/// The byte sent by a worker to tell the parent that it's ready for another connection.
+const READY: u8 = b'R';
+
+/// Runs a pool of worker processes, each of which serves one connection at a time. The parent
+/// process accepts connections and passes each one to an idle worker over a Unix socketpair using
+/// `SCM_RIGHTS`. This gives handlers process isolation, for example when they call into C libraries
+/// which aren't thread-safe, or which may crash.
+///
+/// Workers are started by re-executing the current binary with the same arguments, with
+/// `WORKER_FD_ENV` set. The binary should therefore check for `Worker::from_env()` at startup,
+/// before binding any listeners, and serve requests as a worker if it returns a `Worker`:
+///
+/// ```no_run
+/// # async fn handle(_: tokio_scgi::runtime::Request) -> Result<Vec<u8>, std::io::Error> { Ok(vec![]) }
+/// # async fn run() -> Result<(), std::io::Error> {
+/// use tokio_scgi::listener::Listener;
+/// use tokio_scgi::prefork::{Prefork, Worker};
+/// use tokio_scgi::runtime::Server;
+///
+/// if let Some(worker) = Worker::from_env()? {
+///     return worker.serve(Server::new(handle)).await;
+/// }
+/// let listener = Listener::bind_unix("/tmp/scgi.sock")?;
+/// Prefork::new(4).max_requests(1000).serve(&listener).await
+/// # }
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Prefork {
+    workers: usize,
+    max_requests: Option<usize>,
+}
+
+impl Prefork {
+    /// Returns a `Prefork` which runs the provided number of worker processes, which must be at
+    /// least 1.
+    pub fn new(workers: usize) -> Prefork {
+        Prefork {
+            workers,
+            max_requests: None,
+        }
+    }
+
+    /// Sets the number of requests that each worker serves before it exits and is replaced by a new
+    /// worker. This limits the impact of any resource leaks in the handler. By default workers are
+    /// never recycled. Must be at least 1.
+    pub fn max_requests(mut self, max_requests: usize) -> Prefork {
+        self.max_requests = Some(max_requests);
+        self
+    }
+
+    /// Starts the workers and passes them connections from the listener. Workers which exit or
+    /// crash are restarted. Only returns if starting a worker or accepting a connection fails, or
+    /// with an `InvalidInput` error if the number of workers or the maximum requests is 0.
+    pub async fn serve(&self, listener: &Listener) -> Result<(), io::Error> {
+        if self.workers == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Prefork needs at least one worker",
+            ));
+        }
+        if self.max_requests == Some(0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Prefork max_requests must be at least 1",
+            ));
+        }
+        future::try_join_all((0..self.workers).map(|_| self.run_slot(listener))).await?;
+        Ok(())
+    }
+
+    /// Keeps one worker process running, passing it connections whenever it's ready.
+    async fn run_slot(&self, listener: &Listener) -> Result<(), io::Error> {
+        // A connection that couldn't be passed to a worker because it had exited. Given to the
+        // replacement worker instead.
+        let mut pending: Option<Connection> = None;
+        loop {
+            let (mut control, mut child) = self.spawn_worker()?;
+            let mut served = 0;
+            let mut ready = [0u8; 1];
+            // Wait for the worker to say it's ready, then give it the next connection. If the
+            // worker closes the control socket, it has exited.
+            while let Ok(1) = control.read(&mut ready).await {
+                let conn = match pending.take() {
+                    Some(conn) => conn,
+                    None => accept(listener).await?,
+                };
+                let sent = control
+                    .async_io(Interest::WRITABLE, || {
+                        fd::send_fd(&control, conn.as_raw_fd())
+                    })
+                    .await;
+                if sent.is_err() {
+                    // The worker exited before it could take the connection.
+                    pending = Some(conn);
+                    break;
+                }
+                served += 1;
+            }
+            let status = child.wait().await?;
+            if !status.success() || served == 0 {
+                time::sleep(RESTART_DELAY).await;
+            }
+        }
+    }
+
+    /// Starts a new worker process, returning the parent's end of its control socket.
+    fn spawn_worker(&self) -> Result<(UnixStream, tokio::process::Child), io::Error> {
+        let (parent_end, worker_end) = StdUnixStream::pair()?;
+        let worker_fd = worker_end.as_raw_fd();
+
+        let mut command = StdCommand::new(env::current_exe()?);
+        command
+            .args(env::args_os().skip(1))
+            .env(WORKER_FD_ENV, worker_fd.to_string());
+        if let Some(max_requests) = self.max_requests {
+            command.env(WORKER_MAX_REQUESTS_ENV, max_requests.to_string());
+        }
+        unsafe {
+            command.pre_exec(move || fd::clear_cloexec(worker_fd));
+        }
+        let child = Command::from(command).kill_on_drop(true).spawn()?;
+        // Only the worker should have this end open, so that we see EOF when the worker exits.
+        drop(worker_end);
+
+        parent_end.set_nonblocking(true)?;
+        Ok((UnixStream::from_std(parent_end)?, child))
+    }
+}
+
+/// Accepts the next connection, retrying if the client gave up before we got to it.
+async fn accept(listener: &Listener) -> Result<Connection, io::Error> {
+    loop {
+        match listener.accept().await {
+            Ok((conn, _peer)) => return Ok(conn),
+            Err(e) if is_transient(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A worker process in a `Prefork` pool. Receives connections from the parent process and serves
+/// them one at a time.
+#[derive(Debug)]
+pub struct Worker {
+    control: UnixStream,
+    max_requests: Option<usize>,
+}
+
+impl Worker {
+    /// Returns a `Worker` if this process was started as a worker by `Prefork`, or `None` if it
+    /// wasn't. `WORKER_FD_ENV` and `WORKER_MAX_REQUESTS_ENV` are removed from the environment so
+    /// that they aren't passed on to any processes the handler spawns, and the control socket can
+    /// only be taken once: later calls return `None`. Should be called at startup, before any other
+    /// threads read the environment. Must be called from within a Tokio runtime.
+    pub fn from_env() -> Result<Option<Worker>, io::Error> {
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+        let fd = match fd::take_env_fd(WORKER_FD_ENV, &TAKEN)? {
+            Some(fd) => fd,
+            None => return Ok(None),
+        };
+        let max_requests = env::var(WORKER_MAX_REQUESTS_ENV).ok();
+        env::remove_var(WORKER_MAX_REQUESTS_ENV);
+        let max_requests = match max_requests {
+            Some(value) => match value.parse() {
+                Ok(max) if max > 0 => Some(max),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "{} is not a positive integer: '{}'",
+                            WORKER_MAX_REQUESTS_ENV, value
+                        ),
+                    ))
+                }
+            },
+            None => None,
+        };
+        let control = StdUnixStream::from(fd);
+        control.set_nonblocking(true)?;
+        Ok(Some(Worker {
+            control: UnixStream::from_std(control)?,
+            max_requests,
+        }))
+    }
+
+    /// Serves connections from the parent process with the provided server, one at a time.
+    /// Returns once the configured maximum number of requests has been served, or when the parent
+    /// process has exited. The caller should then exit the process.
+    pub async fn serve<H: Handler>(mut self, server: Server<H>) -> Result<(), io::Error> {
+        let mut served = 0;
+        while self.max_requests.is_none_or(|max| served < max) {
+            match self.control.write_all(&[READY]).await {
+                Ok(()) => {}
+                // The parent has exited.
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                Err(e) => return Err(e),
+            }
+            let fd = match self
+                .control
+                .async_io(Interest::READABLE, || fd::recv_fd(&self.control))
+                .await
+            {
+                Ok(fd) => fd,
+                // The parent has exited.
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            let (conn, peer) = Connection::from_fd(fd)?;
+            // Errors are reported to the client by the server, nothing else to do.
+            let _ = server.serve_accepted(conn, peer).await;
+            served += 1;
+        }
+        Ok(())
+    }
+}
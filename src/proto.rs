@@ -0,0 +1,473 @@
+#![deny(warnings)]
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use bytes::{BufMut, BytesMut};
+use core::{fmt, mem, str};
+use memchr::{memchr, memchr_iter};
+
+const NUL: u8 = b'\0';
+/// The maximum size in bytes of a single header name or value. This limit is far greater than the
+/// 4k-8k that is enforced by most web servers.
+const MAX_HEADER_STRING_BYTES: usize = 32 * 1024;
+/// The maximum size in bytes for all header content. This limit is far greater than the 4k-8k that
+/// is enforced by most web servers.
+const MAX_HEADER_BYTES: usize = 256 * 1024;
+
+/// The kind of problem found in a malformed SCGI request. See `SCGIError`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SCGIErrorKind {
+    /// The netstring size at the start of the request is empty, has a leading zero, or isn't an
+    /// integer.
+    InvalidHeaderSize,
+
+    /// The netstring size at the start of the request exceeds the maximum header size.
+    HeaderTooLarge,
+
+    /// A header key or value exceeds the maximum size.
+    HeaderStringTooLarge,
+
+    /// A header key or value isn't a UTF-8 string.
+    InvalidHeaderString,
+
+    /// The ',' at the end of the headers is missing, or a header key or value extends past the end
+    /// of the headers.
+    MissingSeparator,
+
+    /// The `CONTENT_LENGTH` header isn't an integer.
+    InvalidContentLength,
+
+    /// The `CONTENT_LENGTH` header exceeds the maximum body size. Only reported by servers which
+    /// are configured with a maximum, such as `runtime::Server::max_body_size()`.
+    BodyTooLarge,
+}
+
+impl SCGIErrorKind {
+    /// Returns a short snake_case name for the kind, suitable for use as a metrics label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SCGIErrorKind::InvalidHeaderSize => "invalid_header_size",
+            SCGIErrorKind::HeaderTooLarge => "header_too_large",
+            SCGIErrorKind::HeaderStringTooLarge => "header_string_too_large",
+            SCGIErrorKind::InvalidHeaderString => "invalid_header_string",
+            SCGIErrorKind::MissingSeparator => "missing_separator",
+            SCGIErrorKind::InvalidContentLength => "invalid_content_length",
+            SCGIErrorKind::BodyTooLarge => "body_too_large",
+        }
+    }
+}
+
+/// Describes a malformed SCGI request. Returned by `Parser::feed()`, and wrapped in the
+/// `io::Error`s returned by `server::SCGICodec::decode()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SCGIError {
+    kind: SCGIErrorKind,
+    message: String,
+}
+
+impl SCGIError {
+    /// Returns a new `SCGIError` with the provided kind and description.
+    pub fn new(kind: SCGIErrorKind, message: String) -> SCGIError {
+        SCGIError { kind, message }
+    }
+
+    /// Returns what kind of problem was found in the request.
+    pub fn kind(&self) -> SCGIErrorKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for SCGIError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl core::error::Error for SCGIError {}
+
+#[cfg(feature = "std")]
+impl SCGIError {
+    /// Returns the `SCGIError` wrapped by the provided `io::Error`, or `None` if it doesn't wrap
+    /// one. Errors from `server::SCGICodec::decode()` and `sync::read_request()` are `io::Error`s
+    /// with kind `InvalidData` wrapping an `SCGIError`.
+    pub fn from_io(e: &std::io::Error) -> Option<&SCGIError> {
+        e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<SCGIError>())
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<SCGIError> for std::io::Error {
+    fn from(e: SCGIError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Macro for simplifying creation of `SCGIError` results of the provided kind
+macro_rules! scgi_err {
+    ($kind:ident, $($arg:tt)*) => (
+        Err(SCGIError::new(SCGIErrorKind::$kind, format!($($arg)+)))
+    )
+}
+
+/// A parsed SCGI request header with key/value header data, and/or bytes from the raw request body.
+/// Decoded by `server::SCGICodec` and encoded by `client::SCGICodec`, which both re-export it, so
+/// a request received from a client can be sent on to another SCGI server as-is.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SCGIRequest {
+    /// The Vec contains the headers. The BytesMut optionally contains raw byte data from
+    /// the request body, which may be followed by additional `BodyFragment`s in later calls.
+    /// The `Content-Length` header, required by SCGI, can be used to detect whether to wait for
+    /// additional `BodyFragment`s.
+    Request(Vec<(String, String)>, BytesMut),
+
+    /// Additional body fragment(s), used for streaming fragmented request body data. These should
+    /// only be relevant in cases where the leading `Request` value doesn't contain all of the body.
+    BodyFragment(BytesMut),
+}
+
+/// Request headers, in the order they were sent.
+type Headers = Vec<(String, String)>;
+
+/// What the `Parser` found in the input passed to `Parser::feed()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event<'a> {
+    /// All of the input was consumed, and more is needed to complete the headers.
+    NeedMore,
+
+    /// The headers are complete, in the order they were sent. The first `consumed` bytes of the
+    /// input were used, and anything after them is the start of the body, which can be passed to
+    /// `feed()` again.
+    Headers {
+        headers: Vec<(String, String)>,
+        consumed: usize,
+    },
+
+    /// All of the input is body content, following the headers. May be empty.
+    Body(&'a [u8]),
+}
+
+/// Internal state while parsing the SCGI request
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+enum State {
+    /// Getting the initial netstring size.
+    /// => Headers when ':' is encountered.
+    #[default]
+    HeaderSize,
+
+    /// Waiting for the header netstring and the ',' separating headers from content.
+    /// => Content when they've all been received and parsed.
+    Headers,
+
+    /// Forwarding any payload content, may match CONTENT_SIZE header.
+    Content,
+}
+
+/// A push parser for SCGI requests, independent of any IO or async runtime. Input is passed to
+/// `feed()` as it's received, in pieces of any size, and each call reports what was found. Input
+/// is only copied while the headers are incomplete: if the headers arrive in one piece they're
+/// parsed in place, and body content is never copied.
+///
+/// ```
+/// use tokio_scgi::proto::{Event, Parser};
+///
+/// let mut parser = Parser::new();
+/// assert_eq!(Event::NeedMore, parser.feed(b"24:CONTENT_LENGTH\x005\x00").unwrap());
+/// let input = b"SCGI\x001\x00,hello";
+/// match parser.feed(input).unwrap() {
+///     Event::Headers { headers, consumed } => {
+///         assert_eq!(2, headers.len());
+///         assert_eq!(Event::Body(b"hello"), parser.feed(&input[consumed..]).unwrap());
+///     }
+///     other => panic!("Unexpected event: {:?}", other),
+/// }
+/// ```
+///
+/// After an error the request can't be recovered, and the parser should be discarded.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Parser {
+    /// Parser state. See `State` for transition info.
+    state: State,
+
+    /// Input received while the headers are incomplete, from the start of the request.
+    pending: Vec<u8>,
+
+    /// Pointer to index where the search for the ':' after the header size should begin in the
+    /// request.
+    next_search_index: usize,
+
+    /// The size of the header size prefix, including the ':'. The header netstring starts here.
+    size_len: usize,
+
+    /// The declared size of the header netstring. There should be a ',' after this many bytes of
+    /// header content.
+    header_size: usize,
+}
+
+impl Parser {
+    /// Returns a `Parser` for a new request.
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    /// Parses the next piece of the request. See `Event` for the possible results.
+    pub fn feed<'a>(&mut self, input: &'a [u8]) -> Result<Event<'a>, SCGIError> {
+        if self.state == State::Content {
+            return Ok(Event::Body(input));
+        }
+        if self.pending.is_empty() {
+            // Try parsing the headers in place, only keeping a copy if they're incomplete.
+            return Ok(match self.parse(input)? {
+                Some((headers, consumed)) => Event::Headers { headers, consumed },
+                None => {
+                    self.pending.extend_from_slice(input);
+                    self.reserve_headers();
+                    Event::NeedMore
+                }
+            });
+        }
+        let previous = self.pending.len();
+        self.pending.extend_from_slice(input);
+        let pending = mem::take(&mut self.pending);
+        Ok(match self.parse(&pending)? {
+            Some((headers, consumed)) => Event::Headers {
+                headers,
+                // The previous input didn't reach the end of the headers.
+                consumed: consumed - previous,
+            },
+            None => {
+                self.pending = pending;
+                self.reserve_headers();
+                Event::NeedMore
+            }
+        })
+    }
+
+    /// Once the header size is known, makes room for the rest of the headers and the ',' so that
+    /// they're copied at most once while they arrive.
+    fn reserve_headers(&mut self) {
+        if self.state == State::Headers {
+            let total = self.size_len + self.header_size + 1;
+            self.pending
+                .reserve(total.saturating_sub(self.pending.len()));
+        }
+    }
+
+    /// Returns the declared size of the header netstring, or 0 if it hasn't been received yet.
+    pub fn header_size(&self) -> usize {
+        self.header_size
+    }
+
+    /// Returns whether the headers are complete, so that any further input is body content.
+    pub fn headers_complete(&self) -> bool {
+        self.state == State::Content
+    }
+
+    /// Switches to a new parser state.
+    fn set_state(&mut self, state: State) {
+        event!(
+            trace,
+            from = ?self.state,
+            to = ?state,
+            header_size = self.header_size,
+            "SCGI decoder state transition"
+        );
+        self.state = state;
+    }
+
+    /// Parses the request received so far, starting from its first byte. Returns the headers and
+    /// the size of the request up to and including the ',' after them, or `None` if they're
+    /// incomplete.
+    fn parse(&mut self, request: &[u8]) -> Result<Option<(Headers, usize)>, SCGIError> {
+        if self.state == State::HeaderSize {
+            // Search for ':' which follows the header size int
+            let end = match memchr(b':', &request[self.next_search_index..]) {
+                Some(offset) => self.next_search_index + offset,
+                None => {
+                    // No ':' yet, try again
+                    self.next_search_index = request.len();
+                    return Ok(None);
+                }
+            };
+            self.header_size = consume_header_size(&request[..end])?;
+            if self.header_size > MAX_HEADER_BYTES {
+                // This declared size is way too long. Bad data? Give up. We just want to avoid
+                // buffering too much data while waiting for the headers.
+                return scgi_err!(
+                    HeaderTooLarge,
+                    "Header size exceeds maximum {} bytes",
+                    MAX_HEADER_BYTES
+                );
+            }
+            self.size_len = end + 1;
+            self.set_state(State::Headers);
+        }
+
+        // Wait for the whole header netstring and the ',' after it. Nothing is scanned until then,
+        // so requests which arrive in many small pieces are only parsed once.
+        let separator = self.size_len + self.header_size;
+        if request.len() <= separator {
+            return Ok(None);
+        }
+        let headers = parse_headers(&request[self.size_len..separator])?;
+        if request[separator] != b',' {
+            // Should always have the comma, missing it implies corrupt input.
+            return scgi_err!(
+                MissingSeparator,
+                "Missing ',' separating headers from content"
+            );
+        }
+        self.set_state(State::Content);
+        event!(
+            debug,
+            headers = headers.len(),
+            body_bytes = request.len() - separator - 1,
+            "Decoded SCGI request headers"
+        );
+        Ok(Some((headers, separator + 1)))
+    }
+}
+
+/// Parses the content of the header netstring in a single pass, finding every NUL with `memchr`.
+/// Keys and values alternate, each followed by a NUL, and the last value's NUL must be the last
+/// byte. An empty netstring has no headers, which the SCGI spec doesn't allow, but let's allow it.
+fn parse_headers(netstring: &[u8]) -> Result<Headers, SCGIError> {
+    // Counting the NULs is cheap compared to regrowing the `Vec` for large header sets.
+    let mut headers = Vec::with_capacity(memchr_iter(NUL, netstring).count() / 2);
+    let mut key = None;
+    let mut start = 0;
+    for end in memchr_iter(NUL, netstring) {
+        let string = &netstring[start..end];
+        start = end + 1;
+        if string.len() > MAX_HEADER_STRING_BYTES {
+            return scgi_err!(
+                HeaderStringTooLarge,
+                "Header key or value size exceeds maximum {} bytes",
+                MAX_HEADER_STRING_BYTES
+            );
+        }
+        match key.take() {
+            None => match consume_header_string(string) {
+                Some(k) => key = Some(k),
+                None => {
+                    return scgi_err!(
+                        InvalidHeaderString,
+                        "Failed to parse header key: Header key or value is not a UTF-8 string"
+                    )
+                }
+            },
+            Some(k) => match consume_header_string(string) {
+                Some(val) => headers.push((k, val)),
+                None => {
+                    return scgi_err!(
+                        InvalidHeaderString,
+                        "Failed to parse value for header {}: Header key or value is not a UTF-8 \
+                         string",
+                        k
+                    )
+                }
+            },
+        }
+    }
+    if start < netstring.len() || key.is_some() {
+        // The declared header size ends partway through a key or value.
+        return scgi_err!(
+            MissingSeparator,
+            "Header string extends past the declared header size of {} bytes",
+            netstring.len()
+        );
+    }
+    Ok(headers)
+}
+
+fn consume_header_size(size: &[u8]) -> Result<usize, SCGIError> {
+    if size.is_empty() {
+        // Got an empty size value, i.e. ':' with no preceding integers.
+        // The header size value cannot be empty, must at least provide a '0:'.
+        return scgi_err!(InvalidHeaderSize, "Header size cannot be an empty string");
+    } else if size.len() > 1 && size[0] == b'0' {
+        // Size cannot start with a '0' unless it's literally '0:' for empty headers
+        return scgi_err!(
+            InvalidHeaderSize,
+            "Header size cannot be a non-zero value with a leading '0'"
+        );
+    }
+    let size_str = match str::from_utf8(size) {
+        Ok(size_str) => size_str,
+        Err(_) => return scgi_err!(InvalidHeaderSize, "Header size is not a UTF-8 string"),
+    };
+    match size_str.parse() {
+        Ok(size) => Ok(size),
+        Err(_) => scgi_err!(
+            InvalidHeaderSize,
+            "Header size is not an integer: '{}'",
+            size_str
+        ),
+    }
+}
+
+fn consume_header_string(bytes: &[u8]) -> Option<String> {
+    str::from_utf8(bytes).ok().map(str::to_string)
+}
+
+/// Why headers couldn't be encoded by `encode_headers()`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EncodeError {
+    /// A header key is empty.
+    EmptyKey,
+
+    /// A header key or value contains a NUL character.
+    NulCharacter,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EncodeError::EmptyKey => "Keys in request header cannot be empty",
+            EncodeError::NulCharacter => {
+                "Keys/values in request header cannot contain NUL character"
+            }
+        })
+    }
+}
+
+impl core::error::Error for EncodeError {}
+
+/// Checks that the headers can be encoded, and returns the size of their netstring content. This
+/// excludes the size prefix and the ',' which `encode_headers()` also writes.
+pub fn headers_len(headers: &[(String, String)]) -> Result<usize, EncodeError> {
+    let mut len: usize = 0;
+    for (k, v) in headers {
+        // Do some basic validation per the SCGI protocol spec.
+        if k.is_empty() {
+            return Err(EncodeError::EmptyKey);
+        }
+        if k.as_bytes().contains(&NUL) || v.as_bytes().contains(&NUL) {
+            return Err(EncodeError::NulCharacter);
+        }
+        // Include 2 x NUL in size:
+        len += k.len() + 1/*NUL*/ + v.len() + 1/*NUL*/;
+    }
+    Ok(len)
+}
+
+/// Writes the headers of an SCGI request into the buffer, as a netstring followed by the ','
+/// separating them from the body. The body can then be written as-is. Nothing is written if the
+/// headers are invalid. Returns the size of the netstring content, as with `headers_len()`.
+pub fn encode_headers<B: BufMut>(
+    headers: &[(String, String)],
+    buf: &mut B,
+) -> Result<usize, EncodeError> {
+    let len = headers_len(headers)?;
+    buf.put_slice(len.to_string().as_bytes());
+    buf.put_u8(b':');
+    for (k, v) in headers {
+        buf.put_slice(k.as_bytes());
+        buf.put_u8(NUL);
+        buf.put_slice(v.as_bytes());
+        buf.put_u8(NUL);
+    }
+    buf.put_u8(b',');
+    Ok(len)
+}
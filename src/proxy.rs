@@ -0,0 +1,831 @@
+#![deny(warnings)]
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::Path;
+#[cfg(feature = "rustls")]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+use tokio_util::codec::Framed;
+
+use crate::client;
+use crate::listener::{Cidr, Connection, Listener};
+use crate::proto::SCGIRequest;
+use crate::respond::error_response;
+use crate::runtime::{self, Handler, HandlerFuture, Overload, Request, Server};
+
+/// Re-exported from `listener`, where it moved so that it can be used without the `proxy` feature.
+pub use crate::listener::Address;
+
+/// Number of points each backend gets on the consistent hash ring. More points spread keys more
+/// evenly across backends, at the cost of a larger ring.
+const RING_POINTS: usize = 160;
+
+/// Health check probes stop reading the response after this many bytes. The status is in the
+/// first line or two, there's no need to read a large body.
+const PROBE_RESPONSE_LIMIT: usize = 8192;
+
+/// How each request is assigned to a backend. Whichever strategy is used, backends which are
+/// failing health checks are skipped, unless every backend is failing them.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Strategy {
+    /// Each request goes to the next backend in turn. In TOML: `strategy = "round-robin"`.
+    #[default]
+    RoundRobin,
+
+    /// Each request goes to the backend with the fewest requests in flight, in turn among any
+    /// ties. In TOML: `strategy = "least-connections"`.
+    LeastConnections,
+
+    /// Requests with the same value for the named header go to the same backend, and only the
+    /// keys of a backend which is added or removed move to another backend. Requests without the
+    /// header are assigned in turn. In TOML: `strategy = { consistent-hash = "HTTP_X_USER_ID" }`.
+    ConsistentHash(String),
+}
+
+/// Active health checks, which periodically send a probe request to each backend. A backend is
+/// taken out of rotation after `fall` failed probes in a row, and put back after `rise`
+/// successful probes in a row. All backends start out healthy.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HealthCheck {
+    /// Time between probes of each backend, in milliseconds.
+    pub interval_ms: u64,
+
+    /// How long a probe may take before it counts as failed, in milliseconds.
+    pub timeout_ms: u64,
+
+    /// The headers of the probe request. `CONTENT_LENGTH` and `SCGI` are added unless they're
+    /// already present.
+    pub headers: BTreeMap<String, String>,
+
+    /// The body of the probe request.
+    pub body: String,
+
+    /// The response status which counts as healthy. Anything else, including no response at all,
+    /// counts as a failed probe.
+    pub expect_status: u16,
+
+    /// Number of failed probes in a row before a healthy backend is taken out of rotation.
+    pub fall: u32,
+
+    /// Number of successful probes in a row before an unhealthy backend is put back.
+    pub rise: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> HealthCheck {
+        let mut headers = BTreeMap::new();
+        headers.insert("REQUEST_METHOD".to_string(), "GET".to_string());
+        headers.insert("REQUEST_URI".to_string(), "/".to_string());
+        HealthCheck {
+            interval_ms: 5000,
+            timeout_ms: 2000,
+            headers,
+            body: String::new(),
+            expect_status: 200,
+            fall: 2,
+            rise: 2,
+        }
+    }
+}
+
+/// Configuration for a `Proxy`, usually loaded from a TOML file:
+///
+/// ```toml
+/// listen = "/run/scgi-proxy.sock"
+/// backends = ["10.0.0.2:4000", "10.0.0.3:4000", "/run/app.sock"]
+/// strategy = { consistent-hash = "HTTP_X_USER_ID" }
+/// retries = 2
+/// connect-timeout-ms = 1000
+/// response-timeout-ms = 60000
+/// header-timeout-ms = 5000
+/// max-body-size = 10485760
+/// max-concurrency = 256
+/// allow-uids = [33]
+///
+/// [health-check]
+/// interval-ms = 5000
+/// headers = { REQUEST_METHOD = "GET", REQUEST_URI = "/health" }
+/// ```
+///
+/// Clients of the proxy are served by a `runtime::Server`, and the timeout, limit and access
+/// control options correspond to its settings of the same name. See `Proxy::server()`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Where to accept connections from SCGI clients, such as the frontend web server.
+    pub listen: Address,
+
+    /// The SCGI servers which requests are forwarded to.
+    pub backends: Vec<Address>,
+
+    /// How each request is assigned to a backend. Defaults to round-robin.
+    #[serde(default)]
+    pub strategy: Strategy,
+
+    /// How many other backends to try when connecting to the assigned backend fails. Requests are
+    /// only retried before anything has been sent, so that they're never sent twice.
+    #[serde(default = "default_retries")]
+    pub retries: usize,
+
+    /// How long to wait when connecting to a backend before trying another, in milliseconds.
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+
+    /// Limit on sending a request to a backend and receiving its complete response, in
+    /// milliseconds. Requests which exceed it get a `504 Gateway Timeout` response.
+    #[serde(default = "default_response_timeout_ms")]
+    pub response_timeout_ms: u64,
+
+    /// Limit on the size of backend responses, in bytes. Larger responses are discarded and the
+    /// client gets a `502 Bad Gateway` response instead.
+    #[serde(default = "default_max_response_size")]
+    pub max_response_size: usize,
+
+    /// Active health checks for the backends. Without this, every backend is always in rotation,
+    /// and only connection failures are retried.
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+
+    /// Limit on receiving the request headers from a client, in milliseconds.
+    #[serde(default)]
+    pub header_timeout_ms: Option<u64>,
+
+    /// Limit on time between receiving fragments of the request body from a client, in
+    /// milliseconds.
+    #[serde(default)]
+    pub body_idle_timeout_ms: Option<u64>,
+
+    /// Limit on receiving the complete request from a client, in milliseconds.
+    #[serde(default)]
+    pub request_timeout_ms: Option<u64>,
+
+    /// Limit on writing the response to a client, in milliseconds.
+    #[serde(default)]
+    pub write_timeout_ms: Option<u64>,
+
+    /// Limit on the size of request bodies, in bytes. Larger requests get a
+    /// `413 Payload Too Large` response without being forwarded.
+    #[serde(default)]
+    pub max_body_size: Option<usize>,
+
+    /// Limit on the number of requests being forwarded at once. Further connections are queued,
+    /// or shed if `shed-retry-after-ms` is set.
+    #[serde(default)]
+    pub max_concurrency: Option<usize>,
+
+    /// With `max-concurrency`, the number of further connections which may be accepted and wait
+    /// to be forwarded. Defaults to 0, leaving them in the listener's backlog.
+    #[serde(default)]
+    pub max_queued: Option<usize>,
+
+    /// With `max-concurrency`, turn away further connections with `503 Service Unavailable`,
+    /// asking them to retry after this many milliseconds, instead of queueing them.
+    #[serde(default)]
+    pub shed_retry_after_ms: Option<u64>,
+
+    /// Unix socket clients running as these user IDs are allowed. If any uids or gids are listed,
+    /// other clients are disconnected.
+    #[serde(default)]
+    pub allow_uids: Vec<u32>,
+
+    /// Unix socket clients running as these group IDs are allowed. See `allow-uids`.
+    #[serde(default)]
+    pub allow_gids: Vec<u32>,
+
+    /// TCP clients within these ranges, such as `"10.0.0.0/8"`, are allowed. If any ranges are
+    /// listed, TCP clients outside of them are disconnected.
+    #[serde(default)]
+    pub allow_cidrs: Vec<Cidr>,
+
+    /// TCP clients within these ranges are disconnected, even if they're within `allow-cidrs`.
+    #[serde(default)]
+    pub deny_cidrs: Vec<Cidr>,
+
+    /// Requires TLS for TCP clients. Requires the `rustls` feature.
+    #[cfg(feature = "rustls")]
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+}
+
+/// TLS settings for TCP clients of a `Proxy`, loaded from PEM files. In TOML:
+///
+/// ```toml
+/// [tls]
+/// cert = "/etc/scgi-proxy/cert.pem"
+/// key = "/etc/scgi-proxy/key.pem"
+/// client-ca = "/etc/scgi-proxy/frontend-ca.pem"
+/// ```
+#[cfg(feature = "rustls")]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// The certificate chain to present to clients, starting with the proxy's own certificate.
+    pub cert: PathBuf,
+
+    /// The private key for the certificate.
+    pub key: PathBuf,
+
+    /// CA certificates which clients must present a certificate signed by. Without this, clients
+    /// aren't asked for a certificate.
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+#[cfg(feature = "rustls")]
+impl TlsConfig {
+    /// Reads the PEM files and returns the resulting server configuration.
+    fn server_config(&self) -> io::Result<Arc<crate::tls::rustls::ServerConfig>> {
+        use crate::tls::rustls::pki_types::pem::PemObject;
+        use crate::tls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+        use crate::tls::rustls::RootCertStore;
+
+        let read = |path: &Path| {
+            fs::read(path).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Failed to read {}: {}", path.display(), e),
+                )
+            })
+        };
+        let invalid = |path: &Path, e: &dyn std::fmt::Display| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid PEM file {}: {}", path.display(), e),
+            )
+        };
+        let read_certs = |path: &Path| {
+            let certs = CertificateDer::pem_slice_iter(&read(path)?)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| invalid(path, &e))?;
+            if certs.is_empty() {
+                return Err(invalid(path, &"No certificates found"));
+            }
+            Ok(certs)
+        };
+        let key =
+            PrivateKeyDer::from_pem_slice(&read(&self.key)?).map_err(|e| invalid(&self.key, &e))?;
+        let client_roots = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots.add(cert).map_err(|e| invalid(path, &e))?;
+                }
+                Some(roots)
+            }
+            None => None,
+        };
+        crate::tls::server_config(read_certs(&self.cert)?, key, client_roots)
+    }
+}
+
+fn default_retries() -> usize {
+    2
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    1000
+}
+
+fn default_response_timeout_ms() -> u64 {
+    60_000
+}
+
+fn default_max_response_size() -> usize {
+    64 * 1024 * 1024
+}
+
+impl Config {
+    /// Parses and checks a TOML config.
+    pub fn parse(toml: &str) -> io::Result<Config> {
+        let config: Config = toml::from_str(toml)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Reads, parses and checks a TOML config file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read config {}: {}", path.display(), e),
+            )
+        })?;
+        Config::parse(&toml)
+    }
+
+    fn check(&self) -> io::Result<()> {
+        let invalid = |msg: &str| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        if self.backends.is_empty() {
+            return invalid("No backends configured");
+        }
+        if let Strategy::ConsistentHash(header) = &self.strategy {
+            if header.is_empty() {
+                return invalid("Consistent hash strategy needs a header name");
+            }
+        }
+        if let Some(check) = &self.health_check {
+            if check.interval_ms == 0 || check.timeout_ms == 0 || check.fall == 0 || check.rise == 0
+            {
+                return invalid(
+                    "Health check interval-ms, timeout-ms, fall and rise must be nonzero",
+                );
+            }
+        }
+        let timeouts = [
+            Some(self.response_timeout_ms),
+            self.header_timeout_ms,
+            self.body_idle_timeout_ms,
+            self.request_timeout_ms,
+            self.write_timeout_ms,
+        ];
+        if timeouts.contains(&Some(0)) {
+            return invalid("Timeouts must be nonzero");
+        }
+        if self.max_response_size == 0 {
+            return invalid("max-response-size must be nonzero");
+        }
+        if self.max_concurrency == Some(0) {
+            return invalid("max-concurrency must be nonzero");
+        }
+        if self.max_queued.is_some() && self.shed_retry_after_ms.is_some() {
+            return invalid("Only one of max-queued and shed-retry-after-ms may be set");
+        }
+        if self.max_concurrency.is_none()
+            && (self.max_queued.is_some() || self.shed_retry_after_ms.is_some())
+        {
+            return invalid("max-queued and shed-retry-after-ms need max-concurrency");
+        }
+        Ok(())
+    }
+}
+
+/// The current state of a backend, as returned by `Proxy::backends()`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BackendStatus {
+    /// The address of the backend.
+    pub address: Address,
+
+    /// Whether the backend is passing its health checks. Always true without health checks.
+    pub healthy: bool,
+
+    /// The number of requests currently being forwarded to the backend.
+    pub active: usize,
+}
+
+#[derive(Debug)]
+struct Backend {
+    address: Address,
+    healthy: AtomicBool,
+    active: AtomicUsize,
+    /// Number of probes in a row which disagreed with `healthy`.
+    streak: AtomicU32,
+}
+
+impl Backend {
+    fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Records the result of a health check probe, taking the backend out of rotation or putting
+    /// it back once it's seen enough results in a row.
+    fn record_probe(&self, passed: bool, check: &HealthCheck) {
+        if passed == self.is_healthy() {
+            self.streak.store(0, Ordering::Relaxed);
+            return;
+        }
+        let streak = self.streak.fetch_add(1, Ordering::Relaxed) + 1;
+        if streak >= if passed { check.rise } else { check.fall } {
+            self.healthy.store(passed, Ordering::Relaxed);
+            self.streak.store(0, Ordering::Relaxed);
+            if passed {
+                event!(info, backend = %self.address, "Backend is healthy again");
+            } else {
+                event!(warn, backend = %self.address, "Backend failed health checks");
+            }
+        }
+    }
+}
+
+/// Counts a request as in flight to a backend, for the least-connections strategy.
+struct Active<'a>(&'a Backend);
+
+impl<'a> Active<'a> {
+    fn new(backend: &'a Backend) -> Active<'a> {
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        Active(backend)
+    }
+}
+
+impl Drop for Active<'_> {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A load balancer for SCGI: Accepts requests from SCGI clients, such as a web server, and
+/// forwards each one to one of several backend SCGI servers, sending back the response. See
+/// `Config` for the options, and the `scgi-proxy` binary for running this standalone.
+///
+/// `Proxy` is a `runtime::Handler`, and clients are served by the `runtime::Server` returned by
+/// `server()`, with the same timeouts, limits and access control as any other handler.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), std::io::Error> {
+/// use tokio_scgi::proxy::{Config, Proxy};
+///
+/// let config = Config::load("/etc/scgi-proxy.toml")?;
+/// let listener = config.listen.bind().await?;
+/// Proxy::new(config)?.serve(&listener).await
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: Config,
+    backends: Vec<Backend>,
+    /// Points on the consistent hash ring, sorted by hash, with the index of their backend.
+    ring: Vec<(u64, usize)>,
+    /// Where the next round-robin rotation starts.
+    next: AtomicUsize,
+    /// Loaded from `config.tls` up front, so that any problems with the files are reported by
+    /// `Proxy::new()`.
+    #[cfg(feature = "rustls")]
+    tls: Option<Arc<crate::tls::rustls::ServerConfig>>,
+}
+
+impl Proxy {
+    /// Returns a proxy for the configured backends, after checking the config and loading any TLS
+    /// certificates.
+    pub fn new(config: Config) -> io::Result<Proxy> {
+        config.check()?;
+        #[cfg(feature = "rustls")]
+        let tls = match &config.tls {
+            Some(tls) => Some(tls.server_config()?),
+            None => None,
+        };
+        let backends = config
+            .backends
+            .iter()
+            .map(|address| Backend {
+                address: address.clone(),
+                healthy: AtomicBool::new(true),
+                active: AtomicUsize::new(0),
+                streak: AtomicU32::new(0),
+            })
+            .collect::<Vec<_>>();
+        let mut ring = Vec::new();
+        if let Strategy::ConsistentHash(_) = config.strategy {
+            for (index, backend) in backends.iter().enumerate() {
+                for point in 0..RING_POINTS {
+                    let key = format!("{}#{}", backend.address, point);
+                    ring.push((hash(key.as_bytes()), index));
+                }
+            }
+            ring.sort_unstable();
+        }
+        Ok(Proxy {
+            inner: Arc::new(Inner {
+                config,
+                backends,
+                ring,
+                next: AtomicUsize::new(0),
+                #[cfg(feature = "rustls")]
+                tls,
+            }),
+        })
+    }
+
+    /// Returns the current state of each backend, in the configured order.
+    pub fn backends(&self) -> Vec<BackendStatus> {
+        self.inner
+            .backends
+            .iter()
+            .map(|backend| BackendStatus {
+                address: backend.address.clone(),
+                healthy: backend.is_healthy(),
+                active: backend.active.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Returns a `runtime::Server` which forwards requests with this proxy, with the timeouts,
+    /// limits, access control and TLS settings from the `Config`. Further settings, such as
+    /// `Server::metrics()`, may be added before serving. Health checks aren't run by the server:
+    /// see `run_health_checks()`, or use `serve()` which runs both.
+    pub fn server(&self) -> Server<Proxy> {
+        let config = &self.inner.config;
+        let mut server = Server::new(self.clone());
+        if let Some(ms) = config.header_timeout_ms {
+            server = server.header_timeout(Duration::from_millis(ms));
+        }
+        if let Some(ms) = config.body_idle_timeout_ms {
+            server = server.body_idle_timeout(Duration::from_millis(ms));
+        }
+        if let Some(ms) = config.request_timeout_ms {
+            server = server.request_timeout(Duration::from_millis(ms));
+        }
+        if let Some(ms) = config.write_timeout_ms {
+            server = server.write_timeout(Duration::from_millis(ms));
+        }
+        if let Some(max) = config.max_body_size {
+            server = server.max_body_size(max);
+        }
+        if let Some(max) = config.max_concurrency {
+            let overload = match config.shed_retry_after_ms {
+                Some(ms) => Overload::Shed {
+                    retry_after: Duration::from_millis(ms),
+                },
+                None => Overload::Queue {
+                    max_queued: config.max_queued.unwrap_or(0),
+                },
+            };
+            server = server.max_concurrency(max, overload);
+        }
+        for uid in &config.allow_uids {
+            server = server.allow_uid(*uid);
+        }
+        for gid in &config.allow_gids {
+            server = server.allow_gid(*gid);
+        }
+        for cidr in &config.allow_cidrs {
+            server = server.allow_cidr(*cidr);
+        }
+        for cidr in &config.deny_cidrs {
+            server = server.deny_cidr(*cidr);
+        }
+        #[cfg(feature = "rustls")]
+        {
+            if let Some(tls) = &self.inner.tls {
+                server = server.tls(tls.clone());
+            }
+        }
+        server
+    }
+
+    /// Accepts and forwards connections from the listener, running health checks in the
+    /// background. Only returns if accepting fails.
+    pub async fn serve(&self, listener: &Listener) -> Result<(), io::Error> {
+        self.serve_with_shutdown(listener, futures::future::pending())
+            .await
+    }
+
+    /// Accepts and forwards connections from the listener with `server()` until `signal`
+    /// completes, running health checks in the background. Then stops accepting new connections
+    /// and waits for any in-flight requests to finish before returning.
+    pub async fn serve_with_shutdown<S>(
+        &self,
+        listener: &Listener,
+        signal: S,
+    ) -> Result<(), io::Error>
+    where
+        S: Future<Output = ()>,
+    {
+        let health_checks = {
+            let proxy = self.clone();
+            tokio::spawn(async move { proxy.run_health_checks().await })
+        };
+        let result = self.server().serve_with_shutdown(listener, signal).await;
+        health_checks.abort();
+        result
+    }
+
+    /// Forwards a request to a backend and returns its response. If no backend can be reached, or
+    /// the backend fails before it has sent a response, the client gets a `502 Bad Gateway`
+    /// response instead, or a `504 Gateway Timeout` if the backend exceeds `response-timeout-ms`.
+    /// Requests are never retried once they've been sent to a backend.
+    async fn forward(&self, request: Request) -> Result<Vec<u8>, io::Error> {
+        let (active, conn) = match self.connect(&request.headers).await {
+            Ok(connected) => connected,
+            Err(_e) => {
+                event!(error, error = %_e, "No backend is available");
+                return Ok(error_response("502 Bad Gateway"));
+            }
+        };
+        let config = &self.inner.config;
+        let exchange = async {
+            let mut backend = Framed::new(conn, client::SCGICodec::new());
+            backend
+                .send(SCGIRequest::Request(request.headers, request.body))
+                .await?;
+            let mut response = Vec::new();
+            while let Some(chunk) = backend.next().await {
+                let chunk = chunk?;
+                if response.len() + chunk.len() > config.max_response_size {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "Response exceeds max-response-size of {} bytes",
+                            config.max_response_size
+                        ),
+                    ));
+                }
+                response.extend_from_slice(&chunk);
+            }
+            Ok::<_, io::Error>(response)
+        };
+        let timeout = Duration::from_millis(config.response_timeout_ms);
+        let response = match time::timeout(timeout, exchange).await {
+            Ok(Ok(response)) if !response.is_empty() => response,
+            Ok(Ok(_)) => {
+                event!(warn, backend = %active.0.address, "Backend closed without responding");
+                error_response("502 Bad Gateway")
+            }
+            Err(_) => {
+                event!(warn, backend = %active.0.address, "Backend timed out");
+                error_response("504 Gateway Timeout")
+            }
+            Ok(Err(_e)) => {
+                event!(warn, backend = %active.0.address, error = %_e, "Failed to forward request");
+                error_response("502 Bad Gateway")
+            }
+        };
+        // The request is in flight until the backend's response has been received.
+        drop(active);
+        Ok(response)
+    }
+
+    /// Connects to the first reachable backend for a request, trying up to `retries` others after
+    /// the assigned one.
+    async fn connect(
+        &self,
+        headers: &[(String, String)],
+    ) -> Result<(Active<'_>, Connection), io::Error> {
+        let timeout = Duration::from_millis(self.inner.config.connect_timeout_ms);
+        let mut last_error = io::Error::other("No backends configured");
+        for index in self.candidates(headers) {
+            let backend = &self.inner.backends[index];
+            last_error = match time::timeout(timeout, backend.address.connect()).await {
+                Ok(Ok(conn)) => return Ok((Active::new(backend), conn)),
+                Ok(Err(e)) => io::Error::new(
+                    e.kind(),
+                    format!("Failed to connect to backend {}: {}", backend.address, e),
+                ),
+                Err(_) => io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Timed out connecting to backend {}", backend.address),
+                ),
+            };
+            event!(warn, error = %last_error, "Failed to connect to backend");
+        }
+        Err(last_error)
+    }
+
+    /// Returns the backends to try for a request, in order, according to the strategy.
+    fn candidates(&self, headers: &[(String, String)]) -> Vec<usize> {
+        let inner = &self.inner;
+        let mut order = match &inner.config.strategy {
+            Strategy::RoundRobin => self.rotation(),
+            Strategy::LeastConnections => {
+                // Stable sort, so that ties stay in round-robin order.
+                let mut order = self.rotation();
+                order.sort_by_key(|i| inner.backends[*i].active.load(Ordering::Relaxed));
+                order
+            }
+            Strategy::ConsistentHash(header) => match headers.iter().find(|(k, _)| k == header) {
+                Some((_, value)) => self.ring_order(value),
+                None => self.rotation(),
+            },
+        };
+        // If every backend is failing health checks, the checks may be what's wrong. Better to
+        // try them anyway than to reject every request.
+        if order.iter().any(|i| inner.backends[*i].is_healthy()) {
+            order.retain(|i| inner.backends[*i].is_healthy());
+        }
+        order.truncate(inner.config.retries.saturating_add(1));
+        order
+    }
+
+    /// Returns every backend, starting from the next in the round-robin rotation.
+    fn rotation(&self) -> Vec<usize> {
+        let count = self.inner.backends.len();
+        let start = self.inner.next.fetch_add(1, Ordering::Relaxed) % count;
+        (start..count).chain(0..start).collect()
+    }
+
+    /// Returns every backend, in the order they appear on the hash ring after the key.
+    fn ring_order(&self, key: &str) -> Vec<usize> {
+        let ring = &self.inner.ring;
+        let start = ring.partition_point(|(point, _)| *point < hash(key.as_bytes()));
+        let mut order = Vec::with_capacity(self.inner.backends.len());
+        for (_, index) in ring[start..].iter().chain(&ring[..start]) {
+            if !order.contains(index) {
+                order.push(*index);
+                if order.len() == self.inner.backends.len() {
+                    break;
+                }
+            }
+        }
+        order
+    }
+
+    /// Probes every backend once, concurrently, and updates whether each one is in rotation.
+    /// Does nothing if health checks aren't configured.
+    pub async fn check_health(&self) {
+        let check = match &self.inner.config.health_check {
+            Some(check) => check,
+            None => return,
+        };
+        futures::future::join_all(self.inner.backends.iter().map(|backend| async move {
+            let result = probe(&backend.address, check).await;
+            if let Err(_e) = &result {
+                event!(debug, backend = %backend.address, error = %_e, "Health check failed");
+            }
+            backend.record_probe(result.is_ok(), check);
+        }))
+        .await;
+    }
+
+    /// Runs `check_health()` at the configured interval, forever. Never completes if health
+    /// checks aren't configured.
+    pub async fn run_health_checks(&self) {
+        let interval = match &self.inner.config.health_check {
+            Some(check) => Duration::from_millis(check.interval_ms),
+            None => return futures::future::pending().await,
+        };
+        loop {
+            self.check_health().await;
+            time::sleep(interval).await;
+        }
+    }
+}
+
+impl Handler for Proxy {
+    fn call(&self, request: Request) -> HandlerFuture {
+        let proxy = self.clone();
+        Box::pin(async move { proxy.forward(request).await })
+    }
+}
+
+/// Sends a health check probe to a backend, returning an error unless it responds in time with
+/// the expected status.
+async fn probe(address: &Address, check: &HealthCheck) -> Result<(), io::Error> {
+    let headers = check
+        .headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    let request = SCGIRequest::Request(headers, BytesMut::from(check.body.as_bytes()));
+    let exchange = async {
+        let codec = client::SCGICodec::new().required_headers();
+        let mut backend = Framed::new(address.connect().await?, codec);
+        backend.send(request).await?;
+        let mut response = Vec::new();
+        while let Some(chunk) = backend.next().await {
+            response.extend_from_slice(&chunk?);
+            if response.len() >= PROBE_RESPONSE_LIMIT {
+                break;
+            }
+        }
+        Ok::<_, io::Error>(response)
+    };
+    let response = time::timeout(Duration::from_millis(check.timeout_ms), exchange)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Health check timed out"))??;
+    if response.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "Backend closed without responding",
+        ));
+    }
+    let status = runtime::response_status(&response);
+    if status != check.expect_status {
+        return Err(io::Error::other(format!(
+            "Got status {}, expected {}",
+            status, check.expect_status
+        )));
+    }
+    Ok(())
+}
+
+/// FNV-1a, followed by a mixing step to spread similar keys around the ring. Unlike the std
+/// hashers, this is stable across builds, so that every proxy with the same backends assigns a key
+/// to the same backend.
+fn hash(key: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in key {
+        h ^= u64::from(*b);
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
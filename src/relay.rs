@@ -0,0 +1,243 @@
+#![deny(warnings)]
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+use crate::proto::SCGIRequest;
+use crate::{client, server, sync};
+
+/// Rewrites the request headers before they're sent to the backend.
+type HeaderHook = Box<dyn Fn(&mut Vec<(String, String)>) + Send + Sync>;
+
+/// Relays a request from an SCGI client, such as a web server, to a backend SCGI server, and
+/// streams the backend's response back to the client. The request headers can be rewritten on the
+/// way through. The body is forwarded as it arrives, up to the client's `CONTENT_LENGTH`.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), std::io::Error> {
+/// use tokio::net::{TcpStream, UnixListener};
+/// use tokio_scgi::{client, relay::Relay, server};
+/// use tokio_util::codec::Framed;
+///
+/// let relay = Relay::new()
+///     .remove_header("HTTP_COOKIE")
+///     .set_header("HTTP_X_FORWARDED_BY", "gateway");
+/// let listener = UnixListener::bind("/run/gateway.sock")?;
+/// loop {
+///     let (conn, _) = listener.accept().await?;
+///     let backend = TcpStream::connect("10.0.0.2:4000").await?;
+///     let mut conn = Framed::new(conn, server::SCGICodec::new());
+///     let mut backend = Framed::new(backend, client::SCGICodec::new());
+///     relay.relay(&mut conn, &mut backend).await?;
+/// }
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Relay {
+    hooks: Vec<HeaderHook>,
+}
+
+impl fmt::Debug for Relay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Relay")
+            .field("hooks", &self.hooks.len())
+            .finish()
+    }
+}
+
+/// What was relayed by `Relay::relay()`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Relayed {
+    /// The number of request body bytes sent to the backend.
+    pub body_bytes: u64,
+
+    /// The number of response bytes sent back to the client.
+    pub response_bytes: u64,
+}
+
+impl Relay {
+    /// Returns a `Relay` which forwards requests without changing them.
+    pub fn new() -> Relay {
+        Relay::default()
+    }
+
+    /// Adds a hook which rewrites the request headers before they're sent to the backend. Hooks
+    /// are run in the order they were added, including those added by `set_header()` and
+    /// `remove_header()`. The body is forwarded according to the client's original
+    /// `CONTENT_LENGTH`, so hooks shouldn't change it.
+    pub fn rewrite_headers<F>(mut self, hook: F) -> Relay
+    where
+        F: Fn(&mut Vec<(String, String)>) + Send + Sync + 'static,
+    {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Sets a request header to the provided value, replacing the first header with the same key
+    /// and removing any others, or adding it at the end if it's missing.
+    pub fn set_header(self, key: &str, value: &str) -> Relay {
+        let key = key.to_string();
+        let value = value.to_string();
+        self.rewrite_headers(move |headers| {
+            let mut found = false;
+            headers.retain_mut(|(k, v)| {
+                if *k != key {
+                    return true;
+                }
+                if found {
+                    return false;
+                }
+                found = true;
+                v.clone_from(&value);
+                true
+            });
+            if !found {
+                headers.push((key.clone(), value.clone()));
+            }
+        })
+    }
+
+    /// Removes any request headers with the provided key.
+    pub fn remove_header(self, key: &str) -> Relay {
+        let key = key.to_string();
+        self.rewrite_headers(move |headers| headers.retain(|(k, _)| *k != key))
+    }
+
+    /// Reads a request from the client and sends it to the backend with `forward_request()`, then
+    /// streams the response back with `forward_response()`. Nothing is sent to the client on
+    /// errors. To respond with an error of its own, such as `502 Bad Gateway` when the backend
+    /// can't be reached, the caller can run the two halves separately.
+    pub async fn relay<C, B>(
+        &self,
+        client: &mut Framed<C, server::SCGICodec>,
+        backend: &mut Framed<B, client::SCGICodec>,
+    ) -> Result<Relayed, io::Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        B: AsyncRead + AsyncWrite + Unpin,
+    {
+        let body_bytes = self.forward_request(client, backend).await?;
+        let response_bytes = Relay::forward_response(backend, client).await?;
+        event!(debug, body_bytes, response_bytes, "Relayed SCGI request");
+        Ok(Relayed {
+            body_bytes,
+            response_bytes,
+        })
+    }
+
+    /// Reads a request from the client, rewrites its headers, and sends it to the backend along
+    /// with its body as the body arrives. Returns the number of body bytes sent.
+    pub async fn forward_request<C, B>(
+        &self,
+        client: &mut Framed<C, server::SCGICodec>,
+        backend: &mut Framed<B, client::SCGICodec>,
+    ) -> Result<u64, io::Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        B: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (headers, body) = Relay::read_headers(client).await?;
+        self.send_request(headers, body, client, backend).await
+    }
+
+    /// Reads the request headers from the client, along with the start of the body if it arrived
+    /// with them. Together with `send_request()`, this allows picking a backend based on the
+    /// headers before connecting to it.
+    pub async fn read_headers<C>(
+        client: &mut Framed<C, server::SCGICodec>,
+    ) -> Result<(Vec<(String, String)>, BytesMut), io::Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        match client.next().await {
+            Some(Ok(SCGIRequest::Request(headers, body))) => Ok((headers, body)),
+            Some(Ok(SCGIRequest::BodyFragment(_))) => {
+                // The codec always produces the Request first.
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Got request body before headers",
+                ))
+            }
+            Some(Err(e)) => Err(e),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before request headers were received",
+            )),
+        }
+    }
+
+    /// Rewrites the headers from `read_headers()` and sends them to the backend, followed by the
+    /// rest of the body as it arrives from the client. Returns the number of body bytes sent.
+    pub async fn send_request<C, B>(
+        &self,
+        mut headers: Vec<(String, String)>,
+        mut body: BytesMut,
+        client: &mut Framed<C, server::SCGICodec>,
+        backend: &mut Framed<B, client::SCGICodec>,
+    ) -> Result<u64, io::Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        B: AsyncRead + AsyncWrite + Unpin,
+    {
+        // No CONTENT_LENGTH, so assume that we've got everything.
+        let content_length = sync::content_length(&headers)?.unwrap_or(body.len() as u64);
+        body.truncate(usize::try_from(content_length).unwrap_or(usize::MAX));
+        for hook in &self.hooks {
+            hook(&mut headers);
+        }
+
+        let mut body_bytes = body.len() as u64;
+        backend.feed(SCGIRequest::Request(headers, body)).await?;
+        while body_bytes < content_length {
+            let mut fragment = match client.next().await {
+                Some(Ok(SCGIRequest::BodyFragment(fragment))) => fragment,
+                Some(Ok(SCGIRequest::Request(_, _))) => {
+                    // The codec only produces one Request per connection.
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Got second request headers in the same connection",
+                    ));
+                }
+                Some(Err(e)) => return Err(e),
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "Connection closed after {} of {} body bytes were received",
+                            body_bytes, content_length
+                        ),
+                    ))
+                }
+            };
+            fragment.truncate(usize::try_from(content_length - body_bytes).unwrap_or(usize::MAX));
+            body_bytes += fragment.len() as u64;
+            backend.feed(SCGIRequest::BodyFragment(fragment)).await?;
+        }
+        backend.flush().await?;
+        Ok(body_bytes)
+    }
+
+    /// Sends everything the backend responds with back to the client as it arrives, until the
+    /// backend closes the connection. Returns the number of response bytes sent.
+    pub async fn forward_response<C, B>(
+        backend: &mut Framed<B, client::SCGICodec>,
+        client: &mut Framed<C, server::SCGICodec>,
+    ) -> Result<u64, io::Error>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+        B: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut response_bytes = 0;
+        while let Some(chunk) = backend.next().await {
+            let chunk = chunk?;
+            response_bytes += chunk.len() as u64;
+            client.send(chunk.to_vec()).await?;
+        }
+        Ok(response_bytes)
+    }
+}
@@ -0,0 +1,35 @@
+#![deny(warnings)]
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+/// The SCGI header which carries the `X-Request-Id` HTTP header, as forwarded by web servers.
+pub const DEFAULT_HEADER: &str = "HTTP_X_REQUEST_ID";
+
+/// The response header which echoes the request ID back to the client.
+pub const RESPONSE_HEADER: &str = "X-Request-Id";
+
+/// The longest request ID which is accepted from a client.
+const MAX_LEN: usize = 128;
+
+/// Returns a new request ID: 32 hex characters, unique within this process and random across
+/// processes.
+pub fn generate() -> String {
+    static PREFIX: OnceLock<u64> = OnceLock::new();
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    // RandomState is seeded from the OS's random number generator.
+    let prefix = *PREFIX.get_or_init(|| RandomState::new().build_hasher().finish());
+    format!(
+        "{:016x}{:016x}",
+        prefix,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Returns whether a request ID received from a client is safe to use and echo back in a response
+/// header: up to 128 printable ASCII characters, without spaces.
+pub fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
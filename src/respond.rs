@@ -0,0 +1,44 @@
+#![deny(warnings)]
+
+//! Responses and error classification shared by the servers in `sync`, `runtime`, `prefork` and
+//! `proxy`.
+
+use std::io;
+
+/// Returns whether an accept error was caused by the client, rather than the listener.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// Returns a plain text CGI response with the provided status, which is also used as the body.
+/// The underlying error isn't included, since it may reveal internal details to the client: callers
+/// should log it instead.
+pub(crate) fn error_response(status: &str) -> Vec<u8> {
+    format!(
+        "Status: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        status.len(),
+        status
+    )
+    .into_bytes()
+}
+
+/// Returns a `503 Service Unavailable` response, asking the client to retry after the provided
+/// delay.
+#[cfg(feature = "runtime")]
+pub(crate) fn overloaded_response(retry_after: std::time::Duration) -> Vec<u8> {
+    let status = "503 Service Unavailable";
+    // Retry-After only supports whole seconds. Round up so that clients don't retry too soon.
+    let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    format!(
+        "Status: {}\r\nRetry-After: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        secs,
+        status.len(),
+        status
+    )
+    .into_bytes()
+}
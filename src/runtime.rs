@@ -0,0 +1,1041 @@
+#![deny(warnings)]
+
+use bytes::{BufMut, BytesMut};
+use futures::{SinkExt, StreamExt};
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::{self, Instant};
+use tokio_util::codec::Framed;
+
+use crate::listener::{Cidr, Connection, Listener, PeerAddr, PeerCredentials};
+use crate::metrics::{MetricsHandle, ScgiMetrics};
+use crate::request_id;
+use crate::respond::{error_response, is_transient, overloaded_response};
+use crate::server::{SCGICodec, SCGIError, SCGIErrorKind, SCGIRequest};
+
+/// A complete SCGI request, with all of the request body collected according to `CONTENT_LENGTH`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Request {
+    /// The request headers, in the order they were sent by the client.
+    pub headers: Vec<(String, String)>,
+
+    /// The request body. If the client sent a `CONTENT_LENGTH` header then this contains that many
+    /// bytes, otherwise it contains whatever arrived alongside the headers.
+    pub body: BytesMut,
+
+    /// The address of the SCGI client that sent the request. This is typically a frontend web
+    /// server, not the end user: see the `REMOTE_ADDR` header for the end user.
+    pub peer: PeerAddr,
+
+    /// The credentials of the SCGI client process, if it connected over a Unix socket. Unlike the
+    /// headers, these are provided by the kernel and can't be forged by the client.
+    pub credentials: Option<PeerCredentials>,
+
+    /// The DER-encoded certificate chain presented by the SCGI client over TLS, starting with the
+    /// client's own certificate. Only populated when the server is configured to verify client
+    /// certificates, in which case the chain has already been verified. Empty otherwise.
+    pub peer_certificates: Vec<Vec<u8>>,
+
+    /// The ID of the request, if `Server::request_id()` is enabled. Taken from the configured
+    /// header if the client provided a valid one, otherwise newly generated.
+    pub request_id: Option<String>,
+}
+
+impl Request {
+    /// Returns the value of the first header with the provided key, or `None` if it's missing.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// The future returned by a `Handler`, resolving to the raw response to send back to the client.
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<Vec<u8>, io::Error>> + Send>>;
+
+/// Produces responses to SCGI requests. The response is sent to the client as-is, and would
+/// typically be a CGI or HTTP response. If an error is returned, it's logged and the client instead
+/// gets a plain `500 Internal Server Error` response, without the error message.
+///
+/// This is implemented for any `Fn(Request) -> impl Future<Output = Result<Vec<u8>, io::Error>>`.
+pub trait Handler: Send + Sync + 'static {
+    /// Returns a future producing the response to the provided request.
+    fn call(&self, request: Request) -> HandlerFuture;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Vec<u8>, io::Error>> + Send + 'static,
+{
+    fn call(&self, request: Request) -> HandlerFuture {
+        Box::pin(self(request))
+    }
+}
+
+/// A timeout which was hit while serving a request. Returned as the inner error of an `io::Error`
+/// with kind `TimedOut`, and can be retrieved using `io::Error::get_ref()` and `downcast_ref()`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Timeout {
+    /// The client took longer than `Server::header_timeout()` to send the request headers.
+    HeaderRead,
+
+    /// The client went longer than `Server::body_idle_timeout()` without sending any request body.
+    BodyIdle,
+
+    /// The client took longer than `Server::request_timeout()` to send the complete request.
+    Request,
+
+    /// The client took longer than `Server::write_timeout()` to accept the response.
+    Write,
+}
+
+impl fmt::Display for Timeout {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Timeout::HeaderRead => "Timed out waiting for request headers",
+            Timeout::BodyIdle => "Timed out waiting for more request body",
+            Timeout::Request => "Timed out waiting for the complete request",
+            Timeout::Write => "Timed out writing the response",
+        })
+    }
+}
+
+impl Error for Timeout {}
+
+impl From<Timeout> for io::Error {
+    fn from(timeout: Timeout) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, timeout)
+    }
+}
+
+/// What a `Server` does with new connections while it's already serving as many connections as
+/// allowed by `Server::max_concurrency()`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Overload {
+    /// Keep accepting up to `max_queued` more connections, which are served in turn as earlier
+    /// connections finish. Once the queue is full, stop accepting, leaving any further connections
+    /// in the listener's backlog until there's room in the queue again.
+    Queue {
+        /// The number of accepted connections which may wait to be served.
+        max_queued: usize,
+    },
+
+    /// Immediately respond to new connections with `503 Service Unavailable` and close them,
+    /// without reading the request. The response includes a `Retry-After` header with the
+    /// provided delay, rounded up to whole seconds.
+    Shed {
+        /// How long the client should wait before trying again.
+        retry_after: Duration,
+    },
+}
+
+/// How long to keep discarding input from a connection after responding with
+/// `503 Service Unavailable`. Closing a socket with unread input makes the kernel reset the
+/// connection, which may discard the response before the client has read it.
+const SHED_LINGER: Duration = Duration::from_secs(1);
+
+/// Serves SCGI requests from a `Listener`, passing each request to a `Handler`. Each connection is
+/// served in its own task. Requests are parsed with `server::SCGICodec`.
+pub struct Server<H> {
+    handler: Arc<H>,
+    config: Arc<Config>,
+    limit: Option<Arc<Limit>>,
+    rejected: Arc<AtomicU64>,
+    shed: Arc<AtomicU64>,
+}
+
+/// The concurrency limit set by `Server::max_concurrency()`, shared between connection tasks.
+#[derive(Debug)]
+struct Limit {
+    overload: Overload,
+
+    /// One permit for each connection that may be served at once.
+    serving: Arc<Semaphore>,
+
+    /// One permit for each connection that may be accepted at once, whether it's being served or
+    /// waiting to be served. Only used with `Overload::Queue`.
+    accepted: Option<Arc<Semaphore>>,
+}
+
+/// Server settings, shared between connection tasks.
+#[derive(Clone, Debug, Default)]
+struct Config {
+    /// Unix socket clients whose uid is listed here are allowed.
+    allowed_uids: Vec<u32>,
+
+    /// Unix socket clients whose gid is listed here are allowed.
+    allowed_gids: Vec<u32>,
+
+    /// TCP clients within these ranges are allowed.
+    allowed_cidrs: Vec<Cidr>,
+
+    /// TCP clients within these ranges are denied, even if they're allowed by `allowed_cidrs`.
+    denied_cidrs: Vec<Cidr>,
+
+    /// Limit on receiving the request headers, starting from when the connection is accepted.
+    header_timeout: Option<Duration>,
+
+    /// Limit on time between receiving fragments of the request body.
+    body_idle_timeout: Option<Duration>,
+
+    /// Limit on receiving the complete request, starting from when the connection is accepted.
+    request_timeout: Option<Duration>,
+
+    /// Limit on writing the response to the client.
+    write_timeout: Option<Duration>,
+
+    /// Limit on the size of the request body.
+    max_body_size: Option<usize>,
+
+    /// Where request counts, sizes and latencies are reported, if anywhere.
+    metrics: Option<MetricsHandle>,
+
+    /// The header containing the request ID, if request IDs are enabled.
+    request_id_header: Option<String>,
+
+    /// TLS settings for TCP connections, or `None` for plaintext.
+    #[cfg(feature = "rustls")]
+    tls: Option<Arc<crate::tls::rustls::ServerConfig>>,
+}
+
+impl Config {
+    /// Returns the configured `ScgiMetrics`, if any.
+    fn metrics(&self) -> Option<&dyn ScgiMetrics> {
+        self.metrics.as_ref().map(|m| &*m.0)
+    }
+
+    /// Returns an error if the client's address isn't allowed to connect.
+    fn check_addr(&self, peer: &PeerAddr) -> Result<(), io::Error> {
+        let ip = match peer {
+            PeerAddr::Tcp(addr) => addr.ip(),
+            // Address ranges only apply to TCP clients.
+            PeerAddr::Unix(_) => return Ok(()),
+        };
+        if self.denied_cidrs.iter().any(|cidr| cidr.contains(&ip))
+            || (!self.allowed_cidrs.is_empty()
+                && !self.allowed_cidrs.iter().any(|cidr| cidr.contains(&ip)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Client address {} is not allowed", ip),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns an error if the client's credentials aren't allowed to connect.
+    fn check_credentials(&self, credentials: Option<PeerCredentials>) -> Result<(), io::Error> {
+        if self.allowed_uids.is_empty() && self.allowed_gids.is_empty() {
+            // Access control isn't enabled.
+            return Ok(());
+        }
+        match credentials {
+            Some(c) if self.allowed_uids.contains(&c.uid) || self.allowed_gids.contains(&c.gid) => {
+                Ok(())
+            }
+            Some(c) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("Client uid={} gid={} is not allowed", c.uid, c.gid),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Client credentials are unavailable, only Unix socket clients can be allowed",
+            )),
+        }
+    }
+}
+
+impl<H> Clone for Server<H> {
+    fn clone(&self) -> Self {
+        Server {
+            handler: self.handler.clone(),
+            config: self.config.clone(),
+            limit: self.limit.clone(),
+            rejected: self.rejected.clone(),
+            shed: self.shed.clone(),
+        }
+    }
+}
+
+impl<H: Handler> Server<H> {
+    /// Returns a `Server` which passes requests to the provided `Handler`.
+    pub fn new(handler: H) -> Server<H> {
+        Server {
+            handler: Arc::new(handler),
+            config: Arc::new(Config::default()),
+            limit: None,
+            rejected: Arc::new(AtomicU64::new(0)),
+            shed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Allows Unix socket clients running as the provided user ID. Once any uids or gids are
+    /// allowed, clients which don't match are disconnected before any of their request is read.
+    /// TCP clients have no credentials and are therefore always disconnected in that case.
+    pub fn allow_uid(mut self, uid: u32) -> Server<H> {
+        Arc::make_mut(&mut self.config).allowed_uids.push(uid);
+        self
+    }
+
+    /// Allows Unix socket clients running as the provided group ID. See `allow_uid()`.
+    pub fn allow_gid(mut self, gid: u32) -> Server<H> {
+        Arc::make_mut(&mut self.config).allowed_gids.push(gid);
+        self
+    }
+
+    /// Allows TCP clients within the provided address range. Once any ranges are allowed, clients
+    /// outside of them are disconnected before any of their request is read. Unix socket clients
+    /// aren't affected.
+    pub fn allow_cidr(mut self, cidr: Cidr) -> Server<H> {
+        Arc::make_mut(&mut self.config).allowed_cidrs.push(cidr);
+        self
+    }
+
+    /// Denies TCP clients within the provided address range, even if they're within an allowed
+    /// range. Unix socket clients aren't affected.
+    pub fn deny_cidr(mut self, cidr: Cidr) -> Server<H> {
+        Arc::make_mut(&mut self.config).denied_cidrs.push(cidr);
+        self
+    }
+
+    /// Requires TLS for TCP clients, using the provided configuration. See `tls::server_config()`.
+    /// If the configuration verifies client certificates, the verified certificate chain is passed
+    /// to the handler in `Request::peer_certificates`. Unix socket clients aren't affected. Requires
+    /// the `rustls` feature.
+    #[cfg(feature = "rustls")]
+    pub fn tls(mut self, config: Arc<crate::tls::rustls::ServerConfig>) -> Server<H> {
+        Arc::make_mut(&mut self.config).tls = Some(config);
+        self
+    }
+
+    /// Sets the maximum time for a client to send the request headers, starting from when the
+    /// connection is accepted. This includes any TLS handshake. Protects against clients which
+    /// open connections and then send nothing, or send the headers very slowly. By default there
+    /// is no limit.
+    pub fn header_timeout(mut self, timeout: Duration) -> Server<H> {
+        Arc::make_mut(&mut self.config).header_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum time to wait for more of the request body, after the headers or the
+    /// previous part of the body was received. By default there is no limit.
+    pub fn body_idle_timeout(mut self, timeout: Duration) -> Server<H> {
+        Arc::make_mut(&mut self.config).body_idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum time for a client to send the complete request including the body,
+    /// starting from when the connection is accepted. Protects against clients which keep sending
+    /// the body just quickly enough to avoid the `body_idle_timeout()`. The time taken by the
+    /// handler isn't included. By default there is no limit.
+    pub fn request_timeout(mut self, timeout: Duration) -> Server<H> {
+        Arc::make_mut(&mut self.config).request_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the maximum time to wait for the client to accept the response. By default there is no
+    /// limit.
+    pub fn write_timeout(mut self, timeout: Duration) -> Server<H> {
+        Arc::make_mut(&mut self.config).write_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the size of request bodies. Requests which declare a larger `CONTENT_LENGTH` get a
+    /// `413 Payload Too Large` response, without any of their body being read. By default there is
+    /// no limit, and each client may make the server buffer as much body as it declares.
+    pub fn max_body_size(mut self, max: usize) -> Server<H> {
+        Arc::make_mut(&mut self.config).max_body_size = Some(max);
+        self
+    }
+
+    /// Reports request counts, sizes and latencies to the provided `ScgiMetrics`. See
+    /// `metrics::MetricsFacade` for reporting to the `metrics` crate.
+    pub fn metrics(mut self, metrics: Arc<dyn ScgiMetrics>) -> Server<H> {
+        Arc::make_mut(&mut self.config).metrics = Some(MetricsHandle(metrics));
+        self
+    }
+
+    /// Enables request IDs, taken from the provided header such as `request_id::DEFAULT_HEADER`.
+    /// If a request doesn't have a valid ID in the header, a new one is generated. The ID is passed
+    /// to the handler in `Request::request_id`, and added to the response in an `X-Request-Id`
+    /// header unless the handler already included one.
+    pub fn request_id(mut self, header: &str) -> Server<H> {
+        Arc::make_mut(&mut self.config).request_id_header = Some(header.to_string());
+        self
+    }
+
+    /// Limits the number of connections which `serve()` and `serve_with_shutdown()` serve at once.
+    /// Once the limit is reached, new connections are handled according to `overload`. By default
+    /// there is no limit, and a spike in traffic could use up all available memory.
+    pub fn max_concurrency(mut self, max: usize, overload: Overload) -> Server<H> {
+        let accepted = match overload {
+            Overload::Queue { max_queued } => Some(Arc::new(Semaphore::new(max + max_queued))),
+            Overload::Shed { .. } => None,
+        };
+        self.limit = Some(Arc::new(Limit {
+            overload,
+            serving: Arc::new(Semaphore::new(max)),
+            accepted,
+        }));
+        self
+    }
+
+    /// Returns the number of connections which have been disconnected because their address or
+    /// credentials weren't allowed.
+    pub fn rejected_connections(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of connections which have been turned away with
+    /// `503 Service Unavailable` because the server was at its concurrency limit.
+    pub fn shed_connections(&self) -> u64 {
+        self.shed.load(Ordering::Relaxed)
+    }
+
+    /// Accepts and serves connections from the listener until an accept error occurs.
+    pub async fn serve(&self, listener: &Listener) -> Result<(), io::Error> {
+        self.serve_with_shutdown(listener, futures::future::pending())
+            .await
+    }
+
+    /// Accepts and serves connections from the listener until `signal` completes. Then stops
+    /// accepting new connections and waits for any in-flight requests to finish before returning.
+    ///
+    /// The listener itself is left open, so that it can be handed off to a replacement process
+    /// before `signal` is triggered. Any connections which are still queued on the listener will
+    /// then be accepted by the replacement.
+    pub async fn serve_with_shutdown<S>(
+        &self,
+        listener: &Listener,
+        signal: S,
+    ) -> Result<(), io::Error>
+    where
+        S: Future<Output = ()>,
+    {
+        let mut in_flight = JoinSet::new();
+        tokio::pin!(signal);
+        let result = loop {
+            tokio::select! {
+                _ = &mut signal => break Ok(()),
+                accepted = self.accept(listener) => match accepted {
+                    Ok((conn, peer, queued)) => {
+                        let server = self.clone();
+                        in_flight.spawn(async move {
+                            // Errors are reported to the client by serve_accepted(), nothing else
+                            // to do.
+                            let _ = server.serve_limited(conn, peer, queued).await;
+                        });
+                    }
+                    // The client gave up before we got to it, keep going.
+                    Err(e) if is_transient(&e) => {
+                        event!(debug, error = %e, "Client disconnected before it was accepted");
+                    }
+                    Err(e) => {
+                        event!(error, error = %e, "Failed to accept connection");
+                        break Err(e);
+                    }
+                },
+                // Reap finished connections so that they don't accumulate.
+                Some(_) = in_flight.join_next(), if !in_flight.is_empty() => {}
+            }
+        };
+        // Drain: wait for all in-flight requests to finish.
+        event!(
+            info,
+            in_flight = in_flight.len(),
+            "Stopped accepting, waiting for in-flight requests"
+        );
+        while in_flight.join_next().await.is_some() {}
+        result
+    }
+
+    /// Accepts the next connection. With `Overload::Queue`, first waits until there's room in the
+    /// queue, returning the queue permit to be held while the connection is open.
+    async fn accept(
+        &self,
+        listener: &Listener,
+    ) -> Result<(Connection, PeerAddr, Option<OwnedSemaphorePermit>), io::Error> {
+        let queued = match self.limit.as_ref().and_then(|l| l.accepted.clone()) {
+            Some(accepted) => Some(
+                accepted
+                    .acquire_owned()
+                    .await
+                    .expect("Semaphore is never closed"),
+            ),
+            None => None,
+        };
+        let (conn, peer) = listener.accept().await?;
+        Ok((conn, peer, queued))
+    }
+
+    /// Serves a connection once it's within the concurrency limit, or sheds it if the server is
+    /// overloaded. `_queued` is held until the connection has been served.
+    async fn serve_limited(
+        &self,
+        conn: Connection,
+        peer: PeerAddr,
+        _queued: Option<OwnedSemaphorePermit>,
+    ) -> Result<(), io::Error> {
+        let limit = match &self.limit {
+            Some(limit) => limit,
+            None => return self.serve_accepted(conn, peer).await,
+        };
+        let _serving = match limit.overload {
+            Overload::Queue { .. } => limit
+                .serving
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("Semaphore is never closed"),
+            Overload::Shed { retry_after } => match limit.serving.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => return self.shed(conn, peer, retry_after).await,
+            },
+        };
+        self.serve_accepted(conn, peer).await
+    }
+
+    /// Responds with `503 Service Unavailable` without reading the request, then closes the
+    /// connection.
+    async fn shed(
+        &self,
+        conn: Connection,
+        peer: PeerAddr,
+        retry_after: Duration,
+    ) -> Result<(), io::Error> {
+        self.in_connection(&peer, Instant::now(), async {
+            self.check_peer(&peer, conn.peer_credentials()?)?;
+            self.shed.fetch_add(1, Ordering::Relaxed);
+            if let Some(metrics) = self.config.metrics() {
+                metrics.connection_shed();
+            }
+            record!("outcome", "shed");
+            event!(
+                warn,
+                "Server is at its concurrency limit, shedding connection"
+            );
+            self.respond_overloaded(conn, retry_after).await
+        })
+        .await
+    }
+
+    /// Sends a `503 Service Unavailable` response and closes the connection.
+    async fn respond_overloaded(
+        &self,
+        mut conn: Connection,
+        retry_after: Duration,
+    ) -> Result<(), io::Error> {
+        let e = io::Error::other("Server is at its concurrency limit");
+        #[cfg(feature = "rustls")]
+        {
+            if self.config.tls.is_some() && matches!(conn, Connection::Tcp(_)) {
+                // Not worth doing a TLS handshake while overloaded, just close the connection.
+                return Err(e);
+            }
+        }
+        let response = overloaded_response(retry_after);
+        if let Some(metrics) = self.config.metrics() {
+            metrics.request_completed(503);
+        }
+        let deadline = self.config.write_timeout.map(|t| Instant::now() + t);
+        within(&[(deadline, Timeout::Write)], async {
+            conn.write_all(&response).await?;
+            conn.shutdown().await
+        })
+        .await??;
+        // Give the client a chance to read the response before the connection is closed.
+        let mut discard = [0u8; 1024];
+        let _ = time::timeout(SHED_LINGER, async {
+            while let Ok(1..) = conn.read(&mut discard).await {}
+        })
+        .await;
+        Err(e)
+    }
+
+    /// Returns an error if the client's address or credentials aren't allowed to connect.
+    fn check_peer(
+        &self,
+        peer: &PeerAddr,
+        credentials: Option<PeerCredentials>,
+    ) -> Result<(), io::Error> {
+        let result = self
+            .config
+            .check_addr(peer)
+            .and_then(|()| self.config.check_credentials(credentials));
+        if let Err(_e) = &result {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            if let Some(metrics) = self.config.metrics() {
+                metrics.connection_rejected();
+            }
+            record!("outcome", "rejected");
+            event!(info, error = %_e, "Rejected connection");
+        }
+        result
+    }
+
+    /// Serves a single request from an already-accepted connection, then closes it. The client's
+    /// address and credentials are checked against any allowed/denied ranges and uids/gids before
+    /// the request is read. The concurrency limit isn't applied.
+    pub async fn serve_accepted(&self, conn: Connection, peer: PeerAddr) -> Result<(), io::Error> {
+        let accepted = Instant::now();
+        self.in_connection(
+            &peer.clone(),
+            accepted,
+            self.serve_checked(conn, peer, accepted),
+        )
+        .await
+    }
+
+    async fn serve_checked(
+        &self,
+        conn: Connection,
+        peer: PeerAddr,
+        accepted: Instant,
+    ) -> Result<(), io::Error> {
+        let credentials = conn.peer_credentials()?;
+        // Just close the connection if the client isn't allowed. Don't tell them anything.
+        self.check_peer(&peer, credentials)?;
+        #[cfg(feature = "rustls")]
+        let (conn, peer_certificates) = match &self.config.tls {
+            Some(tls) => {
+                match self
+                    .config
+                    .within_header_deadline(accepted, crate::tls::accept(tls.clone(), conn))
+                    .await
+                    .and_then(|handshake| handshake)
+                {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        record!("outcome", "tls_error");
+                        return Err(e);
+                    }
+                }
+            }
+            None => (conn, Vec::new()),
+        };
+        #[cfg(not(feature = "rustls"))]
+        let peer_certificates = Vec::new();
+        let info = PeerInfo {
+            addr: peer,
+            credentials,
+            peer_certificates,
+            accepted,
+        };
+        self.serve_with(conn, info).await
+    }
+
+    /// Serves a single request from a connection, then closes it. This allows serving any kind of
+    /// stream, but unlike `serve_accepted()` the client isn't checked against any allowed/denied
+    /// ranges or uids/gids, so this must not be used for untrusted clients.
+    pub async fn serve_connection<C>(&self, conn: C, peer: PeerAddr) -> Result<(), io::Error>
+    where
+        C: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let info = PeerInfo {
+            addr: peer.clone(),
+            credentials: None,
+            peer_certificates: Vec::new(),
+            accepted: Instant::now(),
+        };
+        self.in_connection(&peer, info.accepted, self.serve_with(conn, info))
+            .await
+    }
+
+    async fn serve_with<C>(&self, conn: C, info: PeerInfo) -> Result<(), io::Error>
+    where
+        C: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let codec = match self.config.metrics.clone() {
+            Some(metrics) => SCGICodec::with_metrics(metrics.0),
+            None => SCGICodec::new(),
+        };
+        let mut framed = Framed::new(conn, codec);
+        let accepted = info.accepted;
+        let request = match read_request(&mut framed, info, &self.config).await {
+            Ok(request) => request,
+            Err(e) => {
+                let too_large =
+                    SCGIError::from_io(&e).is_some_and(|e| e.kind() == SCGIErrorKind::BodyTooLarge);
+                let (status, _outcome) = match e.kind() {
+                    io::ErrorKind::InvalidData if too_large => {
+                        ("413 Payload Too Large", "body_too_large")
+                    }
+                    // InvalidData implies an error from the SCGI codec. The request was malformed.
+                    io::ErrorKind::InvalidData => ("400 Bad Request", "bad_request"),
+                    io::ErrorKind::TimedOut => ("408 Request Timeout", "timeout"),
+                    _ => {
+                        record!("outcome", "read_error");
+                        return Err(e);
+                    }
+                };
+                record!("outcome", _outcome);
+                event!(debug, error = %e, "Rejected request");
+                self.respond(&mut framed, accepted, error_response(status))
+                    .await?;
+                return Err(e);
+            }
+        };
+        record!("method", request.header("REQUEST_METHOD"));
+        record!("uri", request.header("REQUEST_URI"));
+        record!(
+            "header_bytes",
+            request
+                .headers
+                .iter()
+                .map(|(k, v)| k.len() + v.len() + 2)
+                .sum::<usize>()
+        );
+        record!("body_bytes", request.body.len());
+        if let Some(metrics) = self.config.metrics() {
+            metrics.body_size(request.body.len());
+        }
+        let request_id = request.request_id.clone();
+        match self.handler.call(request).await {
+            Ok(response) => {
+                record!("outcome", "ok");
+                let response = with_request_id(response, request_id.as_deref());
+                self.respond(&mut framed, accepted, response).await
+            }
+            Err(e) => {
+                record!("outcome", "handler_error");
+                event!(error, error = %e, "Handler failed");
+                let response = error_response("500 Internal Server Error");
+                let response = with_request_id(response, request_id.as_deref());
+                self.respond(&mut framed, accepted, response).await?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends the response to the client, within the write timeout.
+    async fn respond<C>(
+        &self,
+        framed: &mut Framed<C, SCGICodec>,
+        accepted: Instant,
+        response: Vec<u8>,
+    ) -> Result<(), io::Error>
+    where
+        C: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        if let Some(metrics) = self.config.metrics() {
+            metrics.time_to_first_byte(accepted.elapsed());
+            metrics.request_completed(response_status(&response));
+        }
+        let deadline = self.config.write_timeout.map(|t| Instant::now() + t);
+        within(&[(deadline, Timeout::Write)], framed.send(response)).await?
+    }
+
+    /// Runs `fut` as an active connection in the metrics, within a span for the connection when
+    /// the `tracing` feature is enabled. Emits an event with the connection's duration and result
+    /// once it completes.
+    async fn in_connection<F>(
+        &self,
+        peer: &PeerAddr,
+        accepted: Instant,
+        fut: F,
+    ) -> Result<(), io::Error>
+    where
+        F: Future<Output = Result<(), io::Error>>,
+    {
+        let _active = ActiveConnection::new(self.config.metrics());
+        in_connection_span(peer, accepted, fut).await
+    }
+}
+
+/// Reports a connection as active to the metrics for as long as it's held.
+struct ActiveConnection<'a>(Option<&'a dyn ScgiMetrics>);
+
+impl<'a> ActiveConnection<'a> {
+    fn new(metrics: Option<&'a dyn ScgiMetrics>) -> ActiveConnection<'a> {
+        if let Some(metrics) = metrics {
+            metrics.connection_opened();
+        }
+        ActiveConnection(metrics)
+    }
+}
+
+impl Drop for ActiveConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(metrics) = self.0 {
+            metrics.connection_closed();
+        }
+    }
+}
+
+/// Runs `fut` within a span for the connection when the `tracing` feature is enabled, and emits an
+/// event with the connection's duration and result once it completes.
+async fn in_connection_span<F>(peer: &PeerAddr, accepted: Instant, fut: F) -> Result<(), io::Error>
+where
+    F: Future<Output = Result<(), io::Error>>,
+{
+    #[cfg(feature = "tracing")]
+    {
+        use tracing::field::Empty;
+        use tracing::Instrument;
+
+        let span = tracing::info_span!(
+            "scgi_connection",
+            peer = %peer,
+            method = Empty,
+            uri = Empty,
+            request_id = Empty,
+            header_bytes = Empty,
+            body_bytes = Empty,
+            duration_ms = Empty,
+            outcome = Empty,
+        );
+        let result = fut.instrument(span.clone()).await;
+        span.record("duration_ms", accepted.elapsed().as_millis() as u64);
+        let _entered = span.enter();
+        match &result {
+            Ok(()) => tracing::debug!("Connection finished"),
+            Err(e) => tracing::debug!(error = %e, "Connection finished with error"),
+        }
+        result
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (peer, accepted);
+        fut.await
+    }
+}
+
+/// What we know about a client before reading its request.
+struct PeerInfo {
+    addr: PeerAddr,
+    credentials: Option<PeerCredentials>,
+    peer_certificates: Vec<Vec<u8>>,
+    accepted: Instant,
+}
+
+impl Config {
+    /// Runs `fut` until the header or request deadline for a connection accepted at `accepted`.
+    #[cfg(feature = "rustls")]
+    async fn within_header_deadline<F: Future>(
+        &self,
+        accepted: Instant,
+        fut: F,
+    ) -> Result<F::Output, io::Error> {
+        let header_deadline = self.header_timeout.map(|t| accepted + t);
+        let request_deadline = self.request_timeout.map(|t| accepted + t);
+        within(
+            &[
+                (header_deadline, Timeout::HeaderRead),
+                (request_deadline, Timeout::Request),
+            ],
+            fut,
+        )
+        .await
+    }
+}
+
+/// Runs `fut` until the earliest of the provided deadlines, if any. If a deadline is hit, returns
+/// the error for that deadline.
+async fn within<F: Future>(
+    deadlines: &[(Option<Instant>, Timeout)],
+    fut: F,
+) -> Result<F::Output, io::Error> {
+    let earliest = deadlines
+        .iter()
+        .filter_map(|(deadline, timeout)| deadline.map(|d| (d, *timeout)))
+        .min_by_key(|(deadline, _)| *deadline);
+    match earliest {
+        Some((deadline, timeout)) => time::timeout_at(deadline, fut)
+            .await
+            .map_err(|_| timeout.into()),
+        None => Ok(fut.await),
+    }
+}
+
+/// Reads the request headers, followed by however much body is declared by `CONTENT_LENGTH`.
+async fn read_request<C>(
+    framed: &mut Framed<C, SCGICodec>,
+    info: PeerInfo,
+    config: &Config,
+) -> Result<Request, io::Error>
+where
+    C: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    let header_deadline = config.header_timeout.map(|t| info.accepted + t);
+    let request_deadline = config.request_timeout.map(|t| info.accepted + t);
+    let deadlines = [
+        (header_deadline, Timeout::HeaderRead),
+        (request_deadline, Timeout::Request),
+    ];
+    let (headers, body) = match within(&deadlines, framed.next()).await? {
+        Some(Ok(SCGIRequest::Request(headers, body))) => (headers, body),
+        Some(Ok(SCGIRequest::BodyFragment(_))) => {
+            // The codec always produces the Request first.
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Got request body before headers",
+            ));
+        }
+        Some(Err(e)) => return Err(e),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Connection closed before request headers were received",
+            ))
+        }
+    };
+    let mut request = Request {
+        headers,
+        body,
+        peer: info.addr,
+        credentials: info.credentials,
+        peer_certificates: info.peer_certificates,
+        request_id: None,
+    };
+    if let Some(header) = &config.request_id_header {
+        let id = match request.header(header) {
+            Some(id) if request_id::is_valid(id) => id.to_string(),
+            _ => request_id::generate(),
+        };
+        record!("request_id", id.as_str());
+        request.request_id = Some(id);
+    }
+    let content_length = match request.header("CONTENT_LENGTH") {
+        Some(value) => value.parse::<usize>().map_err(|e| {
+            if let Some(metrics) = config.metrics() {
+                metrics.decode_error(SCGIErrorKind::InvalidContentLength);
+            }
+            io::Error::from(SCGIError::new(
+                SCGIErrorKind::InvalidContentLength,
+                format!("CONTENT_LENGTH '{}' is not an integer: {}", value, e),
+            ))
+        })?,
+        // No CONTENT_LENGTH, so assume that we've got everything.
+        None => request.body.len(),
+    };
+    if let Some(max) = config.max_body_size.filter(|max| content_length > *max) {
+        if let Some(metrics) = config.metrics() {
+            metrics.decode_error(SCGIErrorKind::BodyTooLarge);
+        }
+        return Err(SCGIError::new(
+            SCGIErrorKind::BodyTooLarge,
+            format!(
+                "CONTENT_LENGTH {} exceeds the maximum body size of {}",
+                content_length, max
+            ),
+        )
+        .into());
+    }
+    while request.body.len() < content_length {
+        let idle_deadline = config.body_idle_timeout.map(|t| Instant::now() + t);
+        let deadlines = [
+            (idle_deadline, Timeout::BodyIdle),
+            (request_deadline, Timeout::Request),
+        ];
+        match within(&deadlines, framed.next()).await? {
+            Some(Ok(SCGIRequest::BodyFragment(fragment))) => {
+                request.body.reserve(fragment.len());
+                request.body.put(fragment);
+            }
+            Some(Ok(SCGIRequest::Request(_, _))) => {
+                // The codec only produces one Request per connection.
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Got second request headers in the same connection",
+                ));
+            }
+            Some(Err(e)) => return Err(e),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!(
+                        "Connection closed after {} of {} body bytes were received",
+                        request.body.len(),
+                        content_length
+                    ),
+                ))
+            }
+        }
+    }
+    // Anything past CONTENT_LENGTH isn't part of the body.
+    request.body.truncate(content_length);
+    Ok(request)
+}
+
+/// Returns the status code of a CGI or HTTP response, from its `Status:` header or HTTP status
+/// line. Defaults to 200 if there's neither, which is what CGI assumes.
+pub(crate) fn response_status(response: &[u8]) -> u16 {
+    for (i, line) in response.split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() {
+            // End of the headers.
+            break;
+        }
+        let status = if i == 0 && line.starts_with(b"HTTP/") {
+            // "HTTP/1.1 404 Not Found"
+            line.splitn(3, |b| *b == b' ').nth(1)
+        } else if line.len() > 7 && line[..7].eq_ignore_ascii_case(b"status:") {
+            // "Status: 404 Not Found"
+            line[7..]
+                .split(|b| *b == b' ')
+                .find(|word| !word.is_empty())
+        } else {
+            None
+        };
+        if let Some(code) = status
+            .and_then(|code| std::str::from_utf8(code).ok())
+            .and_then(|code| code.parse().ok())
+        {
+            return code;
+        }
+    }
+    200
+}
+
+/// Adds an `X-Request-Id` header to a CGI or HTTP response, unless it already has one. The header
+/// goes after the HTTP status line if there is one, otherwise at the start of the response.
+fn with_request_id(response: Vec<u8>, request_id: Option<&str>) -> Vec<u8> {
+    let id = match request_id {
+        Some(id) => id,
+        None => return response,
+    };
+    let mut insert_at = 0;
+    let mut newline: &[u8] = b"\r\n";
+    for (i, line) in response.split(|b| *b == b'\n').enumerate() {
+        let trimmed = line.strip_suffix(b"\r").unwrap_or(line);
+        if i == 0 {
+            if !line.ends_with(b"\r") {
+                newline = b"\n";
+            }
+            if trimmed.starts_with(b"HTTP/") {
+                insert_at = line.len() + 1;
+            }
+        }
+        if trimmed.is_empty() {
+            // End of the headers.
+            break;
+        }
+        let name = request_id::RESPONSE_HEADER.as_bytes();
+        if trimmed.len() > name.len()
+            && trimmed[..name.len()].eq_ignore_ascii_case(name)
+            && trimmed[name.len()] == b':'
+        {
+            // The handler already provided an ID.
+            return response;
+        }
+    }
+    let insert_at = insert_at.min(response.len());
+    let mut header = format!("{}: {}", request_id::RESPONSE_HEADER, id).into_bytes();
+    header.extend_from_slice(newline);
+    let mut with_id = Vec::with_capacity(response.len() + header.len());
+    with_id.extend_from_slice(&response[..insert_at]);
+    with_id.extend_from_slice(&header);
+    with_id.extend_from_slice(&response[insert_at..]);
+    with_id
+}
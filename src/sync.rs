@@ -0,0 +1,489 @@
+#![deny(warnings)]
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::proto::{self, Event, Parser, SCGIError, SCGIErrorKind};
+use crate::respond::{error_response, is_transient};
+
+/// How much is read from the connection at a time while waiting for the request headers.
+const READ_BYTES: usize = 8 * 1024;
+
+/// Request headers, in the order they were sent.
+type Headers = Vec<(String, String)>;
+
+/// Reads the headers of an SCGI request from `reader`, blocking until they've all arrived. Returns
+/// the headers in the order they were sent, and a `Body` for reading the request body.
+///
+/// Malformed requests are returned as errors with kind `InvalidData`, wrapping an `SCGIError`
+/// which can be retrieved with `SCGIError::from_io()`. This includes a `CONTENT_LENGTH` header
+/// which isn't an integer.
+///
+/// ```
+/// use std::io::Read;
+/// use tokio_scgi::sync;
+///
+/// let mut conn: &[u8] = b"24:CONTENT_LENGTH\x005\x00SCGI\x001\x00,hello";
+/// let (headers, mut body) = sync::read_request(&mut conn)?;
+/// assert_eq!(("SCGI".to_string(), "1".to_string()), headers[1]);
+/// let mut content = Vec::new();
+/// body.read_to_end(&mut content)?;
+/// assert_eq!(b"hello", &content[..]);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn read_request<R: Read>(mut reader: R) -> Result<(Headers, Body<R>), io::Error> {
+    let mut parser = Parser::new();
+    let mut buf = vec![0u8; READ_BYTES];
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Connection closed before request headers were received",
+                ))
+            }
+            Ok(len) => len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if let Event::Headers { headers, consumed } = parser.feed(&buf[..len])? {
+            event!(
+                trace,
+                headers = headers.len(),
+                header_bytes = parser.header_size(),
+                "Read SCGI request headers"
+            );
+            let content_length = content_length(&headers)?;
+            buf.truncate(len);
+            buf.drain(..consumed);
+            let body = Body::new(buf, reader, content_length);
+            return Ok((headers, body));
+        }
+    }
+}
+
+/// Returns the value of the `CONTENT_LENGTH` header, or `None` if it's missing.
+pub(crate) fn content_length(headers: &[(String, String)]) -> Result<Option<u64>, io::Error> {
+    match headers.iter().find(|(k, _)| k == "CONTENT_LENGTH") {
+        Some((_, value)) => value.parse::<u64>().map(Some).map_err(|e| {
+            io::Error::from(SCGIError::new(
+                SCGIErrorKind::InvalidContentLength,
+                format!("CONTENT_LENGTH '{}' is not an integer: {}", value, e),
+            ))
+        }),
+        None => Ok(None),
+    }
+}
+
+/// The body of a request read by `read_request()`. Reads up to the `CONTENT_LENGTH` declared in
+/// the request headers, starting with anything that arrived alongside the headers. If there's no
+/// `CONTENT_LENGTH`, only what arrived alongside the headers is read.
+#[derive(Debug)]
+pub struct Body<R> {
+    /// Body content which was read along with the headers.
+    buffered: Vec<u8>,
+
+    /// How much of `buffered` has been returned.
+    pos: usize,
+
+    /// The connection, for reading the rest of the body.
+    reader: R,
+
+    /// How much body is left to read from `reader`.
+    remaining: u64,
+}
+
+impl<R: Read> Body<R> {
+    fn new(mut buffered: Vec<u8>, reader: R, content_length: Option<u64>) -> Body<R> {
+        let content_length = content_length.unwrap_or(buffered.len() as u64);
+        // Anything past CONTENT_LENGTH isn't part of the body.
+        buffered.truncate(usize::try_from(content_length).unwrap_or(usize::MAX));
+        let remaining = content_length - buffered.len() as u64;
+        Body {
+            buffered,
+            pos: 0,
+            reader,
+            remaining,
+        }
+    }
+
+    /// Returns how many bytes of body are left to read.
+    pub fn remaining(&self) -> u64 {
+        (self.buffered.len() - self.pos) as u64 + self.remaining
+    }
+
+    /// Returns the connection, for writing the response. Any body which hasn't been read yet is
+    /// left unread.
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Read for Body<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        if self.pos < self.buffered.len() {
+            let len = buf.len().min(self.buffered.len() - self.pos);
+            buf[..len].copy_from_slice(&self.buffered[self.pos..self.pos + len]);
+            self.pos += len;
+            return Ok(len);
+        }
+        if self.remaining == 0 || buf.is_empty() {
+            return Ok(0);
+        }
+        let max = usize::try_from(self.remaining).unwrap_or(usize::MAX);
+        let limit = buf.len().min(max);
+        match self.reader.read(&mut buf[..limit])? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Connection closed with {} body bytes left to receive",
+                    self.remaining
+                ),
+            )),
+            len => {
+                self.remaining -= len as u64;
+                Ok(len)
+            }
+        }
+    }
+}
+
+/// Writes an SCGI request with the provided headers and body to `writer`, then flushes it. As with
+/// `client::SCGICodec`, the headers are sent as-is, so they should start with `CONTENT_LENGTH`
+/// and `SCGI` headers. The response can then be read from the connection until it's closed.
+///
+/// Headers with an empty key or a NUL character are rejected with kind `InvalidInput`, before
+/// anything is written.
+pub fn write_request<W: Write>(
+    mut writer: W,
+    headers: &[(String, String)],
+    body: &[u8],
+) -> Result<(), io::Error> {
+    let header_size =
+        proto::headers_len(headers).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // Include the size, ':' and ',' in buffer, not included in netstring size:
+    let mut buf = Vec::with_capacity(20 + 1/*:*/ + header_size + 1 /*,*/);
+    proto::encode_headers(headers, &mut buf)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    event!(
+        trace,
+        headers = headers.len(),
+        header_bytes = header_size,
+        body_bytes = body.len(),
+        "Writing SCGI request"
+    );
+    writer.write_all(&buf)?;
+    writer.write_all(body)?;
+    writer.flush()
+}
+
+/// A complete SCGI request received by a `sync::Server`, with all of the request body collected
+/// according to `CONTENT_LENGTH`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Request {
+    /// The request headers, in the order they were sent by the client.
+    pub headers: Vec<(String, String)>,
+
+    /// The request body.
+    pub body: Vec<u8>,
+
+    /// The address of the SCGI client that sent the request, or `None` if it connected over a Unix
+    /// socket. This is typically a frontend web server, not the end user: see the `REMOTE_ADDR`
+    /// header for the end user.
+    pub peer: Option<SocketAddr>,
+}
+
+impl Request {
+    /// Returns the value of the first header with the provided key, or `None` if it's missing.
+    pub fn header(&self, key: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Produces responses to SCGI requests for a `sync::Server`. The response is sent to the client
+/// as-is, and would typically be a CGI or HTTP response. If an error is returned, it's logged and
+/// the client instead gets a plain `500 Internal Server Error` response, without the error message.
+///
+/// This is implemented for any `Fn(Request) -> Result<Vec<u8>, io::Error>`.
+pub trait Handler: Send + Sync + 'static {
+    /// Returns the response to the provided request.
+    fn call(&self, request: Request) -> Result<Vec<u8>, io::Error>;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(Request) -> Result<Vec<u8>, io::Error> + Send + Sync + 'static,
+{
+    fn call(&self, request: Request) -> Result<Vec<u8>, io::Error> {
+        self(request)
+    }
+}
+
+/// Serves SCGI requests from a `TcpListener` or `UnixListener` using a pool of threads, passing
+/// each request to a `Handler`. Each thread serves one connection at a time, and each connection
+/// serves a single request. Connections are accepted while there are idle threads, and otherwise
+/// wait in the listener's backlog. A handler which panics only drops its connection.
+///
+/// ```no_run
+/// use std::net::TcpListener;
+/// use tokio_scgi::sync::{Request, Server};
+///
+/// fn handle(request: Request) -> Result<Vec<u8>, std::io::Error> {
+///     Ok(format!("Status: 200 OK\r\n\r\n{} bytes", request.body.len()).into_bytes())
+/// }
+///
+/// let listener = TcpListener::bind("127.0.0.1:4000")?;
+/// Server::new(handle).threads(8).serve_tcp(&listener)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Server<H> {
+    handler: Arc<H>,
+    threads: usize,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    max_body_size: Option<usize>,
+}
+
+impl<H> Clone for Server<H> {
+    fn clone(&self) -> Self {
+        Server {
+            handler: self.handler.clone(),
+            threads: self.threads,
+            read_timeout: self.read_timeout,
+            write_timeout: self.write_timeout,
+            max_body_size: self.max_body_size,
+        }
+    }
+}
+
+impl<H: Handler> Server<H> {
+    /// Returns a `Server` which passes requests to the provided handler, with one thread per CPU
+    /// and no timeouts or body size limit.
+    pub fn new(handler: H) -> Server<H> {
+        Server {
+            handler: Arc::new(handler),
+            threads: thread::available_parallelism().map_or(4, |n| n.get()),
+            read_timeout: None,
+            write_timeout: None,
+            max_body_size: None,
+        }
+    }
+
+    /// Sets the number of threads serving connections, which is also the maximum number of
+    /// requests being served at once. Defaults to the number of CPUs.
+    pub fn threads(mut self, threads: usize) -> Server<H> {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Limits how long each read from the client may wait, so that a client which stops sending
+    /// can't hold a thread forever. The client gets a `408 Request Timeout` response.
+    pub fn read_timeout(mut self, timeout: Duration) -> Server<H> {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits how long each write of the response may wait for the client to accept it.
+    pub fn write_timeout(mut self, timeout: Duration) -> Server<H> {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Limits the size of request bodies. Requests which declare a larger `CONTENT_LENGTH` get a
+    /// `413 Payload Too Large` response, without any of their body being read. By default there is
+    /// no limit, as with `runtime::Server::max_body_size()`.
+    pub fn max_body_size(mut self, max: usize) -> Server<H> {
+        self.max_body_size = Some(max);
+        self
+    }
+
+    /// Accepts and serves connections from the TCP listener. Only returns if accepting fails, after
+    /// waiting for any in-flight requests to finish.
+    pub fn serve_tcp(&self, listener: &TcpListener) -> Result<(), io::Error> {
+        self.serve_with(|| {
+            let (conn, addr) = listener.accept()?;
+            conn.set_read_timeout(self.read_timeout)?;
+            conn.set_write_timeout(self.write_timeout)?;
+            Ok((Connection::Tcp(conn), Some(addr)))
+        })
+    }
+
+    /// Accepts and serves connections from the Unix socket listener. Only returns if accepting
+    /// fails, after waiting for any in-flight requests to finish. Only available on Unix platforms.
+    #[cfg(unix)]
+    pub fn serve_unix(&self, listener: &UnixListener) -> Result<(), io::Error> {
+        self.serve_with(|| {
+            let (conn, _) = listener.accept()?;
+            conn.set_read_timeout(self.read_timeout)?;
+            conn.set_write_timeout(self.write_timeout)?;
+            Ok((Connection::Unix(conn), None))
+        })
+    }
+
+    /// Accepts connections on the calling thread and passes them to the pool of serving threads.
+    fn serve_with<A>(&self, mut accept: A) -> Result<(), io::Error>
+    where
+        A: FnMut() -> Result<(Connection, Option<SocketAddr>), io::Error>,
+    {
+        // Only accept once a thread is free to take the connection.
+        let (sender, receiver) = mpsc::sync_channel::<(Connection, Option<SocketAddr>)>(0);
+        let receiver = Mutex::new(receiver);
+        thread::scope(|scope| {
+            for _ in 0..self.threads {
+                scope.spawn(|| loop {
+                    let next = receiver.lock().expect("Lock is never poisoned").recv();
+                    let (conn, peer) = match next {
+                        Ok(accepted) => accepted,
+                        // The server has stopped accepting.
+                        Err(_) => break,
+                    };
+                    // Errors are reported to the client by serve_connection(), nothing else to do.
+                    let served =
+                        panic::catch_unwind(AssertUnwindSafe(|| self.serve_connection(conn, peer)));
+                    if served.is_err() {
+                        event!(error, "Handler panicked, dropping connection");
+                    }
+                });
+            }
+            let result = loop {
+                match accept() {
+                    Ok(accepted) => {
+                        if sender.send(accepted).is_err() {
+                            break Err(io::Error::other("All server threads have exited"));
+                        }
+                    }
+                    // The client gave up before we got to it, keep going.
+                    Err(e) if is_transient(&e) => {
+                        event!(debug, error = %e, "Client disconnected before it was accepted");
+                    }
+                    Err(e) => {
+                        event!(error, error = %e, "Failed to accept connection");
+                        break Err(e);
+                    }
+                }
+            };
+            // Let the threads finish any in-flight requests and exit.
+            drop(sender);
+            result
+        })
+    }
+
+    /// Serves a single request from a connection, then closes it. This allows serving any kind of
+    /// stream, such as one end of a `UnixStream::pair()` in tests. Any timeouts must already be
+    /// set on the stream.
+    pub fn serve_connection<C: Read + Write>(
+        &self,
+        mut conn: C,
+        peer: Option<SocketAddr>,
+    ) -> Result<(), io::Error> {
+        let request = match self.read_full_request(&mut conn, peer) {
+            Ok(request) => request,
+            Err(e) => {
+                let too_large =
+                    SCGIError::from_io(&e).is_some_and(|e| e.kind() == SCGIErrorKind::BodyTooLarge);
+                let status = match e.kind() {
+                    io::ErrorKind::InvalidData if too_large => "413 Payload Too Large",
+                    // InvalidData implies an error from the SCGI parser. The request was malformed.
+                    io::ErrorKind::InvalidData => "400 Bad Request",
+                    // Read timeouts on sockets may be reported as either kind.
+                    io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => "408 Request Timeout",
+                    _ => return Err(e),
+                };
+                event!(debug, error = %e, "Rejected request");
+                conn.write_all(&error_response(status))?;
+                conn.flush()?;
+                return Err(e);
+            }
+        };
+        match self.handler.call(request) {
+            Ok(response) => {
+                conn.write_all(&response)?;
+                conn.flush()
+            }
+            Err(e) => {
+                event!(error, error = %e, "Handler failed");
+                conn.write_all(&error_response("500 Internal Server Error"))?;
+                conn.flush()?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Reads the request headers, followed by however much body is declared by `CONTENT_LENGTH`,
+    /// unless that exceeds `max_body_size`.
+    fn read_full_request<C: Read>(
+        &self,
+        conn: C,
+        peer: Option<SocketAddr>,
+    ) -> Result<Request, io::Error> {
+        let (headers, mut body) = read_request(conn)?;
+        let content_length = body.remaining();
+        if let Some(max) = self
+            .max_body_size
+            .filter(|max| content_length > *max as u64)
+        {
+            return Err(SCGIError::new(
+                SCGIErrorKind::BodyTooLarge,
+                format!(
+                    "CONTENT_LENGTH {} exceeds the maximum body size of {}",
+                    content_length, max
+                ),
+            )
+            .into());
+        }
+        // CONTENT_LENGTH comes from the client, so only allocate as the body arrives.
+        let mut content = Vec::new();
+        body.read_to_end(&mut content)?;
+        Ok(Request {
+            headers,
+            body: content,
+            peer,
+        })
+    }
+}
+
+/// An accepted connection, from either kind of listener.
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, io::Error> {
+        match self {
+            Connection::Tcp(conn) => conn.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(conn) => conn.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, io::Error> {
+        match self {
+            Connection::Tcp(conn) => conn.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(conn) => conn.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), io::Error> {
+        match self {
+            Connection::Tcp(conn) => conn.flush(),
+            #[cfg(unix)]
+            Connection::Unix(conn) => conn.flush(),
+        }
+    }
+}
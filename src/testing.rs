@@ -0,0 +1,268 @@
+#![deny(warnings)]
+
+use bytes::{Buf, BytesMut};
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_util::codec::Encoder;
+
+use crate::client::{SCGICodec, SCGIRequest};
+use crate::listener::PeerAddr;
+use crate::runtime::{self, Handler, Server};
+
+/// Sends requests to a `Handler` without any sockets, for use in tests. Each request is encoded
+/// with `client::SCGICodec` and passed through an in-memory connection, where it's served by
+/// `runtime::Server` using `server::SCGICodec`, the same as a real connection. Requires the
+/// `testing` feature.
+///
+/// The connection is a custom in-memory stream rather than a `tokio::io::duplex` pipe: A pipe may
+/// combine fragments which are written before the server reads, whereas here each read returns at
+/// most one fragment. Writes of the response also never block, so a server which responds before
+/// reading the whole request can't deadlock against the client.
+///
+/// ```
+/// # async fn run() -> Result<(), std::io::Error> {
+/// use tokio_scgi::runtime::Request;
+/// use tokio_scgi::testing::TestClient;
+///
+/// async fn handle(request: Request) -> Result<Vec<u8>, std::io::Error> {
+///     Ok(format!("Status: 201 Created\r\n\r\n{}", request.body.len()).into_bytes())
+/// }
+///
+/// // Split the request into single bytes, to check that the handler sees the full body anyway.
+/// let client = TestClient::new(handle).split_every(1);
+/// let response = client.request(vec![], b"hello").await?;
+/// assert_eq!(201, response.status);
+/// assert_eq!(b"5", &response.body[..]);
+/// # Ok(())
+/// # }
+/// ```
+pub struct TestClient<H> {
+    server: Server<H>,
+    peer: PeerAddr,
+    splits: Splits,
+}
+
+/// Where the encoded request is split into separate writes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Splits {
+    At(Vec<usize>),
+    Every(usize),
+}
+
+impl<H: Handler> TestClient<H> {
+    /// Returns a `TestClient` which sends requests to the handler, using a `Server` with the
+    /// default settings.
+    pub fn new(handler: H) -> TestClient<H> {
+        TestClient::with_server(Server::new(handler))
+    }
+
+    /// Returns a `TestClient` which sends requests to the provided `Server`, for testing handlers
+    /// along with server settings such as timeouts or request IDs. Checks of the client's address
+    /// and credentials aren't applied.
+    pub fn with_server(server: Server<H>) -> TestClient<H> {
+        TestClient {
+            server,
+            peer: PeerAddr::Unix(None),
+            splits: Splits::At(Vec::new()),
+        }
+    }
+
+    /// Sets the client address passed to the handler in `Request::peer`. Defaults to an unnamed
+    /// Unix socket.
+    pub fn peer(mut self, peer: PeerAddr) -> TestClient<H> {
+        self.peer = peer;
+        self
+    }
+
+    /// Splits the encoded request into fragments at the provided byte offsets, to exercise
+    /// handling of requests which arrive in pieces. Each read by the server returns at most one
+    /// fragment, so fragments are never combined. Offsets beyond the end of the request are
+    /// ignored. By default the request is sent as a single fragment.
+    pub fn split_at(mut self, offsets: &[usize]) -> TestClient<H> {
+        let mut offsets = offsets.to_vec();
+        offsets.sort_unstable();
+        offsets.dedup();
+        self.splits = Splits::At(offsets);
+        self
+    }
+
+    /// Splits the encoded request into fragments of the provided size. See `split_at()`.
+    pub fn split_every(mut self, size: usize) -> TestClient<H> {
+        self.splits = Splits::Every(size.max(1));
+        self
+    }
+
+    /// Sends a request with the provided headers and body, and returns the parsed response. As a
+    /// web server would, `CONTENT_LENGTH` and `SCGI` headers are added at the start of the headers
+    /// if they're missing.
+    ///
+    /// Errors from reading the request or from the handler are returned as error responses by the
+    /// server, the same as with a real connection. If the server gives up without responding, for
+    /// example because the request is shorter than its `CONTENT_LENGTH`, its error is returned
+    /// instead.
+    pub async fn request(
+        &self,
+        headers: Vec<(String, String)>,
+        body: &[u8],
+    ) -> Result<TestResponse, io::Error> {
+        let mut request = BytesMut::new();
+        SCGICodec::new()
+            .required_headers()
+            .encode(SCGIRequest::Request(headers, body.into()), &mut request)?;
+
+        let mut fragments = VecDeque::new();
+        let mut start = 0;
+        for end in self.split_offsets(request.len()) {
+            fragments.push_back(request.split_to(end - start));
+            start = end;
+        }
+        let mut conn = Connection {
+            fragments,
+            reads: 0,
+            response: Vec::new(),
+        };
+        let served = self
+            .server
+            .serve_connection(&mut conn, self.peer.clone())
+            .await;
+        // Any other error from the server has already been sent to the client as a response.
+        if let Err(e) = served {
+            if conn.response.is_empty() {
+                return Err(e);
+            }
+        }
+        let mut response = TestResponse::parse(conn.response);
+        response.reads = conn.reads;
+        Ok(response)
+    }
+
+    /// Returns the end offset of each fragment for a request of the provided length.
+    fn split_offsets(&self, len: usize) -> Vec<usize> {
+        let mut ends: Vec<usize> = match &self.splits {
+            Splits::At(offsets) => offsets
+                .iter()
+                .copied()
+                .filter(|offset| *offset > 0 && *offset < len)
+                .collect(),
+            Splits::Every(size) => (*size..len).step_by(*size).collect(),
+        };
+        ends.push(len);
+        ends
+    }
+}
+
+/// The server's end of a `TestClient` connection. Reads return the request one fragment at a time,
+/// then EOF, and writes are collected into the response without ever blocking, so the server can
+/// respond before it has read the whole request.
+struct Connection {
+    fragments: VecDeque<BytesMut>,
+    reads: usize,
+    response: Vec<u8>,
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        match this.fragments.front_mut() {
+            Some(fragment) => {
+                let len = fragment.len().min(buf.remaining());
+                buf.put_slice(&fragment[..len]);
+                fragment.advance(len);
+                if fragment.is_empty() {
+                    this.fragments.pop_front();
+                }
+                this.reads += 1;
+                Poll::Ready(Ok(()))
+            }
+            // The whole request has been sent, so further reads see EOF, as if the client had shut
+            // down its end. A server waiting for more of the request reports an error response
+            // rather than hanging the test.
+            None => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.response.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// A response received by a `TestClient`, parsed as a CGI response.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TestResponse {
+    /// The status code from the `Status:` header or HTTP status line, or 200 if there's neither.
+    pub status: u16,
+
+    /// The response headers, in the order they were sent. Doesn't include the HTTP status line.
+    pub headers: Vec<(String, String)>,
+
+    /// The response body, after the blank line following the headers.
+    pub body: Vec<u8>,
+
+    /// The response exactly as it was sent by the handler.
+    pub raw: Vec<u8>,
+
+    /// The number of reads which returned part of the request to the server. Each fragment takes
+    /// one read, or more if it's larger than the server's read buffer.
+    pub reads: usize,
+}
+
+impl TestResponse {
+    fn parse(raw: Vec<u8>) -> TestResponse {
+        let mut headers = Vec::new();
+        let mut body_start = raw.len();
+        let mut offset = 0;
+        for (i, line) in raw.split(|b| *b == b'\n').enumerate() {
+            let next = offset + line.len() + 1;
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                body_start = next.min(raw.len());
+                break;
+            }
+            let line = String::from_utf8_lossy(line);
+            if i == 0 && line.starts_with("HTTP/") {
+                // The status line isn't a header.
+            } else if let Some((name, value)) = line.split_once(':') {
+                headers.push((name.trim().to_string(), value.trim().to_string()));
+            }
+            offset = next;
+        }
+        TestResponse {
+            status: runtime::response_status(&raw),
+            headers,
+            body: raw[body_start..].to_vec(),
+            raw,
+            reads: 0,
+        }
+    }
+
+    /// Returns the value of the first header with the provided name, ignoring case, or `None` if
+    /// it's missing.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
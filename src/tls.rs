@@ -0,0 +1,109 @@
+#![deny(warnings)]
+
+use std::convert::TryFrom;
+use std::io;
+use std::sync::Arc;
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+pub use tokio_rustls::rustls;
+
+use crate::listener::Connection;
+
+/// Returns a TLS configuration for SCGI servers, for use with `runtime::Server::tls()`. If
+/// `client_roots` is provided, clients must present a certificate signed by one of the roots, and
+/// the verified certificate chain is passed to handlers in `Request::peer_certificates`.
+pub fn server_config(
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: Option<RootCertStore>,
+) -> Result<Arc<ServerConfig>, io::Error> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_err)?;
+    let builder = match client_roots {
+        Some(roots) => builder.with_client_cert_verifier(
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .map_err(tls_err)?,
+        ),
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(
+        builder.with_single_cert(cert_chain, key).map_err(tls_err)?,
+    ))
+}
+
+/// Returns a TLS configuration for SCGI clients, for use with `connect()`. The server must present
+/// a certificate signed by one of the provided roots. If `client_auth` is provided, the certificate
+/// chain and key are presented to servers which require client certificates.
+pub fn client_config(
+    server_roots: RootCertStore,
+    client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+) -> Result<Arc<ClientConfig>, io::Error> {
+    let provider = Arc::new(ring::default_provider());
+    let verifier =
+        WebPkiServerVerifier::builder_with_provider(Arc::new(server_roots), provider.clone())
+            .build()
+            .map_err(tls_err)?;
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_err)?
+        .with_webpki_verifier(verifier);
+    let config = match client_auth {
+        Some((cert_chain, key)) => builder
+            .with_client_auth_cert(cert_chain, key)
+            .map_err(tls_err)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+/// Connects to an SCGI server over TLS. The returned stream can be used with `client::SCGICodec`.
+/// `server_name` is the name that the server's certificate is verified against.
+pub async fn connect<A: ToSocketAddrs>(
+    addr: A,
+    server_name: &str,
+    config: Arc<ClientConfig>,
+) -> Result<client::TlsStream<TcpStream>, io::Error> {
+    let server_name = ServerName::try_from(server_name.to_string()).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid TLS server name '{}': {}", server_name, e),
+        )
+    })?;
+    let conn = TcpStream::connect(addr).await?;
+    TlsConnector::from(config).connect(server_name, conn).await
+}
+
+/// Performs the server side of the TLS handshake on a TCP connection, returning the encrypted
+/// connection and the client's verified certificate chain, if any. Unix socket connections are
+/// returned as-is.
+pub(crate) async fn accept(
+    config: Arc<ServerConfig>,
+    conn: Connection,
+) -> Result<(Connection, Vec<Vec<u8>>), io::Error> {
+    match conn {
+        Connection::Tcp(tcp) => {
+            let tls: server::TlsStream<TcpStream> = TlsAcceptor::from(config).accept(tcp).await?;
+            let certificates = tls
+                .get_ref()
+                .1
+                .peer_certificates()
+                .map(|chain| chain.iter().map(|cert| cert.to_vec()).collect())
+                .unwrap_or_default();
+            Ok((Connection::Tls(Box::new(tls)), certificates))
+        }
+        conn => Ok((conn, Vec::new())),
+    }
+}
+
+fn tls_err<E: std::error::Error + Send + Sync + 'static>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, e)
+}
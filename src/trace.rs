@@ -0,0 +1,21 @@
+#![deny(warnings)]
+
+//! Wrappers around the `tracing` macros which compile to nothing when the `tracing` feature is
+//! disabled, so that instrumented code doesn't need a `#[cfg]` at every call site.
+
+/// Emits a `tracing` event at the provided level, for example `event!(debug, key = value, "msg")`.
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::$level!($($arg)+)
+    };
+}
+
+/// Records a field on the current span, for example `record!("outcome", "ok")`.
+#[cfg(feature = "runtime")]
+macro_rules! record {
+    ($field:expr, $value:expr) => {
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record($field, $value);
+    };
+}
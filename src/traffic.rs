@@ -0,0 +1,414 @@
+#![deny(warnings)]
+
+use bytes::BytesMut;
+use tokio_util::codec::Encoder;
+
+use crate::client::{SCGICodec, SCGIRequest};
+use crate::server::SCGIErrorKind;
+
+/// Header keys which frontend web servers commonly send, used alongside arbitrary keys so that
+/// generated requests resemble real traffic.
+const COMMON_KEYS: [&str; 12] = [
+    "REQUEST_METHOD",
+    "REQUEST_URI",
+    "QUERY_STRING",
+    "SERVER_PROTOCOL",
+    "SERVER_NAME",
+    "SERVER_PORT",
+    "REMOTE_ADDR",
+    "REMOTE_PORT",
+    "CONTENT_TYPE",
+    "HTTP_HOST",
+    "HTTP_USER_AGENT",
+    "HTTP_X_REQUEST_ID",
+];
+
+/// Methods used as `REQUEST_METHOD` values.
+const METHODS: [&str; 5] = ["GET", "POST", "PUT", "DELETE", "HEAD"];
+
+/// A well-formed SCGI request, as sent by a frontend web server. The headers start with
+/// `CONTENT_LENGTH` matching the body and `SCGI` set to `1`, followed by unique, non-empty keys.
+/// Keys and values never contain NUL characters.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ValidRequest {
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl ValidRequest {
+    /// Returns a request with the provided headers and body. The `CONTENT_LENGTH` and `SCGI`
+    /// headers are added at the start. Any provided headers which would make the request invalid
+    /// are dropped: empty keys, keys or values with NUL characters, and repeated keys.
+    pub fn new(headers: Vec<(String, String)>, body: Vec<u8>) -> ValidRequest {
+        let mut valid = vec![
+            ("CONTENT_LENGTH".to_string(), body.len().to_string()),
+            ("SCGI".to_string(), "1".to_string()),
+        ];
+        for (key, value) in headers {
+            if key.is_empty()
+                || key.contains('\0')
+                || value.contains('\0')
+                || valid.iter().any(|(k, _)| *k == key)
+            {
+                continue;
+            }
+            valid.push((key, value));
+        }
+        ValidRequest {
+            headers: valid,
+            body,
+        }
+    }
+
+    /// The headers, starting with `CONTENT_LENGTH` and `SCGI`.
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    /// The body.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Returns the request encoded with `client::SCGICodec`.
+    pub fn encode(&self) -> Vec<u8> {
+        encode(self.headers.clone(), &self.body)
+    }
+
+    /// Returns the size of the netstring size prefix, including the ':', and the size of the
+    /// headers within the netstring, which are followed by the ','.
+    fn header_layout(&self) -> (usize, usize) {
+        let headers = self
+            .headers
+            .iter()
+            .map(|(k, v)| k.len() + v.len() + 2)
+            .sum::<usize>();
+        (headers.to_string().len() + 1, headers)
+    }
+}
+
+fn encode(headers: Vec<(String, String)>, body: &[u8]) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    SCGICodec::new()
+        .encode(SCGIRequest::Request(headers, body.into()), &mut buf)
+        .expect("Valid requests can always be encoded");
+    buf.to_vec()
+}
+
+/// A change which makes a `ValidRequest` malformed, while leaving it close enough to valid to get
+/// past the first few bytes of parsing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mutation {
+    /// Declares a header size which is larger than the headers by the provided amount, so that
+    /// the ',' and body are parsed as headers.
+    SizeTooLarge(usize),
+
+    /// Declares a header size which ends partway through the last header, at the provided offset
+    /// into it modulo its size, so that the last header extends past the declared size.
+    SizeTooSmall(usize),
+
+    /// Adds a leading '0' to the header size.
+    LeadingZero,
+
+    /// Inserts the provided byte at the start of the header size. Digits, '+' and ':' are
+    /// replaced with 'x'.
+    NonDigitSize(u8),
+
+    /// Replaces the ',' after the headers with a '.'.
+    MissingComma,
+
+    /// Inserts a NUL character at the provided offset into the headers, modulo their size,
+    /// without updating the declared header size.
+    EmbeddedNul(usize),
+
+    /// Replaces the byte at the provided offset into the headers, modulo their size, with a byte
+    /// which is never valid UTF-8. NUL characters are skipped over.
+    InvalidUtf8(usize),
+
+    /// Replaces the `CONTENT_LENGTH` value with one which isn't an integer, keeping the framing
+    /// otherwise valid.
+    InvalidContentLength,
+}
+
+impl Mutation {
+    /// Returns the request encoded with `client::SCGICodec`, then changed by this mutation.
+    pub fn apply(&self, request: &ValidRequest) -> Vec<u8> {
+        let (prefix_len, header_len) = request.header_layout();
+        let mut encoded = request.encode();
+        match *self {
+            Mutation::SizeTooLarge(extra) => {
+                let size = header_len.saturating_add(extra.max(1));
+                with_size(&encoded[prefix_len..], &size.to_string())
+            }
+            Mutation::SizeTooSmall(offset) => {
+                // Keys and values are at least 1 and 0 bytes long, plus a NUL after each.
+                let (key, value) = request.headers.last().expect("Always has headers");
+                let last_len = key.len() + value.len() + 2;
+                let size = header_len - last_len + 1 + offset % (last_len - 1);
+                with_size(&encoded[prefix_len..], &size.to_string())
+            }
+            Mutation::LeadingZero => {
+                encoded.insert(0, b'0');
+                encoded
+            }
+            Mutation::NonDigitSize(b) => {
+                let b = if b.is_ascii_digit() || b == b'+' || b == b':' {
+                    b'x'
+                } else {
+                    b
+                };
+                encoded.insert(0, b);
+                encoded
+            }
+            Mutation::MissingComma => {
+                encoded[prefix_len + header_len] = b'.';
+                encoded
+            }
+            Mutation::EmbeddedNul(offset) => {
+                encoded.insert(prefix_len + offset % header_len, b'\0');
+                encoded
+            }
+            Mutation::InvalidUtf8(offset) => {
+                let headers = &mut encoded[prefix_len..prefix_len + header_len];
+                let mut i = offset % header_len;
+                while headers[i] == b'\0' {
+                    i = (i + 1) % header_len;
+                }
+                headers[i] = 0xff;
+                encoded
+            }
+            Mutation::InvalidContentLength => {
+                let mut headers = request.headers.clone();
+                headers[0].1 = format!("{}x", request.body.len());
+                encode(headers, &request.body)
+            }
+        }
+    }
+
+    /// Returns the kind of error that the request is always rejected with after this mutation, or
+    /// `None` if the outcome depends on the request. For example, a request whose header size is
+    /// too large may be rejected as malformed, or may leave the server waiting for more headers.
+    /// `InvalidContentLength` is reported by `runtime::Server` rather than by `server::SCGICodec`.
+    pub fn expected_error(&self) -> Option<SCGIErrorKind> {
+        match self {
+            Mutation::SizeTooSmall(_) | Mutation::MissingComma => {
+                Some(SCGIErrorKind::MissingSeparator)
+            }
+            Mutation::LeadingZero | Mutation::NonDigitSize(_) => {
+                Some(SCGIErrorKind::InvalidHeaderSize)
+            }
+            Mutation::InvalidUtf8(_) => Some(SCGIErrorKind::InvalidHeaderString),
+            Mutation::InvalidContentLength => Some(SCGIErrorKind::InvalidContentLength),
+            Mutation::SizeTooLarge(_) | Mutation::EmbeddedNul(_) => None,
+        }
+    }
+}
+
+/// Returns a request with the provided size prefix in front of the encoded headers and body.
+fn with_size(headers_and_body: &[u8], size: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(size.len() + 1 + headers_and_body.len());
+    encoded.extend_from_slice(size.as_bytes());
+    encoded.push(b':');
+    encoded.extend_from_slice(headers_and_body);
+    encoded
+}
+
+/// A `ValidRequest` with a `Mutation` applied.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NearValidRequest {
+    /// The request before it was mutated.
+    pub request: ValidRequest,
+
+    /// The change applied to the request.
+    pub mutation: Mutation,
+}
+
+impl NearValidRequest {
+    /// Returns the encoded request, after the mutation has been applied.
+    pub fn encode(&self) -> Vec<u8> {
+        self.mutation.apply(&self.request)
+    }
+}
+
+/// The sizes of successive reads or writes when delivering a request, to exercise handling of
+/// requests which arrive in fragments. Zero sizes are allowed, and produce empty reads. Whatever
+/// remains after the last size is delivered in one piece.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Schedule(pub Vec<usize>);
+
+impl Schedule {
+    /// Returns the data split into chunks according to the schedule.
+    pub fn chunks<'a>(&self, data: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut chunks = Vec::with_capacity(self.0.len() + 1);
+        let mut rest = data;
+        for size in &self.0 {
+            let (chunk, remainder) = rest.split_at((*size).min(rest.len()));
+            chunks.push(chunk);
+            rest = remainder;
+        }
+        if !rest.is_empty() {
+            chunks.push(rest);
+        }
+        chunks
+    }
+
+    /// Returns the offsets where data of the provided length is split, for use with
+    /// `testing::TestClient::split_at()`.
+    pub fn offsets(&self, len: usize) -> Vec<usize> {
+        let mut offset = 0usize;
+        self.0
+            .iter()
+            .map(|size| {
+                offset = offset.saturating_add(*size);
+                offset
+            })
+            .filter(|offset| *offset < len)
+            .collect()
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+mod arbitrary_impls {
+    use arbitrary::{Arbitrary, Result, Unstructured};
+
+    use super::*;
+
+    fn header_string(u: &mut Unstructured<'_>) -> Result<String> {
+        Ok(String::arbitrary(u)?.replace('\0', ""))
+    }
+
+    impl<'a> Arbitrary<'a> for ValidRequest {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            let mut headers = Vec::new();
+            for _ in 0..u.int_in_range(0..=8)? {
+                let key = if u.ratio(2, 3)? {
+                    u.choose(&COMMON_KEYS)?.to_string()
+                } else {
+                    header_string(u)?
+                };
+                let value = if key == "REQUEST_METHOD" {
+                    u.choose(&METHODS)?.to_string()
+                } else {
+                    header_string(u)?
+                };
+                headers.push((key, value));
+            }
+            Ok(ValidRequest::new(headers, Vec::arbitrary(u)?))
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for Mutation {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(match u.int_in_range(0..=7)? {
+                0 => Mutation::SizeTooLarge(u.arbitrary()?),
+                1 => Mutation::SizeTooSmall(u.arbitrary()?),
+                2 => Mutation::LeadingZero,
+                3 => Mutation::NonDigitSize(u.arbitrary()?),
+                4 => Mutation::MissingComma,
+                5 => Mutation::EmbeddedNul(u.arbitrary()?),
+                6 => Mutation::InvalidUtf8(u.arbitrary()?),
+                _ => Mutation::InvalidContentLength,
+            })
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for NearValidRequest {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            Ok(NearValidRequest {
+                request: u.arbitrary()?,
+                mutation: u.arbitrary()?,
+            })
+        }
+    }
+
+    impl<'a> Arbitrary<'a> for Schedule {
+        fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+            // Small sizes split requests in the most interesting places.
+            let sizes: Vec<u8> = u.arbitrary()?;
+            Ok(Schedule(sizes.into_iter().map(usize::from).collect()))
+        }
+    }
+}
+
+/// Proptest strategies for generating requests and delivery schedules. Requires the `proptest`
+/// feature. Each type also implements `proptest::arbitrary::Arbitrary` using these strategies, so
+/// `any::<ValidRequest>()` can be used too.
+#[cfg(feature = "proptest")]
+pub mod strategy {
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn header_key() -> impl Strategy<Value = String> {
+        prop_oneof![
+            2 => proptest::sample::select(&COMMON_KEYS[..]).prop_map(str::to_string),
+            1 => "[^\\x00]{1,32}",
+        ]
+    }
+
+    fn header_value(key: String) -> BoxedStrategy<(String, String)> {
+        if key == "REQUEST_METHOD" {
+            proptest::sample::select(&METHODS[..])
+                .prop_map(move |method| (key.clone(), method.to_string()))
+                .boxed()
+        } else {
+            "[^\\x00]{0,64}"
+                .prop_map(move |value| (key.clone(), value))
+                .boxed()
+        }
+    }
+
+    /// Returns a strategy for well-formed requests with up to 8 headers besides `CONTENT_LENGTH`
+    /// and `SCGI`, and bodies of up to 256 bytes.
+    pub fn valid_request() -> impl Strategy<Value = ValidRequest> {
+        (
+            vec(header_key().prop_flat_map(header_value), 0..=8),
+            vec(any::<u8>(), 0..=256),
+        )
+            .prop_map(|(headers, body)| ValidRequest::new(headers, body))
+    }
+
+    /// Returns a strategy for each kind of `Mutation`.
+    pub fn mutation() -> impl Strategy<Value = Mutation> {
+        prop_oneof![
+            (1..=64usize).prop_map(Mutation::SizeTooLarge),
+            any::<usize>().prop_map(Mutation::SizeTooSmall),
+            Just(Mutation::LeadingZero),
+            any::<u8>().prop_map(Mutation::NonDigitSize),
+            Just(Mutation::MissingComma),
+            any::<usize>().prop_map(Mutation::EmbeddedNul),
+            any::<usize>().prop_map(Mutation::InvalidUtf8),
+            Just(Mutation::InvalidContentLength),
+        ]
+    }
+
+    /// Returns a strategy for well-formed requests with one mutation applied.
+    pub fn near_valid_request() -> impl Strategy<Value = NearValidRequest> {
+        (valid_request(), mutation())
+            .prop_map(|(request, mutation)| NearValidRequest { request, mutation })
+    }
+
+    /// Returns a strategy for schedules of up to 16 reads or writes, each of up to 16 bytes.
+    pub fn schedule() -> impl Strategy<Value = Schedule> {
+        vec(0..=16usize, 0..=16).prop_map(Schedule)
+    }
+
+    macro_rules! impl_arbitrary {
+        ($type:ty, $strategy:ident) => {
+            impl Arbitrary for $type {
+                type Parameters = ();
+                type Strategy = BoxedStrategy<$type>;
+
+                fn arbitrary_with(_: ()) -> Self::Strategy {
+                    $strategy().boxed()
+                }
+            }
+        };
+    }
+
+    impl_arbitrary!(ValidRequest, valid_request);
+    impl_arbitrary!(Mutation, mutation);
+    impl_arbitrary!(NearValidRequest, near_valid_request);
+    impl_arbitrary!(Schedule, schedule);
+}
@@ -0,0 +1,146 @@
+#![deny(warnings)]
+
+//! A dedicated thread which owns a writer, so that handlers can log to files without blocking the
+//! runtime on file IO.
+
+use std::io::{self, Write};
+use std::iter;
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+/// A function to run on a `WriterThread`'s writer.
+type Call<W> = Box<dyn FnOnce(&mut W) -> io::Result<()> + Send>;
+
+/// Work for a `WriterThread`.
+enum Message<W> {
+    /// Data to write with a single `write_all()` call.
+    Write(Vec<u8>),
+
+    /// Flushes the writer, then sends back the first error since the last flush.
+    Flush(mpsc::Sender<io::Result<()>>),
+
+    /// Flushes the writer, then runs the function on it and sends back the result.
+    Call(Call<W>, mpsc::Sender<io::Result<()>>),
+}
+
+/// Owns a writer on a dedicated thread. `write()` queues data without blocking, and the thread
+/// writes it in order, flushing once it has written everything that's queued rather than after
+/// each write. Dropping the `WriterThread` waits for the queued data to be written.
+pub(crate) struct WriterThread<W> {
+    /// Always `Some`, until it's dropped to stop the thread.
+    sender: Option<SyncSender<Message<W>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<W: Write + Send + 'static> WriterThread<W> {
+    /// Starts a thread with the provided name which owns `writer`. Up to `queue` writes may wait
+    /// for the thread before further writes are dropped.
+    pub(crate) fn spawn(name: &str, writer: W, queue: usize) -> io::Result<WriterThread<W>> {
+        let (sender, receiver) = mpsc::sync_channel(queue);
+        let thread = thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || run(writer, receiver))?;
+        Ok(WriterThread {
+            sender: Some(sender),
+            thread: Some(thread),
+        })
+    }
+
+    /// Queues data to be written. Returns an error with kind `WouldBlock`, dropping the data, if
+    /// the thread has fallen too far behind. Errors writing the data are reported by `flush()`.
+    pub(crate) fn write(&self, data: Vec<u8>) -> io::Result<()> {
+        match self.sender().try_send(Message::Write(data)) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "Writer thread is behind, dropped write",
+            )),
+            Err(TrySendError::Disconnected(_)) => Err(stopped()),
+        }
+    }
+
+    /// Waits for everything queued so far to be written and flushed, and returns the first error
+    /// writing it since the last call. Blocks the calling thread.
+    pub(crate) fn flush(&self) -> io::Result<()> {
+        self.request(Message::Flush)
+    }
+
+    /// Waits for everything queued so far to be written and flushed, then runs `f` on the writer and
+    /// returns its result. Blocks the calling thread.
+    pub(crate) fn call<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut W) -> io::Result<()> + Send + 'static,
+    {
+        self.request(|reply| Message::Call(Box::new(f), reply))
+    }
+
+    fn request<F>(&self, message: F) -> io::Result<()>
+    where
+        F: FnOnce(mpsc::Sender<io::Result<()>>) -> Message<W>,
+    {
+        let (reply, receiver) = mpsc::channel();
+        self.sender().send(message(reply)).map_err(|_| stopped())?;
+        receiver.recv().map_err(|_| stopped())?
+    }
+
+    fn sender(&self) -> &SyncSender<Message<W>> {
+        self.sender
+            .as_ref()
+            .expect("WriterThread sender is only taken on drop")
+    }
+}
+
+impl<W> Drop for WriterThread<W> {
+    fn drop(&mut self) {
+        // The thread exits once it has written everything that was queued.
+        self.sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "Writer thread has stopped")
+}
+
+/// Runs until the `WriterThread` is dropped, keeping the first error since the last flush.
+fn run<W: Write>(mut writer: W, receiver: Receiver<Message<W>>) {
+    let mut error: Option<io::Error> = None;
+    while let Ok(first) = receiver.recv() {
+        let mut flushes = Vec::new();
+        for message in iter::once(first).chain(receiver.try_iter()) {
+            match message {
+                Message::Write(data) => {
+                    if let Err(e) = writer.write_all(&data) {
+                        failed(&mut error, e);
+                    }
+                }
+                Message::Flush(reply) => flushes.push(reply),
+                Message::Call(f, reply) => {
+                    if let Err(e) = writer.flush() {
+                        failed(&mut error, e);
+                    }
+                    let _ = reply.send(f(&mut writer));
+                }
+            }
+        }
+        if let Err(e) = writer.flush() {
+            failed(&mut error, e);
+        }
+        if !flushes.is_empty() {
+            let result = error.take();
+            for reply in flushes {
+                let _ = reply.send(match &result {
+                    Some(e) => Err(io::Error::new(e.kind(), e.to_string())),
+                    None => Ok(()),
+                });
+            }
+        }
+    }
+}
+
+fn failed(error: &mut Option<io::Error>, e: io::Error) {
+    event!(warn, thread = thread::current().name(), error = %e, "Background write failed");
+    error.get_or_insert(e);
+}
@@ -0,0 +1,227 @@
+#![deny(warnings)]
+#![cfg(feature = "runtime")]
+
+use std::env;
+use std::fs;
+use std::io::{Error, Write};
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use tokio_scgi::access_log::{self, AccessLog, LogFormat, LogSink};
+use tokio_scgi::listener::PeerAddr;
+use tokio_scgi::runtime::{Handler, Request, Server};
+
+/// Collects log lines in memory.
+#[derive(Clone, Default)]
+struct Lines(Arc<Mutex<Vec<u8>>>);
+
+impl Lines {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn created(_request: Request) -> Result<Vec<u8>, Error> {
+    Ok(b"Status: 201 Created\r\nContent-Type: text/plain\r\n\r\nabc".to_vec())
+}
+
+async fn fail(_request: Request) -> Result<Vec<u8>, Error> {
+    Err(Error::other("broken"))
+}
+
+/// Encodes the headers as an SCGI request with an empty body.
+fn scgi_request(headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut netstring = Vec::new();
+    for (k, v) in headers {
+        netstring.extend_from_slice(k.as_bytes());
+        netstring.push(0);
+        netstring.extend_from_slice(v.as_bytes());
+        netstring.push(0);
+    }
+    let mut request = format!("{}:", netstring.len()).into_bytes();
+    request.extend_from_slice(&netstring);
+    request.push(b',');
+    request
+}
+
+async fn roundtrip<H: Handler>(handler: AccessLog<H>, headers: &[(&str, &str)]) {
+    let (mut client, server_conn) = tokio::io::duplex(1024);
+    let server = Server::new(handler);
+    let serve = tokio::spawn(async move {
+        server
+            .serve_connection(server_conn, PeerAddr::Unix(None))
+            .await
+    });
+    client.write_all(&scgi_request(headers)).await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    let _ = serve.await.unwrap();
+}
+
+const HEADERS: &[(&str, &str)] = &[
+    ("CONTENT_LENGTH", "0"),
+    ("SCGI", "1"),
+    ("REMOTE_ADDR", "203.0.113.7"),
+    ("REMOTE_USER", "alice"),
+    ("REQUEST_METHOD", "GET"),
+    ("REQUEST_URI", "/path?q=1"),
+    ("SERVER_PROTOCOL", "HTTP/1.1"),
+    ("HTTP_REFERER", "https://example.com/"),
+    ("HTTP_USER_AGENT", "agent \"quoted\"\x01"),
+];
+
+#[tokio::test]
+async fn combined_format() {
+    let lines = Lines::default();
+    let sink = Arc::new(LogSink::writer(lines.clone()).unwrap());
+
+    roundtrip(
+        AccessLog::new(created, LogFormat::Combined, sink.clone()),
+        HEADERS,
+    )
+    .await;
+    sink.flush().unwrap();
+    let line = lines.take();
+    assert!(line.starts_with("203.0.113.7 - alice ["), "{}", line);
+    assert!(
+        line.ends_with(
+            " +0000] \"GET /path?q=1 HTTP/1.1\" 201 3 \"https://example.com/\" \"agent \\\"quoted\\\"\\x01\"\n"
+        ),
+        "{}",
+        line
+    );
+    // [18/Oct/2026:13:55:36 +0000]
+    let time = &line[line.find('[').unwrap() + 1..line.find(']').unwrap()];
+    assert_eq!(26, time.len(), "{}", time);
+    assert_eq!(b'/', time.as_bytes()[2]);
+    assert_eq!(b'/', time.as_bytes()[6]);
+
+    // Missing fields and handler errors.
+    roundtrip(AccessLog::new(fail, LogFormat::Combined, sink.clone()), &[]).await;
+    sink.flush().unwrap();
+    let line = lines.take();
+    assert!(line.starts_with("- - - ["), "{}", line);
+    assert!(line.ends_with("] \"-\" 500 - \"-\" \"-\"\n"), "{}", line);
+}
+
+#[tokio::test]
+async fn json_format() {
+    let lines = Lines::default();
+    let sink = Arc::new(LogSink::writer(lines.clone()).unwrap());
+
+    roundtrip(
+        AccessLog::new(created, LogFormat::Json, sink.clone()),
+        HEADERS,
+    )
+    .await;
+    sink.flush().unwrap();
+    let line = lines.take();
+    let now_year = 1970
+        + SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            / 31_556_952;
+    assert!(
+        line.starts_with(&format!("{{\"time\":\"{}-", now_year)),
+        "{}",
+        line
+    );
+    assert!(
+        line.contains(
+            "\"remote_addr\":\"203.0.113.7\",\"remote_user\":\"alice\",\"method\":\"GET\",\
+             \"uri\":\"/path?q=1\",\"protocol\":\"HTTP/1.1\",\"referer\":\"https://example.com/\",\
+             \"user_agent\":\"agent \\\"quoted\\\"\\u0001\",\"request_id\":null,\"status\":201,\"bytes\":3,\"duration_ms\":"
+        ),
+        "{}",
+        line
+    );
+    assert!(line.ends_with("}\n"), "{}", line);
+
+    roundtrip(AccessLog::new(fail, LogFormat::Json, sink.clone()), &[]).await;
+    sink.flush().unwrap();
+    let line = lines.take();
+    assert!(
+        line.contains("\"remote_addr\":null,") && line.contains("\"status\":500,\"bytes\":null,"),
+        "{}",
+        line
+    );
+}
+
+#[tokio::test]
+async fn reopen_on_sighup() {
+    let path = env::temp_dir().join(format!("tokio-scgi-access-{}.log", process::id()));
+    let rotated = path.with_extension("log.1");
+    let sink = Arc::new(LogSink::file(&path).unwrap());
+    let reopener = access_log::reopen_on_sighup(sink.clone()).unwrap();
+
+    roundtrip(
+        AccessLog::new(created, LogFormat::Combined, sink.clone()),
+        HEADERS,
+    )
+    .await;
+    fs::rename(&path, &rotated).unwrap();
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGHUP);
+    }
+    // Wait for the reopen to create the new file.
+    for _ in 0..100 {
+        if path.exists() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    roundtrip(
+        AccessLog::new(created, LogFormat::Combined, sink.clone()),
+        HEADERS,
+    )
+    .await;
+    sink.flush().unwrap();
+
+    assert_eq!(1, fs::read_to_string(&rotated).unwrap().lines().count());
+    assert_eq!(1, fs::read_to_string(&path).unwrap().lines().count());
+
+    reopener.abort();
+    fs::remove_file(&path).unwrap();
+    fs::remove_file(&rotated).unwrap();
+}
+
+/// A writer which always fails.
+struct Failing;
+
+impl Write for Failing {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(Error::other("disk full"))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn write_errors() {
+    let sink = Arc::new(LogSink::writer(Failing).unwrap());
+
+    // The request still succeeds, and the error is reported by the next flush only.
+    roundtrip(
+        AccessLog::new(created, LogFormat::Combined, sink.clone()),
+        HEADERS,
+    )
+    .await;
+    assert_eq!("disk full", sink.flush().unwrap_err().to_string());
+    sink.flush().unwrap();
+}
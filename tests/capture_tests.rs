@@ -0,0 +1,358 @@
+#![deny(warnings)]
+#![cfg(feature = "capture")]
+
+use futures::SinkExt;
+use std::io::{Error, ErrorKind, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio_util::codec::Framed;
+
+use tokio_scgi::capture::{Capture, Format, Pace, Reader, Record, Recorder, Replay};
+use tokio_scgi::client::{SCGICodec, SCGIRequest};
+use tokio_scgi::listener::{Address, Listener, PeerAddr};
+use tokio_scgi::runtime::{Request, Server};
+
+/// A capture file in memory, which can be read back after the recorder is done with it.
+#[derive(Clone, Default)]
+struct Shared(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Shared {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn record(uri: &str, time_us: u64) -> Record {
+    Record {
+        time_us,
+        peer: None,
+        headers: vec![
+            ("CONTENT_LENGTH".to_string(), "3".to_string()),
+            ("REQUEST_URI".to_string(), uri.to_string()),
+        ],
+        body: vec![0, 0xff, b'\n'],
+        duration_us: None,
+        response: None,
+    }
+}
+
+fn round_trip(format: Format, records: &[Record]) -> Vec<u8> {
+    let shared = Shared::default();
+    let recorder = Recorder::new(shared.clone(), format).unwrap();
+    for record in records {
+        recorder.record(record).unwrap();
+    }
+    recorder.flush().unwrap();
+    let encoded = shared.0.lock().unwrap().clone();
+    let reader = Reader::new(&encoded[..]).unwrap();
+    assert_eq!(format, reader.format());
+    let read = reader.collect::<Result<Vec<_>, _>>().unwrap();
+    assert_eq!(records, &read[..]);
+    encoded
+}
+
+/// Starts an SCGI server which responds with the request URI, and returns its address.
+async fn target(status: &'static str) -> Address {
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let addr = match &listener {
+        Listener::Tcp(listener) => listener.local_addr().unwrap(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    let server = Server::new(move |request: Request| async move {
+        Ok::<_, Error>(
+            format!(
+                "Status: {}\r\nDate: {:?}\r\n\r\n{}",
+                status,
+                Instant::now(),
+                request.header("REQUEST_URI").unwrap_or("")
+            )
+            .into_bytes(),
+        )
+    });
+    tokio::spawn(async move { server.serve(&listener).await });
+    addr.to_string().parse().unwrap()
+}
+
+#[test]
+fn formats_round_trip() {
+    let mut full = record("/full", 1_000_000);
+    full.peer = Some("127.0.0.1:1234".to_string());
+    full.duration_us = Some(250);
+    full.response = Some(b"Status: 200 OK\r\n\r\nhello".to_vec());
+    let records = vec![record("/", 0), full, record("/empty", 2_000_000)];
+
+    let json = round_trip(Format::JsonLines, &records);
+    let json = String::from_utf8(json).unwrap();
+    assert_eq!(3, json.lines().count());
+    // Bodies are base64.
+    assert!(json.contains("\"body\":\"AP8K\""), "{}", json);
+    assert!(json.contains("\"response\":\"U3RhdHVzOiAyMDAgT0sNCg0KaGVsbG8=\""));
+
+    let binary = round_trip(Format::Binary, &records);
+    assert!(binary.starts_with(b"SCGICAP1"));
+    assert!(binary.len() < json.len());
+
+    // Empty captures.
+    round_trip(Format::JsonLines, &[]);
+    round_trip(Format::Binary, &[]);
+}
+
+#[test]
+fn invalid_captures() {
+    let reader = Reader::new(&b"{\"time_us\":1}\n"[..]).unwrap();
+    let e = reader.collect::<Result<Vec<_>, _>>().unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, e.kind());
+
+    let line = b"{\"time_us\":1,\"peer\":null,\"headers\":[],\"body\":\"A*==\"}\n";
+    let e = Reader::new(&line[..]).unwrap().next().unwrap().unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, e.kind());
+    assert!(e.to_string().contains("line 1"), "{}", e);
+
+    // Truncated records, and lengths which are far longer than the capture.
+    let binary = round_trip(Format::Binary, &[record("/", 0)]);
+    for end in [12, binary.len() - 1] {
+        let mut reader = Reader::new(&binary[..end]).unwrap();
+        assert_eq!(
+            ErrorKind::UnexpectedEof,
+            reader.next().unwrap().unwrap_err().kind()
+        );
+        assert!(reader.next().is_none());
+    }
+    // A header key of almost u64::MAX bytes.
+    let mut huge = b"SCGICAP1\x00\x00\x01".to_vec();
+    huge.extend_from_slice(&[0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+    let mut reader = Reader::new(&huge[..]).unwrap();
+    assert_eq!(
+        ErrorKind::UnexpectedEof,
+        reader.next().unwrap().unwrap_err().kind()
+    );
+    let mut reader =
+        Reader::new(&b"SCGICAP1\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff"[..]).unwrap();
+    assert_eq!(
+        ErrorKind::InvalidData,
+        reader.next().unwrap().unwrap_err().kind()
+    );
+}
+
+/// A capture file on a full disk.
+struct Failing;
+
+impl Write for Failing {
+    fn write(&mut self, _: &[u8]) -> Result<usize, Error> {
+        Err(Error::other("disk full"))
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn recorder_writes_in_background() {
+    // Queued records are written by the time the recorder has been dropped.
+    let shared = Shared::default();
+    let recorder = Recorder::new(shared.clone(), Format::Binary).unwrap();
+    for i in 0..100 {
+        recorder.record(&record("/", i)).unwrap();
+    }
+    drop(recorder);
+    let encoded = shared.0.lock().unwrap().clone();
+    assert_eq!(100, Reader::new(&encoded[..]).unwrap().count());
+
+    // Write errors are reported by the next flush, and only once.
+    let recorder = Recorder::new(Failing, Format::JsonLines).unwrap();
+    recorder.flush().unwrap();
+    recorder.record(&record("/", 0)).unwrap();
+    recorder.record(&record("/", 1)).unwrap();
+    assert_eq!("disk full", recorder.flush().unwrap_err().to_string());
+    recorder.flush().unwrap();
+}
+
+#[tokio::test]
+async fn capture_then_replay() {
+    // Capture a request along with its response.
+    let shared = Shared::default();
+    let recorder = Arc::new(Recorder::new(shared.clone(), Format::JsonLines).unwrap());
+    let server = Server::new(Capture::new(
+        |request: Request| async move {
+            let mut response = b"Status: 200 OK\r\nDate: today\r\n\r\n".to_vec();
+            response.extend_from_slice(request.header("REQUEST_URI").unwrap().as_bytes());
+            Ok::<_, Error>(response)
+        },
+        recorder.clone(),
+    ));
+    let (client, conn) = tokio::io::duplex(1024);
+    let serving = server.serve_connection(conn, PeerAddr::Unix(None));
+    let send = async move {
+        let mut client = Framed::new(client, SCGICodec::new());
+        let headers = record("/a", 0).headers;
+        client
+            .send(SCGIRequest::Request(headers, (&b"abc"[..]).into()))
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        client
+            .into_inner()
+            .read_to_end(&mut response)
+            .await
+            .unwrap();
+    };
+    let (served, ()) = tokio::join!(serving, send);
+    served.unwrap();
+    tokio::task::spawn_blocking(move || recorder.flush())
+        .await
+        .unwrap()
+        .unwrap();
+    let captured = shared.0.lock().unwrap().clone();
+    let records = Reader::new(&captured[..])
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(1, records.len());
+    assert_eq!(b"abc".to_vec(), records[0].body);
+    assert_eq!(Some("unix"), records[0].peer.as_deref());
+    assert!(records[0].duration_us.is_some());
+
+    // The same response, apart from the ignored Date header.
+    let replay = Replay::new(target("200 OK").await).ignore_header("date");
+    let replayed = replay.run(records.clone()).await;
+    assert_eq!(None, replayed[0].diff);
+    assert!(replayed[0].response.as_ref().unwrap().ends_with(b"/a"));
+
+    // Without ignoring Date, or with a different status.
+    let replay = Replay::new(target("200 OK").await);
+    let diff = replay.run(records.clone()).await.remove(0).diff.unwrap();
+    assert!(diff.starts_with("Header line 2"), "{}", diff);
+    let replay = Replay::new(target("500 Internal Server Error").await);
+    let diff = replay.run(records).await.remove(0).diff.unwrap();
+    assert_eq!("Status 500, expected 200", diff);
+}
+
+#[tokio::test]
+async fn replay_pace() {
+    let target = target("200 OK").await;
+    let records = vec![record("/first", 1_000_000), record("/second", 1_400_000)];
+
+    // 400ms apart, four times as fast.
+    let started = Instant::now();
+    let replayed = Replay::new(target.clone())
+        .pace(Pace::Speed(4.0))
+        .run(records.clone())
+        .await;
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+    let uris = replayed
+        .iter()
+        .map(|r| {
+            r.response
+                .as_ref()
+                .unwrap()
+                .ends_with(r.record.header("REQUEST_URI").unwrap().as_bytes())
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(vec![true, true], uris);
+    // Nothing to compare against without captured responses.
+    assert!(replayed.iter().all(|r| r.diff.is_none()));
+
+    let started = Instant::now();
+    let replayed = Replay::new(target).pace(Pace::Unpaced).run(records).await;
+    assert!(started.elapsed() < Duration::from_millis(100));
+    assert_eq!(2, replayed.len());
+
+    // A target which isn't listening.
+    let refused: Address = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string().parse().unwrap()
+    };
+    let mut failed = record("/", 0);
+    failed.response = Some(b"Status: 200 OK\r\n\r\n".to_vec());
+    let replayed = Replay::new(refused).run(vec![failed]).await.remove(0);
+    assert!(replayed.response.is_err());
+    assert!(replayed.diff.unwrap().starts_with("Request failed"));
+}
+
+#[tokio::test]
+async fn replay_concurrency() {
+    // A target which takes a while to respond, and tracks how many requests it has in flight.
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let max_in_flight = Arc::new(AtomicUsize::new(0));
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let target: Address = match &listener {
+        Listener::Tcp(listener) => listener.local_addr().unwrap().to_string().parse().unwrap(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    let server = {
+        let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+        Server::new(move |_: Request| {
+            let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                Ok::<_, Error>(b"Status: 200 OK\r\n\r\n".to_vec())
+            }
+        })
+    };
+    tokio::spawn(async move { server.serve(&listener).await });
+
+    // Captured at the same time, so they're all due at once.
+    let records = (0..12).map(|_| record("/", 0)).collect::<Vec<_>>();
+    let replayed = Replay::new(target)
+        .pace(Pace::Speed(1.0))
+        .concurrency(3)
+        .run(records)
+        .await;
+    assert!(replayed.iter().all(|r| r.response.is_ok()));
+    assert_eq!(3, max_in_flight.load(Ordering::SeqCst));
+}
+
+#[test]
+fn base64_bodies() {
+    // The test vectors from RFC 4648.
+    for (body, encoded) in [
+        ("", ""),
+        ("f", "Zg=="),
+        ("fo", "Zm8="),
+        ("foo", "Zm9v"),
+        ("foob", "Zm9vYg=="),
+        ("fooba", "Zm9vYmE="),
+        ("foobar", "Zm9vYmFy"),
+    ] {
+        let mut record = record("/", 0);
+        record.body = body.as_bytes().to_vec();
+        let json = round_trip(Format::JsonLines, &[record]);
+        let json = String::from_utf8(json).unwrap();
+        assert!(
+            json.contains(&format!("\"body\":\"{}\"", encoded)),
+            "{}",
+            json
+        );
+
+        // Padding is optional when reading.
+        let unpadded = json.replace('=', "");
+        let read = Reader::new(unpadded.as_bytes()).unwrap().next().unwrap();
+        assert_eq!(body.as_bytes(), &read.unwrap().body[..]);
+    }
+
+    for invalid in ["Z", "Zm9v*g==", "Zm9vYg==Zg=="] {
+        let line = format!(
+            "{{\"time_us\":1,\"peer\":null,\"headers\":[],\"body\":\"{}\"}}\n",
+            invalid
+        );
+        let e = Reader::new(line.as_bytes())
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.kind(), "{}", invalid);
+    }
+}
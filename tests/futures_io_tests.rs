@@ -0,0 +1,113 @@
+#![deny(warnings)]
+#![cfg(feature = "futures-io")]
+
+use asynchronous_codec::{FramedRead, FramedWrite};
+use bytes::BytesMut;
+use futures::executor::block_on;
+use futures::io::{AsyncRead, Cursor};
+use futures::{SinkExt, StreamExt};
+use std::io::{Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio_scgi::client::{SCGICodec as ClientCodec, SCGIRequest as ClientRequest};
+use tokio_scgi::proto::{SCGIError, SCGIErrorKind};
+use tokio_scgi::server::{SCGICodec as ServerCodec, SCGIRequest as ServerRequest};
+
+/// Returns at most `limit` bytes per read, to check that requests are reassembled across reads.
+struct Trickle<'a> {
+    data: &'a [u8],
+    limit: usize,
+}
+
+impl AsyncRead for Trickle<'_> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize, Error>> {
+        let len = self.data.len().min(self.limit).min(buf.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+        Poll::Ready(Ok(len))
+    }
+}
+
+fn headers(body_len: usize) -> Vec<(String, String)> {
+    vec![
+        ("CONTENT_LENGTH".to_string(), body_len.to_string()),
+        ("SCGI".to_string(), "1".to_string()),
+        ("REQUEST_URI".to_string(), "/futures".to_string()),
+    ]
+}
+
+#[test]
+fn client_to_server() {
+    block_on(async {
+        let mut encoded = Vec::new();
+        let mut client = FramedWrite::new(&mut encoded, ClientCodec::new());
+        client
+            .send(ClientRequest::Request(
+                headers(11),
+                BytesMut::from(&b"hello"[..]),
+            ))
+            .await
+            .unwrap();
+        client
+            .send(ClientRequest::BodyFragment(BytesMut::from(&b" world"[..])))
+            .await
+            .unwrap();
+
+        for limit in [1, 7, 4096] {
+            let reader = Trickle {
+                data: &encoded,
+                limit,
+            };
+            let mut server = FramedRead::new(reader, ServerCodec::new());
+            let (headers_read, mut body) = match server.next().await {
+                Some(Ok(ServerRequest::Request(headers, body))) => (headers, body),
+                other => panic!("Unexpected request: {:?}", other),
+            };
+            assert_eq!(headers(11), headers_read);
+            while let Some(fragment) = server.next().await {
+                match fragment.unwrap() {
+                    ServerRequest::BodyFragment(fragment) => body.extend_from_slice(&fragment),
+                    other => panic!("Unexpected fragment: {:?}", other),
+                }
+            }
+            assert_eq!(&b"hello world"[..], &body[..]);
+        }
+    });
+}
+
+#[test]
+fn server_to_client() {
+    block_on(async {
+        let mut response = Vec::new();
+        let mut server = FramedWrite::new(&mut response, ServerCodec::new());
+        server
+            .send(b"Status: 200 OK\r\n\r\nhi".to_vec())
+            .await
+            .unwrap();
+
+        let mut client = FramedRead::new(Cursor::new(response), ClientCodec::new());
+        let mut received = Vec::new();
+        while let Some(chunk) = client.next().await {
+            received.extend_from_slice(&chunk.unwrap());
+        }
+        assert_eq!(&b"Status: 200 OK\r\n\r\nhi"[..], &received[..]);
+    });
+}
+
+#[test]
+fn malformed_request() {
+    block_on(async {
+        let mut server = FramedRead::new(Cursor::new(b"5x:".to_vec()), ServerCodec::new());
+        let e = server.next().await.unwrap().unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.kind());
+        assert_eq!(
+            Some(SCGIErrorKind::InvalidHeaderSize),
+            SCGIError::from_io(&e).map(SCGIError::kind)
+        );
+    });
+}
@@ -0,0 +1,214 @@
+#![deny(warnings)]
+#![cfg(feature = "runtime")]
+
+use bytes::BytesMut;
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::Decoder;
+
+use tokio_scgi::listener::PeerAddr;
+use tokio_scgi::metrics::ScgiMetrics;
+use tokio_scgi::runtime::{Request, Server};
+use tokio_scgi::server::{SCGICodec, SCGIError, SCGIErrorKind};
+
+/// Records each call as a string, in order.
+#[derive(Default)]
+struct Recorder(Mutex<Vec<String>>);
+
+impl Recorder {
+    fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+
+    fn push(&self, call: String) {
+        self.0.lock().unwrap().push(call);
+    }
+}
+
+impl ScgiMetrics for Recorder {
+    fn connection_opened(&self) {
+        self.push("opened".to_string());
+    }
+
+    fn connection_closed(&self) {
+        self.push("closed".to_string());
+    }
+
+    fn request_completed(&self, status: u16) {
+        self.push(format!("status {}", status));
+    }
+
+    fn decode_error(&self, kind: SCGIErrorKind) {
+        self.push(format!("decode_error {}", kind.as_str()));
+    }
+
+    fn header_size(&self, bytes: usize) {
+        self.push(format!("header_size {}", bytes));
+    }
+
+    fn body_size(&self, bytes: usize) {
+        self.push(format!("body_size {}", bytes));
+    }
+
+    fn time_to_first_byte(&self, elapsed: Duration) {
+        assert!(elapsed < Duration::from_secs(10));
+        self.push("time_to_first_byte".to_string());
+    }
+}
+
+async fn not_found(_request: Request) -> Result<Vec<u8>, Error> {
+    Ok(b"Status: 404 Not Found\r\nContent-Type: text/plain\r\n\r\nmissing".to_vec())
+}
+
+/// Sends the raw request to a server and returns the raw response.
+async fn roundtrip(server: Server<impl tokio_scgi::runtime::Handler>, request: &[u8]) -> Vec<u8> {
+    let (mut client, server_conn) = tokio::io::duplex(1024);
+    let serve = tokio::spawn(async move {
+        server
+            .serve_connection(server_conn, PeerAddr::Unix(None))
+            .await
+    });
+    client.write_all(request).await.unwrap();
+    let mut response = Vec::new();
+    client.read_to_end(&mut response).await.unwrap();
+    let _ = serve.await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn runtime_reports_request() {
+    let recorder = Arc::new(Recorder::default());
+    let server = Server::new(not_found).metrics(recorder.clone());
+
+    let response = roundtrip(server, b"17:CONTENT_LENGTH\x005\x00,hello").await;
+    assert!(response.starts_with(b"Status: 404"));
+    assert_eq!(
+        vec![
+            "opened",
+            "header_size 17",
+            "body_size 5",
+            "time_to_first_byte",
+            "status 404",
+            "closed"
+        ],
+        recorder.take()
+    );
+}
+
+#[tokio::test]
+async fn runtime_reports_decode_errors() {
+    let recorder = Arc::new(Recorder::default());
+    let server = Server::new(not_found).metrics(recorder.clone());
+    roundtrip(server.clone(), b"01:").await;
+    assert_eq!(
+        vec![
+            "opened",
+            "decode_error invalid_header_size",
+            "time_to_first_byte",
+            "status 400",
+            "closed"
+        ],
+        recorder.take()
+    );
+
+    roundtrip(server, b"18:CONTENT_LENGTH\x00xx\x00,").await;
+    assert_eq!(
+        vec![
+            "opened",
+            "header_size 18",
+            "decode_error invalid_content_length",
+            "time_to_first_byte",
+            "status 400",
+            "closed"
+        ],
+        recorder.take()
+    );
+}
+
+#[test]
+fn codec_errors_have_kinds() {
+    let recorder = Arc::new(Recorder::default());
+    let mut codec = SCGICodec::with_metrics(recorder.clone());
+    let mut buf = BytesMut::from(&b"4:A\x00B\x00;"[..]);
+    let err = codec.decode(&mut buf).unwrap_err();
+    assert_eq!(
+        SCGIErrorKind::MissingSeparator,
+        SCGIError::from_io(&err).unwrap().kind()
+    );
+    assert_eq!(vec!["decode_error missing_separator"], recorder.take());
+
+    let mut codec = SCGICodec::new();
+    let mut buf = BytesMut::from(&b"999999999:"[..]);
+    let err = codec.decode(&mut buf).unwrap_err();
+    assert_eq!(
+        SCGIErrorKind::HeaderTooLarge,
+        SCGIError::from_io(&err).unwrap().kind()
+    );
+}
+
+#[tokio::test]
+async fn response_status_from_http_status_line() {
+    let recorder = Arc::new(Recorder::default());
+    let server = Server::new(|_request: Request| async {
+        Ok(b"HTTP/1.1 302 Found\r\nLocation: /\r\n\r\n".to_vec())
+    })
+    .metrics(recorder.clone());
+    roundtrip(server, b"0:,").await;
+    assert!(recorder.take().contains(&"status 302".to_string()));
+
+    let server = Server::new(|_request: Request| async {
+        Ok(b"Content-Type: text/plain\r\n\r\nok".to_vec())
+    })
+    .metrics(recorder.clone());
+    roundtrip(server, b"0:,").await;
+    assert!(recorder.take().contains(&"status 200".to_string()));
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn metrics_facade_reports_to_recorder() {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use tokio_scgi::metrics::MetricsFacade;
+
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    metrics::with_local_recorder(&recorder, || {
+        MetricsFacade.connection_opened();
+        MetricsFacade.request_completed(404);
+        MetricsFacade.request_completed(404);
+        MetricsFacade.decode_error(SCGIErrorKind::MissingSeparator);
+        MetricsFacade.header_size(17);
+    });
+
+    let snapshot = snapshotter.snapshot().into_vec();
+    let find = |name: &str| {
+        snapshot
+            .iter()
+            .find(|(key, _, _, _)| key.key().name() == name)
+            .unwrap_or_else(|| panic!("{} missing from {:?}", name, snapshot))
+    };
+    let (key, _, _, value) = find("scgi_requests_total");
+    assert_eq!(
+        vec![("status", "404")],
+        key.key()
+            .labels()
+            .map(|l| (l.key(), l.value()))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(&DebugValue::Counter(2), value);
+    let (key, _, _, _) = find("scgi_decode_errors_total");
+    assert_eq!(
+        Some("missing_separator"),
+        key.key().labels().next().map(|l| l.value())
+    );
+    assert!(matches!(
+        find("scgi_connections_active").3,
+        DebugValue::Gauge(_)
+    ));
+    assert!(matches!(
+        find("scgi_header_bytes").3,
+        DebugValue::Histogram(_)
+    ));
+}
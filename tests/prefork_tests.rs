@@ -0,0 +1,183 @@
+#![deny(warnings)]
+
+//! Runs without the libtest harness: Prefork workers re-execute the test binary, and each worker
+//! must become a worker before anything else runs, rather than running the tests in parallel.
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant};
+use tokio::net::UnixStream;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_util::codec::Framed;
+
+use tokio_scgi::client::{SCGICodec as ClientCodec, SCGIRequest as ClientRequest};
+use tokio_scgi::listener::Listener;
+use tokio_scgi::prefork::{Prefork, Worker, WORKER_FD_ENV, WORKER_MAX_REQUESTS_ENV};
+use tokio_scgi::runtime::{Request, Server};
+
+/// If set on the parent, workers append a line to the file at this path and exit straight away,
+/// without serving anything.
+const SPAWN_LOG_ENV: &str = "PREFORK_TEST_SPAWN_LOG";
+
+/// Responds with the worker's pid, and whether the prefork variables were left in its environment.
+/// Crashes for requests with a CRASH header, and exits cleanly for requests with an EXIT header.
+async fn handle(request: Request) -> Result<Vec<u8>, Error> {
+    if request.header("CRASH").is_some() {
+        process::abort();
+    }
+    if request.header("EXIT").is_some() {
+        process::exit(0);
+    }
+    let leaked =
+        env::var_os(WORKER_FD_ENV).is_some() || env::var_os(WORKER_MAX_REQUESTS_ENV).is_some();
+    Ok(format!("{} {}", process::id(), leaked).into_bytes())
+}
+
+/// Sends a request and returns the response, which is empty if the worker exited.
+async fn query(path: &Path, header: Option<&str>) -> String {
+    let conn = UnixStream::connect(path).await.unwrap();
+    let mut framed = Framed::new(conn, ClientCodec::new());
+    let headers = header
+        .map(|name| vec![(name.to_string(), "1".to_string())])
+        .unwrap_or_default();
+    framed
+        .send(ClientRequest::Request(headers, BytesMut::new()))
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    while let Some(Ok(chunk)) = framed.next().await {
+        if chunk.is_empty() {
+            break;
+        }
+        response.extend_from_slice(&chunk);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+/// Returns the pid of the worker that served a request.
+async fn worker_pid(path: &Path) -> String {
+    let response = query(path, None).await;
+    let (pid, leaked) = response.split_once(' ').unwrap();
+    assert_eq!(
+        "false", leaked,
+        "Prefork variables were left in the worker's environment"
+    );
+    assert_ne!(process::id().to_string(), pid);
+    pid.to_string()
+}
+
+fn start(name: &str, prefork: Prefork) -> (PathBuf, JoinHandle<Result<(), Error>>) {
+    let path = env::temp_dir().join(format!(
+        "tokio-scgi-prefork-{}-{}.sock",
+        name,
+        process::id()
+    ));
+    let listener = Listener::bind_unix(&path).unwrap();
+    (
+        path,
+        tokio::spawn(async move { prefork.serve(&listener).await }),
+    )
+}
+
+async fn recycles_after_max_requests() {
+    let (path, serve) = start("recycle", Prefork::new(1).max_requests(2));
+    let first = worker_pid(&path).await;
+    assert_eq!(first, worker_pid(&path).await);
+    let second = worker_pid(&path).await;
+    assert_ne!(first, second);
+    assert_eq!(second, worker_pid(&path).await);
+    assert_ne!(second, worker_pid(&path).await);
+    serve.abort();
+    fs::remove_file(&path).unwrap();
+}
+
+async fn restarts_exited_workers() {
+    let (path, serve) = start("exit", Prefork::new(1));
+    let first = worker_pid(&path).await;
+    assert_eq!(first, worker_pid(&path).await);
+    assert_eq!("", query(&path, Some("EXIT")).await);
+    // Workers which exit cleanly are replaced straight away.
+    let started = Instant::now();
+    assert_ne!(first, worker_pid(&path).await);
+    assert!(started.elapsed() < Duration::from_secs(1));
+    serve.abort();
+    fs::remove_file(&path).unwrap();
+}
+
+async fn restarts_crashed_workers() {
+    let (path, serve) = start("crash", Prefork::new(1));
+    let before = worker_pid(&path).await;
+    assert_eq!("", query(&path, Some("CRASH")).await);
+    // The request waits for the replacement worker rather than being refused.
+    assert_ne!(before, worker_pid(&path).await);
+    serve.abort();
+    fs::remove_file(&path).unwrap();
+}
+
+async fn rejects_zero() {
+    let (path, serve) = start("zero-workers", Prefork::new(0));
+    let err = serve.await.unwrap().unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, err.kind());
+    fs::remove_file(&path).unwrap();
+
+    let (path, serve) = start("zero-requests", Prefork::new(1).max_requests(0));
+    let err = serve.await.unwrap().unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, err.kind());
+    fs::remove_file(&path).unwrap();
+}
+
+async fn delays_restarting_idle_workers() {
+    let log = env::temp_dir().join(format!("tokio-scgi-prefork-spawns-{}", process::id()));
+    env::set_var(SPAWN_LOG_ENV, &log);
+    let (path, serve) = start("idle", Prefork::new(1));
+    time::sleep(Duration::from_millis(500)).await;
+    serve.abort();
+    env::remove_var(SPAWN_LOG_ENV);
+
+    // Workers which exit cleanly without serving anything are restarted after a delay, rather
+    // than in a tight loop.
+    let spawns = fs::read_to_string(&log).unwrap().lines().count();
+    assert!((1..=2).contains(&spawns), "{}", spawns);
+    fs::remove_file(&log).unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+async fn run<F: Future<Output = ()>>(name: &str, test: F) {
+    test.await;
+    println!("test {} ... ok", name);
+}
+
+fn main() {
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        if let Some(worker) = Worker::from_env().unwrap() {
+            if let Some(log) = env::var_os(SPAWN_LOG_ENV) {
+                let mut log = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(log)
+                    .unwrap();
+                writeln!(log, "{}", process::id()).unwrap();
+                return;
+            }
+            worker.serve(Server::new(handle)).await.unwrap();
+            return;
+        }
+        run("recycles_after_max_requests", recycles_after_max_requests()).await;
+        run("restarts_exited_workers", restarts_exited_workers()).await;
+        run("restarts_crashed_workers", restarts_crashed_workers()).await;
+        run("rejects_zero", rejects_zero()).await;
+        run(
+            "delays_restarting_idle_workers",
+            delays_restarting_idle_workers(),
+        )
+        .await;
+    });
+}
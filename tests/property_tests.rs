@@ -1,16 +1,19 @@
 #![deny(warnings)]
+#![cfg(feature = "codec")]
 
 use bytes::{BufMut, BytesMut};
 use proptest::prelude::*;
 use tokio_util::codec::{Decoder, Encoder};
 
 use tokio_scgi::client::{SCGICodec as ClientCodec, SCGIRequest as ClientRequest};
-use tokio_scgi::server::{SCGICodec as ServerCodec, SCGIRequest as ServerRequest};
+use tokio_scgi::server::{
+    SCGICodec as ServerCodec, SCGIError, SCGIErrorKind, SCGIRequest as ServerRequest,
+};
 
 #[test]
 fn decode_encode_protocol_sample() {
     // Sample from SCGI protocol.txt:
-    let protocol_sample = b"70:CONTENT_LENGTH\027\0SCGI\01\0REQUEST_METHOD\0POST\0REQUEST_URI\0/deepthought\0,What is the answer to life?";
+    let protocol_sample = b"70:CONTENT_LENGTH\x0027\0SCGI\x001\0REQUEST_METHOD\0POST\0REQUEST_URI\0/deepthought\0,What is the answer to life?";
 
     let mut buf = BytesMut::with_capacity(protocol_sample.len());
     buf.put_slice(protocol_sample);
@@ -18,11 +21,12 @@ fn decode_encode_protocol_sample() {
     let mut decoder = ServerCodec::new();
 
     // First call should produce both headers and body
-    let mut expected_headers = Vec::new();
-    expected_headers.push(("CONTENT_LENGTH".to_string(), "27".to_string()));
-    expected_headers.push(("SCGI".to_string(), "1".to_string()));
-    expected_headers.push(("REQUEST_METHOD".to_string(), "POST".to_string()));
-    expected_headers.push(("REQUEST_URI".to_string(), "/deepthought".to_string()));
+    let expected_headers = vec![
+        ("CONTENT_LENGTH".to_string(), "27".to_string()),
+        ("SCGI".to_string(), "1".to_string()),
+        ("REQUEST_METHOD".to_string(), "POST".to_string()),
+        ("REQUEST_URI".to_string(), "/deepthought".to_string()),
+    ];
     let expected_body_str = b"What is the answer to life?";
     let mut expected_body = BytesMut::new();
     expected_body.reserve(expected_body_str.len());
@@ -56,6 +60,38 @@ fn decode_encode_protocol_sample() {
     assert_eq!(buf.to_vec(), protocol_sample.to_vec());
 }
 
+#[test]
+fn encode_required_headers() {
+    let protocol_sample = b"70:CONTENT_LENGTH\x0027\0SCGI\x001\0REQUEST_METHOD\0POST\0REQUEST_URI\0/deepthought\0,What is the answer to life?";
+    let headers = vec![
+        ("REQUEST_METHOD".to_string(), "POST".to_string()),
+        ("REQUEST_URI".to_string(), "/deepthought".to_string()),
+    ];
+    let body = BytesMut::from(&b"What is the answer to life?"[..]);
+    let mut encoder = ClientCodec::new().required_headers();
+    let mut buf = BytesMut::new();
+    encoder
+        .encode(ClientRequest::Request(headers, body.clone()), &mut buf)
+        .unwrap();
+    assert_eq!(buf.to_vec(), protocol_sample.to_vec());
+
+    // Headers which are already present are left as they are.
+    let headers = vec![
+        ("CONTENT_LENGTH".to_string(), "27".to_string()),
+        ("SCGI".to_string(), "1".to_string()),
+        ("REQUEST_METHOD".to_string(), "POST".to_string()),
+        ("REQUEST_URI".to_string(), "/deepthought".to_string()),
+    ];
+    buf.clear();
+    encoder
+        .encode(ClientRequest::Request(headers, BytesMut::new()), &mut buf)
+        .unwrap();
+    encoder
+        .encode(ClientRequest::BodyFragment(body), &mut buf)
+        .unwrap();
+    assert_eq!(buf.to_vec(), protocol_sample.to_vec());
+}
+
 #[test]
 fn encode_decode_empty_headers() {
     let mut buf = BytesMut::new();
@@ -76,7 +112,7 @@ fn encode_decode_empty_headers() {
         assert_eq!(0, headers.len());
         assert_eq!(0, body.len());
     } else {
-        assert!(false, "expected None");
+        panic!("expected None");
     }
 
     check_content_slow(buf, Vec::new(), &String::new());
@@ -93,14 +129,55 @@ fn encode_decode_empty_body() {
     assert_eq!(0, buf.len());
 
     // Should get None when nothing's left
-    if let None = ServerCodec::new().decode(&mut buf).unwrap() {
-    } else {
-        assert!(false, "expected None");
-    }
+    assert!(
+        ServerCodec::new().decode(&mut buf).unwrap().is_none(),
+        "expected None"
+    );
 
     check_content_slow(buf, Vec::new(), &String::new());
 }
 
+#[test]
+fn long_header_string_rejected_in_one_read() {
+    // The limit on header strings applies even when the whole string arrives at once.
+    let long_value = "x".repeat(64 * 1024);
+    let mut buf = BytesMut::new();
+    ClientCodec::new()
+        .encode(
+            ClientRequest::Request(vec![("KEY".to_string(), long_value)], BytesMut::new()),
+            &mut buf,
+        )
+        .unwrap();
+    let err = ServerCodec::new().decode(&mut buf).unwrap_err();
+    assert_eq!(
+        SCGIErrorKind::HeaderStringTooLarge,
+        SCGIError::from_io(&err).unwrap().kind()
+    );
+}
+
+#[test]
+fn client_decode_passes_responses_through() {
+    let mut decoder = ClientCodec::new();
+    let mut buf = BytesMut::new();
+    // Nothing is returned until there's some response, rather than an empty chunk.
+    assert_eq!(None, decoder.decode(&mut buf).unwrap());
+
+    buf.put_slice(b"Status: 200 OK\r\n");
+    assert_eq!(
+        Some(BytesMut::from(&b"Status: 200 OK\r\n"[..])),
+        decoder.decode(&mut buf).unwrap()
+    );
+    assert_eq!(0, buf.len());
+    assert_eq!(None, decoder.decode(&mut buf).unwrap());
+
+    buf.put_slice(b"\r\nbody");
+    assert_eq!(
+        Some(BytesMut::from(&b"\r\nbody"[..])),
+        decoder.decode_eof(&mut buf).unwrap()
+    );
+    assert_eq!(None, decoder.decode_eof(&mut buf).unwrap());
+}
+
 proptest! {
     #[test]
     fn server_decode_doesnt_crash(s in ".*") {
@@ -158,15 +235,12 @@ fn check_content(headers: &Vec<(String, String)>, content: &String) {
         assert_eq!(headers, &headers_decoded);
         assert_eq!(content_req, body_decoded);
     } else {
-        assert!(false, "expected Headers (with content)");
+        panic!("expected Headers (with content)");
     }
 
     // Should get None when nothing's left
     assert_eq!(0, buf.len());
-    if let None = decoder.decode(&mut buf).unwrap() {
-    } else {
-        assert!(false, "expected None");
-    }
+    assert!(decoder.decode(&mut buf).unwrap().is_none(), "expected None");
 
     check_content_slow(encoded_data_combined, headers.to_vec(), content);
 
@@ -187,15 +261,12 @@ fn check_content(headers: &Vec<(String, String)>, content: &String) {
         assert_eq!(headers, &headers_decoded);
         assert_eq!(0, body_decoded.len());
     } else {
-        assert!(false, "expected Headers (without content)");
+        panic!("expected Headers (without content)");
     }
 
     // Should get None when nothing's left
     assert_eq!(0, buf.len());
-    if let None = decoder.decode(&mut buf).unwrap() {
-    } else {
-        assert!(false, "expected None");
-    }
+    assert!(decoder.decode(&mut buf).unwrap().is_none(), "expected None");
 
     check_content_slow(encoded_data_header_only, headers.clone(), &String::new());
 
@@ -218,8 +289,7 @@ fn check_content(headers: &Vec<(String, String)>, content: &String) {
         assert_eq!(headers, &headers_decoded);
         assert_eq!(content_req, body_decoded);
     } else {
-        assert!(
-            false,
+        panic!(
             "expected Headers (with content): {:?} (from {:?})",
             r, encoded_data_separate
         );
@@ -227,10 +297,7 @@ fn check_content(headers: &Vec<(String, String)>, content: &String) {
 
     // Should get None when nothing's left
     assert_eq!(0, buf.len());
-    if let None = decoder.decode(&mut buf).unwrap() {
-    } else {
-        assert!(false, "expected None");
-    }
+    assert!(decoder.decode(&mut buf).unwrap().is_none(), "expected None");
 
     check_content_slow(encoded_data_separate, headers.clone(), content);
 }
@@ -269,8 +336,7 @@ fn check_content_slow(
                 got_content.put(fragment);
             }
             Ok(None) => {}
-            Err(err) => assert!(
-                false,
+            Err(err) => panic!(
                 "Slow content error (added {} from {:?}): {}",
                 chr, data, err
             ),
@@ -0,0 +1,107 @@
+#![deny(warnings)]
+
+use tokio_scgi::proto::{self, EncodeError, Event, Parser, SCGIErrorKind};
+
+fn headers() -> Vec<(String, String)> {
+    vec![
+        ("CONTENT_LENGTH".to_string(), "5".to_string()),
+        ("SCGI".to_string(), "1".to_string()),
+        ("REQUEST_METHOD".to_string(), "POST".to_string()),
+    ]
+}
+
+/// Feeds the request in pieces of the provided size, and returns the headers and body.
+fn feed_in_pieces(request: &[u8], size: usize) -> (Vec<(String, String)>, Vec<u8>) {
+    let mut parser = Parser::new();
+    let mut headers = None;
+    let mut body = Vec::new();
+    for piece in request.chunks(size) {
+        match parser.feed(piece).unwrap() {
+            Event::NeedMore => assert!(!parser.headers_complete()),
+            Event::Headers {
+                headers: h,
+                consumed,
+            } => {
+                assert!(headers.is_none());
+                headers = Some(h);
+                match parser.feed(&piece[consumed..]).unwrap() {
+                    Event::Body(b) => body.extend_from_slice(b),
+                    other => panic!("Unexpected event: {:?}", other),
+                }
+            }
+            Event::Body(b) => body.extend_from_slice(b),
+        }
+    }
+    (headers.unwrap(), body)
+}
+
+#[test]
+fn encode_then_feed() {
+    let mut request = Vec::new();
+    assert_eq!(44, proto::encode_headers(&headers(), &mut request).unwrap());
+    request.extend_from_slice(b"hello");
+    assert_eq!(
+        b"44:CONTENT_LENGTH\x005\x00SCGI\x001\x00REQUEST_METHOD\x00POST\x00,hello".to_vec(),
+        request
+    );
+
+    for size in 1..=request.len() {
+        assert_eq!(
+            (headers(), b"hello".to_vec()),
+            feed_in_pieces(&request, size),
+            "pieces of {}",
+            size
+        );
+    }
+}
+
+#[test]
+fn feed_reports_header_size() {
+    let mut parser = Parser::new();
+    assert_eq!(0, parser.header_size());
+    assert_eq!(Event::NeedMore, parser.feed(b"8:KE").unwrap());
+    assert_eq!(8, parser.header_size());
+    assert_eq!(
+        Event::Headers {
+            headers: vec![("KEY".to_string(), "VAL".to_string())],
+            consumed: 7,
+        },
+        parser.feed(b"Y\0VAL\0,body").unwrap()
+    );
+    assert!(parser.headers_complete());
+    assert_eq!(Event::Body(b""), parser.feed(b"").unwrap());
+}
+
+#[test]
+fn feed_errors() {
+    let cases: [(&[u8], SCGIErrorKind); 5] = [
+        (b":", SCGIErrorKind::InvalidHeaderSize),
+        (b"08:", SCGIErrorKind::InvalidHeaderSize),
+        (b"999999:", SCGIErrorKind::HeaderTooLarge),
+        (b"8:KEY\0VAL\0;", SCGIErrorKind::MissingSeparator),
+        (b"8:KEY\0V\xffL\0,", SCGIErrorKind::InvalidHeaderString),
+    ];
+    for (request, kind) in cases.iter() {
+        let err = Parser::new().feed(request).unwrap_err();
+        assert_eq!(*kind, err.kind(), "{:?}: {}", request, err);
+    }
+}
+
+#[test]
+fn encode_errors_write_nothing() {
+    let mut buf = Vec::new();
+    let empty_key = vec![("".to_string(), "value".to_string())];
+    assert_eq!(
+        Err(EncodeError::EmptyKey),
+        proto::encode_headers(&empty_key, &mut buf)
+    );
+    let nul = vec![
+        ("KEY".to_string(), "value".to_string()),
+        ("NUL".to_string(), "va\0lue".to_string()),
+    ];
+    assert_eq!(
+        Err(EncodeError::NulCharacter),
+        proto::encode_headers(&nul, &mut buf)
+    );
+    assert!(buf.is_empty());
+}
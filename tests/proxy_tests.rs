@@ -0,0 +1,416 @@
+#![deny(warnings)]
+#![cfg(feature = "proxy")]
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use std::io::{Error, ErrorKind};
+use std::net::TcpListener as StdTcpListener;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio_util::codec::Framed;
+
+use tokio_scgi::client::{SCGICodec, SCGIRequest};
+use tokio_scgi::listener::{Cidr, Listener, PeerAddr};
+use tokio_scgi::proxy::{Address, Config, Proxy, Strategy};
+use tokio_scgi::runtime::{Request, Server};
+
+/// Starts a backend which responds with its name, or with 503 to health checks if it's `sick`.
+/// Returns its address.
+async fn backend(name: &'static str, sick: bool) -> String {
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let addr = match &listener {
+        Listener::Tcp(listener) => listener.local_addr().unwrap(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    let server = Server::new(move |request: Request| async move {
+        if request.header("REQUEST_URI") == Some("/health") && sick {
+            return Ok(b"Status: 503 Service Unavailable\r\n\r\n".to_vec());
+        }
+        Ok(format!("Status: 200 OK\r\n\r\n{}", name).into_bytes())
+    });
+    tokio::spawn(async move { server.serve(&listener).await });
+    addr.to_string()
+}
+
+/// Returns an address which refuses connections.
+fn dead_backend() -> String {
+    let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn config(backends: &[&str], extra: &str) -> Config {
+    let backends = backends
+        .iter()
+        .map(|b| format!("\"{}\"", b))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Config::parse(&format!(
+        "listen = \"127.0.0.1:0\"\nbackends = [{}]\n{}",
+        backends, extra
+    ))
+    .unwrap()
+}
+
+/// Sends a request through the proxy's server and returns the body of the response.
+async fn query(proxy: &Proxy, user: Option<&str>) -> String {
+    let (client, conn) = tokio::io::duplex(1024);
+    let server = proxy.server();
+    let serving = server.serve_connection(conn, PeerAddr::Unix(None));
+    let send = async move {
+        let mut client = Framed::new(client, SCGICodec::new());
+        let mut headers = vec![
+            ("CONTENT_LENGTH".to_string(), "0".to_string()),
+            ("SCGI".to_string(), "1".to_string()),
+        ];
+        if let Some(user) = user {
+            headers.push(("HTTP_X_USER".to_string(), user.to_string()));
+        }
+        client
+            .send(SCGIRequest::Request(headers, Default::default()))
+            .await
+            .unwrap();
+        let mut response = Vec::new();
+        while let Some(chunk) = client.next().await {
+            response.extend_from_slice(&chunk.unwrap());
+        }
+        String::from_utf8(response).unwrap()
+    };
+    let (_, response) = tokio::join!(serving, send);
+    match response.split_once("\r\n\r\n") {
+        Some((_, body)) => body.to_string(),
+        None => response,
+    }
+}
+
+#[test]
+fn parse_config() {
+    let config = Config::parse(
+        r#"
+        listen = "/run/scgi-proxy.sock"
+        backends = ["10.0.0.2:4000", "/run/app.sock"]
+        strategy = { consistent-hash = "HTTP_X_USER" }
+        retries = 1
+
+        [health-check]
+        headers = { REQUEST_URI = "/health" }
+        fall = 3
+        "#,
+    )
+    .unwrap();
+    assert_eq!(Address::Unix("/run/scgi-proxy.sock".into()), config.listen);
+    assert_eq!(
+        vec![
+            Address::Tcp("10.0.0.2:4000".to_string()),
+            Address::Unix("/run/app.sock".into())
+        ],
+        config.backends
+    );
+    assert_eq!(
+        Strategy::ConsistentHash("HTTP_X_USER".to_string()),
+        config.strategy
+    );
+    assert_eq!(1, config.retries);
+    assert_eq!(1000, config.connect_timeout_ms);
+    assert_eq!(60_000, config.response_timeout_ms);
+    assert_eq!(64 * 1024 * 1024, config.max_response_size);
+    let check = config.health_check.unwrap();
+    assert_eq!(
+        Some(&"/health".to_string()),
+        check.headers.get("REQUEST_URI")
+    );
+    assert_eq!(3, check.fall);
+    assert_eq!(2, check.rise);
+    assert_eq!(200, check.expect_status);
+    assert_eq!(None, config.max_concurrency);
+    assert!(config.allow_cidrs.is_empty());
+
+    let config = Config::parse(
+        r#"
+        listen = "127.0.0.1:4000"
+        backends = ["/run/app.sock"]
+        header-timeout-ms = 500
+        max-concurrency = 8
+        shed-retry-after-ms = 1000
+        allow-uids = [33]
+        allow-cidrs = ["10.0.0.0/8"]
+        deny-cidrs = ["10.1.0.0/16"]
+        "#,
+    )
+    .unwrap();
+    assert_eq!(Some(500), config.header_timeout_ms);
+    assert_eq!(Some(8), config.max_concurrency);
+    assert_eq!(Some(1000), config.shed_retry_after_ms);
+    assert_eq!(vec![33], config.allow_uids);
+    assert_eq!(
+        vec!["10.0.0.0/8".parse::<Cidr>().unwrap()],
+        config.allow_cidrs
+    );
+    assert_eq!(
+        vec!["10.1.0.0/16".parse::<Cidr>().unwrap()],
+        config.deny_cidrs
+    );
+
+    for invalid in [
+        "listen = \"127.0.0.1:0\"\nbackends = []",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"nope\"]",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\nstrategy = \"random\"",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\nretry = 1",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\n[health-check]\ntimeout-ms = 0",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\n[health-check]\ninterval-ms = 0",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\nwrite-timeout-ms = 0",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\nresponse-timeout-ms = 0",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\nmax-response-size = 0",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\nmax-queued = 4",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\nmax-concurrency = 0",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\nallow-cidrs = [\"10.0.0.0/33\"]",
+        "listen = \"127.0.0.1:0\"\nbackends = [\"a:1\"]\nmax-concurrency = 1\nmax-queued = 1\nshed-retry-after-ms = 1",
+    ] {
+        let e = Config::parse(invalid).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.kind(), "{}", invalid);
+    }
+}
+
+#[tokio::test]
+async fn round_robin_retries_dead_backends() {
+    let a = backend("a", false).await;
+    let b = backend("b", false).await;
+    let dead = dead_backend();
+    let proxy = Proxy::new(config(&[&a, &dead, &b], "")).unwrap();
+    let mut responses = Vec::new();
+    for _ in 0..6 {
+        responses.push(query(&proxy, None).await);
+    }
+    // The dead backend's turns go to the next backend.
+    assert_eq!(vec!["a", "b", "b", "a", "b", "b"], responses);
+
+    // Without retries, the client gets a 502 instead.
+    let proxy = Proxy::new(config(&[&dead], "retries = 0")).unwrap();
+    assert_eq!("502 Bad Gateway", query(&proxy, None).await);
+}
+
+#[tokio::test]
+async fn consistent_hash_is_sticky() {
+    let a = backend("a", false).await;
+    let b = backend("b", false).await;
+    let c = backend("c", false).await;
+    let extra = "strategy = { consistent-hash = \"HTTP_X_USER\" }";
+    let proxy = Proxy::new(config(&[&a, &b, &c], extra)).unwrap();
+
+    let mut assigned = Vec::new();
+    for user in 0..30 {
+        let user = user.to_string();
+        let first = query(&proxy, Some(&user)).await;
+        assert_eq!(first, query(&proxy, Some(&user)).await);
+        assigned.push(first);
+    }
+    for name in ["a", "b", "c"] {
+        assert!(assigned.iter().any(|n| n == name), "{:?}", assigned);
+    }
+
+    // Only the keys of a removed backend move elsewhere.
+    let proxy = Proxy::new(config(&[&a, &c], extra)).unwrap();
+    for (user, before) in assigned.iter().enumerate() {
+        let after = query(&proxy, Some(&user.to_string())).await;
+        if before != "b" {
+            assert_eq!(*before, after);
+        }
+    }
+}
+
+#[tokio::test]
+async fn least_connections_avoids_busy_backend() {
+    let release = Arc::new(Notify::new());
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let slow = match &listener {
+        Listener::Tcp(listener) => listener.local_addr().unwrap().to_string(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    let server = {
+        let release = release.clone();
+        Server::new(move |_: Request| {
+            let release = release.clone();
+            async move {
+                release.notified().await;
+                Ok::<_, Error>(b"Status: 200 OK\r\n\r\nslow".to_vec())
+            }
+        })
+    };
+    tokio::spawn(async move { server.serve(&listener).await });
+    let fast = backend("fast", false).await;
+    let proxy = Proxy::new(config(&[&slow, &fast], "strategy = \"least-connections\"")).unwrap();
+
+    let busy = {
+        let proxy = proxy.clone();
+        tokio::spawn(async move { query(&proxy, None).await })
+    };
+    while proxy.backends()[0].active == 0 {
+        tokio::task::yield_now().await;
+    }
+    for _ in 0..3 {
+        assert_eq!("fast", query(&proxy, None).await);
+    }
+    release.notify_one();
+    assert_eq!("slow", busy.await.unwrap());
+    assert!(proxy.backends().iter().all(|b| b.active == 0));
+}
+
+#[tokio::test]
+async fn health_checks_take_backends_out_of_rotation() {
+    let healthy = backend("healthy", false).await;
+    let sick = backend("sick", true).await;
+    let extra = "[health-check]\nheaders = { REQUEST_URI = \"/health\" }\nfall = 2\nrise = 1";
+    let proxy = Proxy::new(config(&[&sick, &healthy], extra)).unwrap();
+
+    // Still in rotation after a single failure.
+    proxy.check_health().await;
+    assert!(proxy.backends()[0].healthy);
+    proxy.check_health().await;
+    let status = proxy.backends();
+    assert!(!status[0].healthy);
+    assert!(status[1].healthy);
+    for _ in 0..4 {
+        assert_eq!("healthy", query(&proxy, None).await);
+    }
+}
+
+#[tokio::test]
+async fn limits_backend_responses() {
+    // Connections are accepted by the kernel, but the backend never reads or responds.
+    let stalled = StdTcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = stalled.local_addr().unwrap().to_string();
+    let proxy = Proxy::new(config(&[&addr], "response-timeout-ms = 100")).unwrap();
+    assert_eq!("504 Gateway Timeout", query(&proxy, None).await);
+    assert_eq!(0, proxy.backends()[0].active);
+
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let large = match &listener {
+        Listener::Tcp(listener) => listener.local_addr().unwrap().to_string(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    let server = Server::new(|_: Request| async {
+        let mut response = b"Status: 200 OK\r\n\r\n".to_vec();
+        response.resize(100_000, b'x');
+        Ok::<_, Error>(response)
+    });
+    tokio::spawn(async move { server.serve(&listener).await });
+    let proxy = Proxy::new(config(&[&large], "max-response-size = 100000")).unwrap();
+    assert_eq!(100_000 - 18, query(&proxy, None).await.len());
+    let proxy = Proxy::new(config(&[&large], "max-response-size = 99999")).unwrap();
+    assert_eq!("502 Bad Gateway", query(&proxy, None).await);
+}
+
+/// Runs the proxy on a local TCP listener, returning its address.
+async fn serve(proxy: Proxy) -> String {
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let addr = match &listener {
+        Listener::Tcp(listener) => listener.local_addr().unwrap(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    tokio::spawn(async move { proxy.serve(&listener).await });
+    addr.to_string()
+}
+
+/// Sends a request with the provided body over TCP and returns the whole response, which is empty
+/// if the connection was closed without one.
+async fn tcp_query(addr: &str, body: &[u8]) -> String {
+    let conn = TcpStream::connect(addr).await.unwrap();
+    let mut client = Framed::new(conn, SCGICodec::new().required_headers());
+    let _ = client
+        .send(SCGIRequest::Request(Vec::new(), BytesMut::from(body)))
+        .await;
+    let mut response = Vec::new();
+    while let Some(Ok(chunk)) = client.next().await {
+        response.extend_from_slice(&chunk);
+    }
+    String::from_utf8(response).unwrap()
+}
+
+#[tokio::test]
+async fn server_applies_limits_and_access_control() {
+    let a = backend("a", false).await;
+
+    // Larger bodies are refused without being forwarded.
+    let addr = serve(Proxy::new(config(&[&a], "max-body-size = 4")).unwrap()).await;
+    assert!(tcp_query(&addr, b"1234").await.ends_with("\r\n\r\na"));
+    let response = tcp_query(&addr, b"12345").await;
+    assert!(
+        response.starts_with("Status: 413 Payload Too Large\r\n"),
+        "{}",
+        response
+    );
+
+    // Clients which don't send their request in time get a 408.
+    let addr = serve(Proxy::new(config(&[&a], "header-timeout-ms = 50")).unwrap()).await;
+    let mut stalled = TcpStream::connect(&addr).await.unwrap();
+    let mut response = String::new();
+    stalled.read_to_string(&mut response).await.unwrap();
+    assert!(
+        response.starts_with("Status: 408 Request Timeout\r\n"),
+        "{}",
+        response
+    );
+
+    // Denied clients are disconnected without a response.
+    let proxy = Proxy::new(config(&[&a], "deny-cidrs = [\"127.0.0.0/8\"]")).unwrap();
+    let addr = serve(proxy).await;
+    assert_eq!("", tcp_query(&addr, b"").await);
+}
+
+#[cfg(feature = "rustls")]
+#[tokio::test]
+async fn server_requires_tls() {
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use std::fs;
+    use tokio_scgi::tls;
+    use tokio_scgi::tls::rustls::RootCertStore;
+
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["localhost".to_string()])
+        .unwrap()
+        .signed_by(&key, &ca)
+        .unwrap();
+    let dir = std::env::temp_dir().join(format!("tokio-scgi-proxy-tls-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("cert.pem"), cert.pem()).unwrap();
+    fs::write(dir.join("key.pem"), key.serialize_pem()).unwrap();
+
+    let a = backend("a", false).await;
+    let tls_config = format!(
+        "[tls]\ncert = \"{}\"\nkey = \"{}\"",
+        dir.join("cert.pem").display(),
+        dir.join("key.pem").display()
+    );
+    let addr = serve(Proxy::new(config(&[&a], &tls_config)).unwrap()).await;
+
+    let mut roots = RootCertStore::empty();
+    roots.add(ca.der().clone()).unwrap();
+    let conn = tls::connect(&addr, "localhost", tls::client_config(roots, None).unwrap())
+        .await
+        .unwrap();
+    let mut client = Framed::new(conn, SCGICodec::new().required_headers());
+    client
+        .send(SCGIRequest::Request(Vec::new(), BytesMut::new()))
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    while let Some(Ok(chunk)) = client.next().await {
+        response.extend_from_slice(&chunk);
+    }
+    assert!(response.ends_with(b"\r\n\r\na"), "{:?}", response);
+
+    // Plaintext clients don't get a response from the backend.
+    assert!(!tcp_query(&addr, b"").await.ends_with("\r\n\r\na"));
+
+    // Missing or invalid files are reported up front.
+    fs::write(dir.join("key.pem"), "not a key").unwrap();
+    let e = Proxy::new(config(&[&a], &tls_config)).unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, e.kind());
+    fs::remove_dir_all(&dir).unwrap();
+    let e = Proxy::new(config(&[&a], &tls_config)).unwrap_err();
+    assert_eq!(ErrorKind::NotFound, e.kind());
+}
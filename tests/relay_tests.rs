@@ -0,0 +1,126 @@
+#![deny(warnings)]
+#![cfg(feature = "runtime")]
+
+use bytes::BytesMut;
+use futures::SinkExt;
+use std::io::{Error, ErrorKind};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::Framed;
+
+use tokio_scgi::client::{self, SCGIRequest};
+use tokio_scgi::listener::PeerAddr;
+use tokio_scgi::relay::{Relay, Relayed};
+use tokio_scgi::runtime::{Request, Server};
+use tokio_scgi::server;
+
+/// Responds with the request headers, one per line, followed by the body.
+async fn echo(request: Request) -> Result<Vec<u8>, Error> {
+    let mut response = b"Status: 200 OK\r\n\r\n".to_vec();
+    for (k, v) in &request.headers {
+        response.extend_from_slice(format!("{}={}\n", k, v).as_bytes());
+    }
+    response.extend_from_slice(&request.body);
+    Ok(response)
+}
+
+fn headers(body_len: usize) -> Vec<(String, String)> {
+    vec![
+        ("CONTENT_LENGTH".to_string(), body_len.to_string()),
+        ("SCGI".to_string(), "1".to_string()),
+        ("HTTP_COOKIE".to_string(), "secret".to_string()),
+        ("HTTP_X_FORWARDED_BY".to_string(), "client".to_string()),
+        ("HTTP_X_FORWARDED_BY".to_string(), "again".to_string()),
+    ]
+}
+
+/// Relays a request sent as the provided writes to an echo backend, and returns the result along
+/// with the response received by the client.
+async fn relay(relay: &Relay, writes: Vec<SCGIRequest>) -> (Result<Relayed, Error>, Vec<u8>) {
+    let (client, proxy_client) = tokio::io::duplex(1024);
+    let (proxy_backend, backend) = tokio::io::duplex(1024);
+    let backend_server = Server::new(echo);
+    let serve_backend = backend_server.serve_connection(backend, PeerAddr::Unix(None));
+
+    let mut proxy_client = Framed::new(proxy_client, server::SCGICodec::new());
+    let mut proxy_backend = Framed::new(proxy_backend, client::SCGICodec::new());
+    // Both connections are closed once the relay is done, so that the client sees the end of the
+    // response, and the backend sees the end of any truncated request.
+    let proxy = async move { relay.relay(&mut proxy_client, &mut proxy_backend).await };
+
+    let send = async move {
+        let mut client = Framed::new(client, client::SCGICodec::new());
+        for write in writes {
+            client.send(write).await.unwrap();
+        }
+        let mut conn = client.into_inner();
+        conn.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).await.unwrap();
+        response
+    };
+    let (_, result, response) = tokio::join!(serve_backend, proxy, send);
+    (result, response)
+}
+
+#[tokio::test]
+async fn relay_rewrites_headers() {
+    let rewrite = Relay::new()
+        .remove_header("HTTP_COOKIE")
+        .set_header("HTTP_X_FORWARDED_BY", "relay")
+        .set_header("HTTP_X_RELAYED", "1")
+        .rewrite_headers(|headers| headers.retain(|(k, _)| k != "SCGI"));
+    let (result, response) = relay(
+        &rewrite,
+        vec![
+            SCGIRequest::Request(headers(11), BytesMut::from(&b"hello"[..])),
+            SCGIRequest::BodyFragment(BytesMut::from(&b" world"[..])),
+        ],
+    )
+    .await;
+
+    let expected = "Status: 200 OK\r\n\r\n\
+                    CONTENT_LENGTH=11\n\
+                    HTTP_X_FORWARDED_BY=relay\n\
+                    HTTP_X_RELAYED=1\n\
+                    hello world";
+    assert_eq!(expected, String::from_utf8(response).unwrap());
+    assert_eq!(
+        Relayed {
+            body_bytes: 11,
+            response_bytes: expected.len() as u64,
+        },
+        result.unwrap()
+    );
+}
+
+#[tokio::test]
+async fn relay_unchanged() {
+    let (result, response) = relay(
+        &Relay::new(),
+        vec![SCGIRequest::Request(
+            headers(2),
+            // Anything past CONTENT_LENGTH isn't forwarded.
+            BytesMut::from(&b"hi there"[..]),
+        )],
+    )
+    .await;
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.contains("HTTP_COOKIE=secret\n"), "{}", response);
+    assert!(response.ends_with("again\nhi"), "{}", response);
+    assert_eq!(2, result.unwrap().body_bytes);
+}
+
+#[tokio::test]
+async fn relay_truncated_body() {
+    let (result, response) = relay(
+        &Relay::new(),
+        vec![SCGIRequest::Request(
+            headers(10),
+            BytesMut::from(&b"short"[..]),
+        )],
+    )
+    .await;
+    assert_eq!(ErrorKind::UnexpectedEof, result.unwrap_err().kind());
+    // Nothing is sent back to the client.
+    assert!(response.is_empty());
+}
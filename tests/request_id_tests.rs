@@ -0,0 +1,137 @@
+#![deny(warnings)]
+#![cfg(feature = "runtime")]
+
+use bytes::BytesMut;
+use std::io::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::codec::{Decoder, Encoder};
+
+use tokio_scgi::client::{SCGICodec as ClientCodec, SCGIRequest as ClientRequest};
+use tokio_scgi::listener::PeerAddr;
+use tokio_scgi::request_id;
+use tokio_scgi::runtime::{Handler, Request, Server};
+use tokio_scgi::server::{SCGICodec as ServerCodec, SCGIRequest as ServerRequest};
+
+/// Responds with the request ID in the body.
+async fn echo_id(request: Request) -> Result<Vec<u8>, Error> {
+    let mut response = b"Content-Type: text/plain\r\n\r\n".to_vec();
+    response.extend_from_slice(request.request_id.unwrap_or_default().as_bytes());
+    Ok(response)
+}
+
+/// Sends a request with the provided headers and returns the response as a string.
+async fn query<H: Handler>(server: Server<H>, headers: Vec<(String, String)>) -> String {
+    let mut encoded = BytesMut::new();
+    ClientCodec::new()
+        .encode(
+            ClientRequest::Request(headers, BytesMut::new()),
+            &mut encoded,
+        )
+        .unwrap();
+    let (mut client, server_conn) = tokio::io::duplex(1024);
+    let serve = tokio::spawn(async move {
+        server
+            .serve_connection(server_conn, PeerAddr::Unix(None))
+            .await
+    });
+    client.write_all(&encoded).await.unwrap();
+    let mut response = String::new();
+    client.read_to_string(&mut response).await.unwrap();
+    let _ = serve.await.unwrap();
+    response
+}
+
+fn id_header(id: &str) -> Vec<(String, String)> {
+    vec![(request_id::DEFAULT_HEADER.to_string(), id.to_string())]
+}
+
+#[tokio::test]
+async fn server_reads_or_generates_id() {
+    let server = Server::new(echo_id).request_id(request_id::DEFAULT_HEADER);
+
+    // A valid ID from the client is kept and echoed.
+    let response = query(server.clone(), id_header("abc-123")).await;
+    assert_eq!(
+        "X-Request-Id: abc-123\r\nContent-Type: text/plain\r\n\r\nabc-123",
+        response
+    );
+
+    // Missing or unsafe IDs are replaced with a generated one.
+    for headers in [Vec::new(), id_header("bad id\r\nSet-Cookie: x")] {
+        let response = query(server.clone(), headers).await;
+        let (head, id) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(32, id.len(), "{}", response);
+        assert_eq!(
+            format!("X-Request-Id: {}\r\nContent-Type: text/plain", id),
+            head
+        );
+    }
+
+    // Without request IDs enabled, the response is untouched.
+    let response = query(Server::new(echo_id), id_header("abc-123")).await;
+    assert_eq!("Content-Type: text/plain\r\n\r\n", response);
+}
+
+#[tokio::test]
+async fn response_header_placement() {
+    let http = Server::new(|_request: Request| async {
+        Ok(b"HTTP/1.1 200 OK\nContent-Type: text/plain\n\nok".to_vec())
+    })
+    .request_id(request_id::DEFAULT_HEADER);
+    assert_eq!(
+        "HTTP/1.1 200 OK\nX-Request-Id: abc\nContent-Type: text/plain\n\nok",
+        query(http, id_header("abc")).await
+    );
+
+    // The handler's own ID takes precedence.
+    let own = Server::new(|_request: Request| async {
+        Ok(b"Status: 200 OK\r\nx-request-id: mine\r\n\r\nok".to_vec())
+    })
+    .request_id(request_id::DEFAULT_HEADER);
+    assert_eq!(
+        "Status: 200 OK\r\nx-request-id: mine\r\n\r\nok",
+        query(own, id_header("abc")).await
+    );
+}
+
+/// Encodes a request with the client codec and returns the headers decoded by the server codec.
+fn roundtrip_headers(
+    mut codec: ClientCodec,
+    headers: Vec<(String, String)>,
+) -> Vec<(String, String)> {
+    let mut buf = BytesMut::new();
+    codec
+        .encode(ClientRequest::Request(headers, BytesMut::new()), &mut buf)
+        .unwrap();
+    match ServerCodec::new().decode(&mut buf).unwrap() {
+        Some(ServerRequest::Request(headers, _)) => headers,
+        other => panic!("Unexpected decode result: {:?}", other),
+    }
+}
+
+#[test]
+fn client_adds_missing_id() {
+    let codec = ClientCodec::with_request_id(request_id::DEFAULT_HEADER);
+    let headers = roundtrip_headers(
+        codec.clone(),
+        vec![("CONTENT_LENGTH".to_string(), "0".to_string())],
+    );
+    assert_eq!(2, headers.len());
+    assert_eq!(request_id::DEFAULT_HEADER, headers[1].0);
+    assert!(request_id::is_valid(&headers[1].1));
+
+    // An existing ID is passed through, so that IDs follow requests across hops.
+    assert_eq!(id_header("abc"), roundtrip_headers(codec, id_header("abc")));
+    assert!(roundtrip_headers(ClientCodec::new(), Vec::new()).is_empty());
+}
+
+#[test]
+fn generated_ids_are_unique_and_valid() {
+    let first = request_id::generate();
+    let second = request_id::generate();
+    assert_ne!(first, second);
+    assert!(request_id::is_valid(&first));
+    assert!(!request_id::is_valid(""));
+    assert!(!request_id::is_valid("has space"));
+    assert!(!request_id::is_valid(&"x".repeat(129)));
+}
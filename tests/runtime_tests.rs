@@ -0,0 +1,481 @@
+#![deny(warnings)]
+#![cfg(feature = "runtime")]
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use std::env;
+use std::fs;
+use std::io::Error;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::PathBuf;
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio_util::codec::Framed;
+
+use tokio_scgi::client::{SCGICodec as ClientCodec, SCGIRequest as ClientRequest};
+use tokio_scgi::listener::{Cidr, Listener, LISTEN_FD_ENV};
+use tokio_scgi::runtime::{Overload, Request, Server, Timeout};
+
+fn sock_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("tokio-scgi-{}-{}.sock", name, process::id()))
+}
+
+async fn echo_body(request: Request) -> Result<Vec<u8>, Error> {
+    Ok(request.body.to_vec())
+}
+
+/// Sends a request with the body split across two writes, and returns the full response. The
+/// response is empty if the server disconnected without responding.
+async fn query(path: &PathBuf, body: &[u8]) -> Vec<u8> {
+    query_conn(UnixStream::connect(path).await.unwrap(), body).await
+}
+
+async fn query_conn<C>(conn: C, body: &[u8]) -> Vec<u8>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(conn, ClientCodec::new());
+    let headers = vec![("CONTENT_LENGTH".to_string(), body.len().to_string())];
+    let (first, second) = body.split_at(body.len() / 2);
+    if framed
+        .send(ClientRequest::Request(headers, BytesMut::from(first)))
+        .await
+        .is_err()
+        || framed
+            .send(ClientRequest::BodyFragment(BytesMut::from(second)))
+            .await
+            .is_err()
+    {
+        return Vec::new();
+    }
+    let mut response = Vec::new();
+    while let Some(Ok(chunk)) = framed.next().await {
+        if chunk.is_empty() {
+            break;
+        }
+        response.extend_from_slice(&chunk);
+    }
+    response
+}
+
+#[tokio::test]
+async fn serve_collects_fragmented_body() {
+    let path = sock_path("fragmented");
+    let listener = Listener::bind_unix(&path).unwrap();
+    let (stop_tx, stop_rx) = oneshot::channel::<()>();
+    let serve = tokio::spawn(async move {
+        Server::new(echo_body)
+            .serve_with_shutdown(&listener, async {
+                let _ = stop_rx.await;
+            })
+            .await
+    });
+
+    assert_eq!(b"hello world".to_vec(), query(&path, b"hello world").await);
+
+    stop_tx.send(()).unwrap();
+    serve.await.unwrap().unwrap();
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn bind_unix_with_mode() {
+    let path = sock_path("mode");
+    let _old = Listener::bind_unix(&path).unwrap();
+    let _new = Listener::bind_unix_with_mode(&path, 0o600).unwrap();
+    assert_eq!(0o600, fs::metadata(&path).unwrap().mode() & 0o777);
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn bind_unix_replaces_existing_socket() {
+    let path = sock_path("replace");
+    let _old = Listener::bind_unix(&path).unwrap();
+    // The path should be replaced in place rather than failing with AddrInUse.
+    let new = Listener::bind_unix(&path).unwrap();
+    assert!(path.exists());
+    let serve = tokio::spawn(async move { Server::new(echo_body).serve(&new).await });
+
+    assert_eq!(b"replaced".to_vec(), query(&path, b"replaced").await);
+
+    serve.abort();
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn handoff_over_control_socket() {
+    let path = sock_path("handoff");
+    let old = Listener::bind_unix(&path).unwrap();
+    let (old_control, new_control) = StdUnixStream::pair().unwrap();
+    old.send_to(&old_control).unwrap();
+    let new = Listener::recv_from(&new_control).unwrap();
+
+    // Queue a connection before anything is accepting, then close the old listener.
+    let pending = tokio::spawn({
+        let path = path.clone();
+        async move { query(&path, b"queued").await }
+    });
+    drop(old);
+
+    // The queued connection should be picked up by the new listener.
+    let serve = tokio::spawn(async move { Server::new(echo_body).serve(&new).await });
+    assert_eq!(b"queued".to_vec(), pending.await.unwrap());
+
+    serve.abort();
+    fs::remove_file(&path).unwrap();
+}
+
+/// Sends `count` copies of the socket's own fd in a single message.
+fn send_fds(control: &StdUnixStream, count: usize) {
+    let fds = vec![control.as_raw_fd(); count];
+    let size = std::mem::size_of_val(&fds[..]) as u32;
+    let mut payload = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: payload.as_mut_ptr() as *mut libc::c_void,
+        iov_len: payload.len(),
+    };
+    let mut cmsg_buf = [0u64; 16];
+    unsafe {
+        let mut msg: libc::msghdr = std::mem::zeroed();
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = libc::CMSG_SPACE(size) as _;
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size) as _;
+        std::ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, count);
+        assert_eq!(1, libc::sendmsg(control.as_raw_fd(), &msg, 0));
+    }
+}
+
+#[tokio::test]
+async fn handoff_rejects_extra_fds() {
+    let (old_control, new_control) = StdUnixStream::pair().unwrap();
+    // Two fds fit in the space for one on 64-bit platforms, more are truncated by the kernel.
+    for count in &[2, 8] {
+        send_fds(&old_control, *count);
+        let err = Listener::recv_from(&new_control).unwrap_err();
+        assert_eq!(std::io::ErrorKind::InvalidData, err.kind(), "{}", err);
+    }
+}
+
+#[tokio::test]
+async fn handoff_from_env() {
+    let std_listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = std_listener.local_addr().unwrap();
+    let closed = std_listener.try_clone().unwrap().into_raw_fd();
+    unsafe { libc::close(closed) };
+
+    for invalid in ["not-a-number", "-1", &closed.to_string()] {
+        env::set_var(LISTEN_FD_ENV, invalid);
+        assert!(Listener::from_env().is_err(), "{}", invalid);
+        assert!(env::var_os(LISTEN_FD_ENV).is_none());
+    }
+
+    let fd = std_listener.into_raw_fd();
+    env::set_var(LISTEN_FD_ENV, fd.to_string());
+    let listener = Listener::from_env().unwrap().unwrap();
+    // Not passed on to child processes.
+    assert!(env::var_os(LISTEN_FD_ENV).is_none());
+    assert_ne!(
+        0,
+        unsafe { libc::fcntl(fd, libc::F_GETFD) } & libc::FD_CLOEXEC
+    );
+    // The listener can only be taken once.
+    assert!(Listener::from_env().unwrap().is_none());
+    env::set_var(LISTEN_FD_ENV, fd.to_string());
+    assert!(Listener::from_env().is_err());
+    assert!(env::var_os(LISTEN_FD_ENV).is_none());
+
+    let serve = tokio::spawn(async move { Server::new(echo_body).serve(&listener).await });
+    let conn = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(b"inherited".to_vec(), query_conn(conn, b"inherited").await);
+    serve.abort();
+}
+
+async fn echo_uid(request: Request) -> Result<Vec<u8>, Error> {
+    Ok(request.credentials.unwrap().uid.to_string().into_bytes())
+}
+
+#[tokio::test]
+async fn peer_credentials_allowlist() {
+    let path = sock_path("credentials");
+    let listener = Listener::bind_unix(&path).unwrap();
+    // The socket file is owned by our own uid, which is also the uid of the client.
+    let uid = fs::metadata(&path).unwrap().uid();
+
+    let allowed = Server::new(echo_uid).allow_uid(uid);
+    let serve = tokio::spawn(async move { allowed.serve(&listener).await });
+    assert_eq!(uid.to_string().into_bytes(), query(&path, b"").await);
+    serve.abort();
+
+    // Clients which aren't allowed are disconnected without a response.
+    let listener = Listener::bind_unix(&path).unwrap();
+    let denied = Server::new(echo_uid).allow_uid(uid + 1);
+    let serve = tokio::spawn(async move { denied.serve(&listener).await });
+    assert!(query(&path, b"").await.is_empty());
+    serve.abort();
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn cidr_contains() {
+    let v4: Cidr = "10.1.0.0/16".parse().unwrap();
+    assert!(v4.contains(&"10.1.2.3".parse().unwrap()));
+    assert!(!v4.contains(&"10.2.0.1".parse().unwrap()));
+    // IPv4-mapped addresses from dual-stack listeners should match too.
+    assert!(v4.contains(&"::ffff:10.1.2.3".parse().unwrap()));
+    assert!(!v4.contains(&"::1".parse().unwrap()));
+
+    let v6: Cidr = "fd00::/8".parse().unwrap();
+    assert!(v6.contains(&"fd12::1".parse().unwrap()));
+    assert!(!v6.contains(&"fe80::1".parse().unwrap()));
+
+    let single: Cidr = "127.0.0.1".parse().unwrap();
+    assert_eq!("127.0.0.1/32", single.to_string());
+    assert!(!single.contains(&"127.0.0.2".parse().unwrap()));
+
+    let any: Cidr = "0.0.0.0/0".parse().unwrap();
+    assert!(any.contains(&"192.0.2.1".parse().unwrap()));
+
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("10.0.0.0/".parse::<Cidr>().is_err());
+    assert!("localhost".parse::<Cidr>().is_err());
+}
+
+#[tokio::test]
+async fn tcp_address_allowlist() {
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let addr = match &listener {
+        Listener::Tcp(tcp) => tcp.local_addr().unwrap(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    let server = Server::new(echo_body)
+        .allow_cidr("127.0.0.0/8".parse().unwrap())
+        .deny_cidr("127.0.0.1".parse().unwrap());
+    let counter = server.clone();
+    let serve = tokio::spawn(async move { server.serve(&listener).await });
+
+    // Denied ranges take precedence over allowed ranges.
+    let conn = TcpStream::connect(addr).await.unwrap();
+    assert!(query_conn(conn, b"denied").await.is_empty());
+    assert_eq!(1, counter.rejected_connections());
+    serve.abort();
+
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let addr = match &listener {
+        Listener::Tcp(tcp) => tcp.local_addr().unwrap(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    let server = Server::new(echo_body).allow_cidr("127.0.0.0/8".parse().unwrap());
+    let serve = tokio::spawn(async move { server.serve(&listener).await });
+    let conn = TcpStream::connect(addr).await.unwrap();
+    assert_eq!(b"allowed".to_vec(), query_conn(conn, b"allowed").await);
+    serve.abort();
+}
+
+#[tokio::test]
+async fn slow_clients_time_out() {
+    let path = sock_path("timeouts");
+    let listener = Listener::bind_unix(&path).unwrap();
+    let server = Server::new(echo_body)
+        .header_timeout(Duration::from_millis(100))
+        .body_idle_timeout(Duration::from_millis(100));
+
+    // A client which never sends its headers.
+    let mut idle = UnixStream::connect(&path).await.unwrap();
+    let (conn, peer) = listener.accept().await.unwrap();
+    let err = server.serve_accepted(conn, peer).await.unwrap_err();
+    assert_eq!(
+        Some(&Timeout::HeaderRead),
+        err.get_ref().unwrap().downcast_ref::<Timeout>()
+    );
+    let mut response = String::new();
+    idle.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("Status: 408"), "{}", response);
+
+    // A client which sends its headers but stops partway through the body.
+    let mut stalled = UnixStream::connect(&path).await.unwrap();
+    stalled
+        .write_all(b"18:CONTENT_LENGTH\x0010\x00,hello")
+        .await
+        .unwrap();
+    let (conn, peer) = listener.accept().await.unwrap();
+    let err = server.serve_accepted(conn, peer).await.unwrap_err();
+    assert_eq!(
+        Some(&Timeout::BodyIdle),
+        err.get_ref().unwrap().downcast_ref::<Timeout>()
+    );
+
+    // A client which keeps sending its body within the idle limit, but takes too long overall.
+    let server = server.request_timeout(Duration::from_millis(300));
+    let mut trickle = UnixStream::connect(&path).await.unwrap();
+    let trickling = tokio::spawn(async move {
+        trickle
+            .write_all(b"19:CONTENT_LENGTH\x00100\x00,")
+            .await
+            .unwrap();
+        // Stops once the server gives up and closes the connection.
+        while trickle.write_all(b"x").await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+    let (conn, peer) = listener.accept().await.unwrap();
+    let started = Instant::now();
+    let err = server.serve_accepted(conn, peer).await.unwrap_err();
+    assert_eq!(
+        Some(&Timeout::Request),
+        err.get_ref().unwrap().downcast_ref::<Timeout>()
+    );
+    assert!(started.elapsed() >= Duration::from_millis(250));
+    trickling.abort();
+
+    // A client which sends its request but never reads the response.
+    let server = Server::new(|_: Request| async { Ok::<_, Error>(vec![b'x'; 16 * 1024 * 1024]) })
+        .write_timeout(Duration::from_millis(100));
+    let mut unread = UnixStream::connect(&path).await.unwrap();
+    unread
+        .write_all(b"17:CONTENT_LENGTH\x000\x00,")
+        .await
+        .unwrap();
+    let (conn, peer) = listener.accept().await.unwrap();
+    let err = server.serve_accepted(conn, peer).await.unwrap_err();
+    assert_eq!(
+        Some(&Timeout::Write),
+        err.get_ref().unwrap().downcast_ref::<Timeout>()
+    );
+    drop(unread);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn body_size_limit_and_truncation() {
+    let path = sock_path("body-size");
+    let listener = Listener::bind_unix(&path).unwrap();
+    let server = Server::new(echo_body).max_body_size(10);
+    let serve = tokio::spawn(async move { server.serve(&listener).await });
+
+    assert_eq!(b"0123456789".to_vec(), query(&path, b"0123456789").await);
+    let response = String::from_utf8(query(&path, b"0123456789a").await).unwrap();
+    assert!(
+        response.starts_with("Status: 413 Payload Too Large\r\n"),
+        "{}",
+        response
+    );
+
+    // Bytes past CONTENT_LENGTH aren't part of the body, whether they arrive with the headers or
+    // in a later fragment.
+    for writes in [
+        &[&b"17:CONTENT_LENGTH\x005\x00,helloworld"[..]][..],
+        &[b"17:CONTENT_LENGTH\x005\x00,hel", b"loworld"],
+    ] {
+        let mut conn = UnixStream::connect(&path).await.unwrap();
+        for write in writes {
+            conn.write_all(write).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response).await.unwrap();
+        assert_eq!(b"hello".to_vec(), response);
+    }
+
+    serve.abort();
+    fs::remove_file(&path).unwrap();
+}
+
+/// Returns a server whose handler reports each request on the returned channel, then waits for a
+/// permit from the returned semaphore before echoing the body.
+fn gated_server(
+    overload: Overload,
+) -> (
+    Server<impl tokio_scgi::runtime::Handler>,
+    mpsc::UnboundedReceiver<()>,
+    Arc<Semaphore>,
+) {
+    let (entered_tx, entered_rx) = mpsc::unbounded_channel();
+    let gate = Arc::new(Semaphore::new(0));
+    let handler_gate = gate.clone();
+    let server = Server::new(move |request: Request| {
+        let entered_tx = entered_tx.clone();
+        let gate = handler_gate.clone();
+        async move {
+            entered_tx.send(()).unwrap();
+            gate.acquire().await.unwrap().forget();
+            Ok(request.body.to_vec())
+        }
+    })
+    .max_concurrency(1, overload);
+    (server, entered_rx, gate)
+}
+
+#[tokio::test]
+async fn overload_sheds_with_retry_after() {
+    let path = sock_path("shed");
+    let listener = Listener::bind_unix(&path).unwrap();
+    let (server, mut entered, gate) = gated_server(Overload::Shed {
+        retry_after: Duration::from_millis(1500),
+    });
+    let counter = server.clone();
+    let serve = tokio::spawn(async move { server.serve(&listener).await });
+
+    let first = tokio::spawn({
+        let path = path.clone();
+        async move { query(&path, b"first").await }
+    });
+    entered.recv().await.unwrap();
+
+    // The first request is still being handled, so the second is turned away.
+    let response = String::from_utf8(query(&path, b"second").await).unwrap();
+    assert!(
+        response.starts_with("Status: 503 Service Unavailable\r\nRetry-After: 2\r\n"),
+        "{}",
+        response
+    );
+    assert_eq!(1, counter.shed_connections());
+
+    gate.add_permits(1);
+    assert_eq!(b"first".to_vec(), first.await.unwrap());
+
+    serve.abort();
+    fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn overload_queues_connections() {
+    let path = sock_path("queue");
+    let listener = Listener::bind_unix(&path).unwrap();
+    let (server, mut entered, gate) = gated_server(Overload::Queue { max_queued: 1 });
+    let serve = tokio::spawn(async move { server.serve(&listener).await });
+
+    let first = tokio::spawn({
+        let path = path.clone();
+        async move { query(&path, b"first").await }
+    });
+    entered.recv().await.unwrap();
+    let second = tokio::spawn({
+        let path = path.clone();
+        async move { query(&path, b"second").await }
+    });
+
+    // The second request waits for the first to finish rather than being handled concurrently.
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(entered.try_recv().is_err());
+    gate.add_permits(1);
+    assert_eq!(b"first".to_vec(), first.await.unwrap());
+    entered.recv().await.unwrap();
+    gate.add_permits(1);
+    assert_eq!(b"second".to_vec(), second.await.unwrap());
+
+    serve.abort();
+    fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,161 @@
+#![deny(warnings)]
+#![cfg(feature = "runtime")]
+
+use std::env;
+use std::fs;
+use std::io::Error;
+use std::process::{self, Output};
+use std::sync::{Arc, Mutex};
+use tokio::process::Command;
+
+use tokio_scgi::listener::Listener;
+use tokio_scgi::runtime::{Request, Server};
+
+/// The URI, body length and header count of each request a backend received.
+type Seen = Arc<Mutex<Vec<(String, usize, usize)>>>;
+
+/// Serves requests with a 404 for URIs under /missing, and an empty 200 otherwise.
+fn serve(listener: Listener) -> Seen {
+    let seen = Seen::default();
+    let server_seen = seen.clone();
+    let server = Server::new(move |request: Request| {
+        let seen = server_seen.clone();
+        async move {
+            let uri = request.header("REQUEST_URI").unwrap_or("").to_string();
+            let status = if uri.starts_with("/missing") {
+                "404 Not Found"
+            } else {
+                "200 OK"
+            };
+            seen.lock()
+                .unwrap()
+                .push((uri, request.body.len(), request.headers.len()));
+            Ok::<_, Error>(format!("Status: {}\r\n\r\n", status).into_bytes())
+        }
+    });
+    tokio::spawn(async move { server.serve(&listener).await });
+    seen
+}
+
+async fn tcp_backend() -> (String, Seen) {
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let addr = match &listener {
+        Listener::Tcp(listener) => listener.local_addr().unwrap(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    (addr.to_string(), serve(listener))
+}
+
+async fn scgi_bench(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_scgi-bench"))
+        .args(args)
+        .output()
+        .await
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn request_count() {
+    let (backend, seen) = tcp_backend().await;
+    let output = scgi_bench(&[
+        &backend,
+        "-n",
+        "50",
+        "-c",
+        "4",
+        "-t",
+        "2",
+        "-u",
+        "/item/{n}",
+        "-u",
+        "/missing?r={rand}",
+        "-b",
+        "10-20",
+        "--headers",
+        "3",
+        "-H",
+        "User-Agent: bench",
+    ])
+    .await;
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("50 completed, 0 failed"), "{}", stdout);
+    assert!(
+        stdout.contains("Statuses:    200: 25, 404: 25\n"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("Latency:     min "), "{}", stdout);
+    assert!(stdout.contains("Conn errors: 0\n"), "{}", stdout);
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(50, seen.len());
+    let mut items = seen
+        .iter()
+        .filter_map(|(uri, _, _)| uri.strip_prefix("/item/"))
+        .map(|n| n.parse::<u64>().unwrap())
+        .collect::<Vec<_>>();
+    items.sort_unstable();
+    assert_eq!((0..50).step_by(2).collect::<Vec<_>>(), items);
+    assert!(seen
+        .iter()
+        .any(|(uri, _, _)| uri.starts_with("/missing?r=")));
+    // Seven standard headers, the User-Agent and three generated headers.
+    for (_, body_len, headers) in seen.iter() {
+        assert!((10..=20).contains(body_len), "{}", body_len);
+        assert_eq!(11, *headers);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn duration_over_unix_socket() {
+    let sock = env::temp_dir().join(format!("tokio-scgi-bench-{}.sock", process::id()));
+    let seen = serve(Listener::bind_unix(&sock).unwrap());
+    let output = scgi_bench(&["--unix", sock.to_str().unwrap(), "-d", "300ms", "-c", "2"]).await;
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let completed = seen.lock().unwrap().len();
+    assert!(completed > 0);
+    // Requests cut off by the end of the run aren't counted.
+    let counted = stdout
+        .split("Requests:    ")
+        .nth(1)
+        .and_then(|rest| rest.split(' ').next())
+        .unwrap()
+        .parse::<usize>()
+        .unwrap();
+    assert!(counted <= completed, "{}", stdout);
+    assert!(stdout.contains("0 failed"), "{}", stdout);
+    fs::remove_file(&sock).unwrap();
+}
+
+#[tokio::test]
+async fn connection_errors() {
+    let refused = {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    };
+    let output = scgi_bench(&[&refused, "-n", "6", "-c", "3"]).await;
+    assert!(output.status.success(), "{:?}", output);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("0 completed, 6 failed"), "{}", stdout);
+    assert!(stdout.contains("Conn errors: 6\n"), "{}", stdout);
+    assert!(stdout.contains("6  connect: "), "{}", stdout);
+    assert!(!stdout.contains("Latency"), "{}", stdout);
+}
+
+#[tokio::test]
+async fn invalid_arguments() {
+    for args in [
+        &[][..],
+        &["127.0.0.1:1", "127.0.0.1:2"],
+        &["127.0.0.1:1", "--bogus"],
+        &["127.0.0.1:1", "-c", "0"],
+        &["127.0.0.1:1", "-b", "20-10"],
+        &["127.0.0.1:1", "-d", "5 parsecs"],
+        &["127.0.0.1:1", "-u", "no-slash"],
+    ] {
+        let output = scgi_bench(args).await;
+        assert!(!output.status.success(), "{:?}", args);
+    }
+}
@@ -0,0 +1,177 @@
+#![deny(warnings)]
+#![cfg(feature = "runtime")]
+
+use std::env;
+use std::fs;
+use std::io::Error;
+use std::process::{self, Output};
+use tokio::process::Command;
+
+use tokio_scgi::listener::Listener;
+use tokio_scgi::runtime::{Request, Server};
+
+/// Serves requests with a response listing the request headers, one per line, followed by the
+/// body.
+fn serve(listener: Listener) {
+    let server = Server::new(|request: Request| async move {
+        let mut response = b"Status: 201 Created\r\nX-Echo:  yes\r\n\r\n".to_vec();
+        for (k, v) in &request.headers {
+            response.extend_from_slice(format!("{}={}\n", k, v).as_bytes());
+        }
+        response.extend_from_slice(&request.body);
+        Ok::<_, Error>(response)
+    });
+    tokio::spawn(async move { server.serve(&listener).await });
+}
+
+async fn tcp_backend() -> String {
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let addr = match &listener {
+        Listener::Tcp(listener) => listener.local_addr().unwrap(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    serve(listener);
+    addr.to_string()
+}
+
+async fn scgi_curl(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_scgi-curl"))
+        .args(args)
+        .output()
+        .await
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+    output
+}
+
+#[tokio::test]
+async fn request_headers() {
+    let backend = tcp_backend().await;
+    let output = scgi_curl(&[
+        &backend,
+        "https://example.com/search?q=scgi",
+        "-H",
+        "User-Agent: test",
+        "-H",
+        "Accept: text/html",
+        "-H",
+        "accept: text/plain",
+        "-H",
+        "Content-Type: text/plain",
+        "-e",
+        "REMOTE_ADDR=10.1.2.3",
+        "-e",
+        "SERVER_PORT=8443",
+        "-d",
+        "a=1",
+        "-d",
+        "b=2",
+    ])
+    .await;
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let expected = "Status: 201 Created\n\
+                    X-Echo: yes\n\
+                    \n\
+                    CONTENT_LENGTH=7\n\
+                    SCGI=1\n\
+                    REQUEST_METHOD=POST\n\
+                    SERVER_PROTOCOL=HTTP/1.1\n\
+                    REQUEST_URI=/search?q=scgi\n\
+                    SCRIPT_NAME=\n\
+                    PATH_INFO=/search\n\
+                    QUERY_STRING=q=scgi\n\
+                    REQUEST_SCHEME=https\n\
+                    HTTPS=on\n\
+                    SERVER_NAME=example.com\n\
+                    SERVER_PORT=8443\n\
+                    HTTP_HOST=example.com\n\
+                    CONTENT_TYPE=text/plain\n\
+                    HTTP_USER_AGENT=test\n\
+                    HTTP_ACCEPT=text/html, text/plain\n\
+                    REMOTE_ADDR=10.1.2.3\n\
+                    a=1&b=2";
+    assert_eq!(expected, stdout);
+}
+
+#[tokio::test]
+async fn unix_socket_with_file_body() {
+    let dir = env::temp_dir();
+    let sock = dir.join(format!("tokio-scgi-curl-{}.sock", process::id()));
+    let data = dir.join(format!("tokio-scgi-curl-{}.data", process::id()));
+    serve(Listener::bind_unix(&sock).unwrap());
+    fs::write(&data, b"line one\r\nline two\n").unwrap();
+    let data_arg = format!("@{}", data.display());
+
+    let output = scgi_curl(&[
+        "--unix",
+        sock.to_str().unwrap(),
+        "-X",
+        "PUT",
+        "--data-binary",
+        &data_arg,
+        "/upload",
+    ])
+    .await;
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("\nCONTENT_LENGTH=19\n"), "{}", stdout);
+    assert!(stdout.contains("\nREQUEST_METHOD=PUT\n"), "{}", stdout);
+    assert!(!stdout.contains("CONTENT_TYPE"), "{}", stdout);
+    assert!(stdout.ends_with("line one\r\nline two\n"), "{}", stdout);
+
+    // Form data from files loses its line breaks.
+    let output = scgi_curl(&["--unix", sock.to_str().unwrap(), "-d", &data_arg, "/"]).await;
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.ends_with("\nline oneline two"), "{}", stdout);
+    fs::remove_file(&sock).unwrap();
+    fs::remove_file(&data).unwrap();
+}
+
+#[tokio::test]
+async fn raw_and_verbose() {
+    let backend = tcp_backend().await;
+    let output = scgi_curl(&["--raw", &backend, "/", "-e", "CONTENT_LENGTH=0"]).await;
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("121:CONTENT_LENGTH\x000\x00SCGI\x001\x00"),
+        "{:?}",
+        stderr
+    );
+    assert!(stderr.ends_with("\x00,"), "{:?}", stderr);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("Status: 201 Created\r\nX-Echo:  yes\r\n\r\n"),
+        "{}",
+        stdout
+    );
+
+    let output = scgi_curl(&["-v", &backend, "/path", "--data-binary", "hi"]).await;
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains(&format!("* Connecting to {}\n", backend)),
+        "{}",
+        stderr
+    );
+    assert!(
+        stderr.contains("> 130:CONTENT_LENGTH\\02\\0SCGI\\01\\0REQUEST_METHOD\\0POST\\0"),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("> (2 body bytes)\n"), "{}", stderr);
+}
+
+#[tokio::test]
+async fn invalid_arguments() {
+    for args in [
+        &["/"][..],
+        &["127.0.0.1:1", "/", "--bogus"],
+        &["127.0.0.1:1", "/", "-H", "no colon"],
+        &["127.0.0.1:1", "no-slash"],
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_scgi-curl"))
+            .args(args)
+            .output()
+            .await
+            .unwrap();
+        assert!(!output.status.success(), "{:?}", args);
+    }
+}
@@ -0,0 +1,227 @@
+#![deny(warnings)]
+#![cfg(feature = "std")]
+
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use tokio_scgi::proto::{SCGIError, SCGIErrorKind};
+use tokio_scgi::sync::{self, Request, Server};
+
+fn sock_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("tokio-scgi-sync-{}-{}.sock", name, process::id()))
+}
+
+fn headers(body_len: usize) -> Vec<(String, String)> {
+    vec![
+        ("CONTENT_LENGTH".to_string(), body_len.to_string()),
+        ("SCGI".to_string(), "1".to_string()),
+        ("REQUEST_METHOD".to_string(), "POST".to_string()),
+    ]
+}
+
+/// Returns one byte per read, to check that requests are reassembled from any fragmentation.
+struct Bytewise<'a>(&'a [u8]);
+
+impl Read for Bytewise<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.0.is_empty() || buf.is_empty() {
+            return Ok(0);
+        }
+        buf[0] = self.0[0];
+        self.0 = &self.0[1..];
+        Ok(1)
+    }
+}
+
+fn handle(request: Request) -> Result<Vec<u8>, Error> {
+    match request.header("REQUEST_METHOD") {
+        Some("POST") => {
+            let mut response = b"Status: 200 OK\r\n\r\n".to_vec();
+            response.extend_from_slice(&request.body);
+            Ok(response)
+        }
+        Some("PANIC") => panic!("Handler panicked on purpose"),
+        _ => Err(Error::other("Unsupported method")),
+    }
+}
+
+/// Sends a request over the stream and returns the full response.
+fn query<C: Read + Write>(mut conn: C, headers: &[(String, String)], body: &[u8]) -> String {
+    sync::write_request(&mut conn, headers, body).unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn write_then_read() {
+    let body = b"hello, world";
+    let mut encoded = Vec::new();
+    sync::write_request(&mut encoded, &headers(body.len()), body).unwrap();
+    // Anything after CONTENT_LENGTH isn't part of the body.
+    encoded.extend_from_slice(b"next");
+
+    // In one read, and one byte at a time.
+    let (headers_read, mut body_reader) = sync::read_request(&encoded[..]).unwrap();
+    assert_eq!(headers(body.len()), headers_read);
+    assert_eq!(body.len() as u64, body_reader.remaining());
+    let mut body_read = Vec::new();
+    body_reader.read_to_end(&mut body_read).unwrap();
+    assert_eq!(&body[..], &body_read[..]);
+    assert_eq!(0, body_reader.remaining());
+
+    let (headers_read, mut body_reader) = sync::read_request(Bytewise(&encoded)).unwrap();
+    assert_eq!(headers(body.len()), headers_read);
+    let mut body_read = Vec::new();
+    body_reader.read_to_end(&mut body_read).unwrap();
+    assert_eq!(&body[..], &body_read[..]);
+}
+
+#[test]
+fn read_errors() {
+    // Truncated headers.
+    let e = sync::read_request(&b"24:CONTENT_LENGTH\x005"[..]).unwrap_err();
+    assert_eq!(ErrorKind::UnexpectedEof, e.kind());
+
+    // Truncated body.
+    let (_, mut body) =
+        sync::read_request(&b"24:CONTENT_LENGTH\x009\x00SCGI\x001\x00,hello"[..]).unwrap();
+    let e = body.read_to_end(&mut Vec::new()).unwrap_err();
+    assert_eq!(ErrorKind::UnexpectedEof, e.kind());
+
+    // Malformed requests wrap an SCGIError.
+    for (request, kind) in [
+        (&b"x:"[..], SCGIErrorKind::InvalidHeaderSize),
+        (
+            &b"24:CONTENT_LENGTH\x00x\x00SCGI\x001\x00,"[..],
+            SCGIErrorKind::InvalidContentLength,
+        ),
+    ] {
+        let e = sync::read_request(request).unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, e.kind());
+        assert_eq!(Some(kind), SCGIError::from_io(&e).map(SCGIError::kind));
+    }
+
+    let invalid = vec![("".to_string(), "value".to_string())];
+    let mut written = Vec::new();
+    let e = sync::write_request(&mut written, &invalid, b"").unwrap_err();
+    assert_eq!(ErrorKind::InvalidInput, e.kind());
+    assert!(written.is_empty());
+}
+
+#[test]
+fn serve_connection_responses() {
+    let server = Server::new(handle);
+    for (method, body, expected) in [
+        ("POST", &b"hello"[..], "Status: 200 OK\r\n\r\nhello"),
+        ("GET", &b""[..], "Status: 500 Internal Server Error\r\n"),
+    ] {
+        let (client, conn) = UnixStream::pair().unwrap();
+        let serving = {
+            let server = server.clone();
+            thread::spawn(move || server.serve_connection(conn, None))
+        };
+        let mut headers = headers(body.len());
+        headers[2].1 = method.to_string();
+        let response = query(client, &headers, body);
+        assert!(response.starts_with(expected), "{}", response);
+        // Handler errors aren't passed on to the client.
+        assert!(!response.contains("Unsupported method"), "{}", response);
+        assert_eq!(method == "POST", serving.join().unwrap().is_ok());
+    }
+
+    // Malformed request: Responds with 400 and returns the error.
+    let (mut client, conn) = UnixStream::pair().unwrap();
+    client.write_all(b"5x:").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let e = server.serve_connection(conn, None).unwrap_err();
+    assert_eq!(ErrorKind::InvalidData, e.kind());
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("Status: 400 Bad Request\r\n"),
+        "{}",
+        response
+    );
+
+    // Body too large: Responds with 413 without waiting for the body, and returns the error.
+    let server = server.max_body_size(5);
+    let (client, conn) = UnixStream::pair().unwrap();
+    let serving = {
+        let server = server.clone();
+        thread::spawn(move || server.serve_connection(conn, None))
+    };
+    let response = query(client, &headers(5), b"hello");
+    assert!(response.starts_with("Status: 200 OK\r\n"), "{}", response);
+    serving.join().unwrap().unwrap();
+    let (mut client, conn) = UnixStream::pair().unwrap();
+    sync::write_request(&mut client, &headers(1_000_000), b"").unwrap();
+    let e = server.serve_connection(conn, None).unwrap_err();
+    assert_eq!(
+        SCGIErrorKind::BodyTooLarge,
+        SCGIError::from_io(&e).unwrap().kind()
+    );
+    let mut response = String::new();
+    client.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("Status: 413 Payload Too Large\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn serve_unix_survives_panics() {
+    let path = sock_path("panic");
+    let _ = fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    thread::spawn(move || Server::new(handle).threads(1).serve_unix(&listener));
+
+    let mut panic_headers = headers(0);
+    panic_headers[2].1 = "PANIC".to_string();
+    assert_eq!(
+        "",
+        query(UnixStream::connect(&path).unwrap(), &panic_headers, b"")
+    );
+
+    // The only thread is still serving.
+    let response = query(UnixStream::connect(&path).unwrap(), &headers(2), b"ok");
+    assert_eq!("Status: 200 OK\r\n\r\nok", response);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn serve_tcp_concurrent_with_timeout() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        Server::new(|request: Request| {
+            let peer = request.peer.expect("TCP clients have an address");
+            Ok(format!("Status: 200 OK\r\n\r\n{}", peer.ip()).into_bytes())
+        })
+        .threads(2)
+        .read_timeout(Duration::from_millis(100))
+        .serve_tcp(&listener)
+    });
+
+    // A stalled client times out without holding up the other thread.
+    let mut stalled = TcpStream::connect(addr).unwrap();
+    stalled.write_all(b"24:CONTENT_LENGTH").unwrap();
+    let response = query(TcpStream::connect(addr).unwrap(), &headers(0), b"");
+    assert_eq!("Status: 200 OK\r\n\r\n127.0.0.1", response);
+
+    let mut response = String::new();
+    stalled.read_to_string(&mut response).unwrap();
+    assert!(
+        response.starts_with("Status: 408 Request Timeout\r\n"),
+        "{}",
+        response
+    );
+}
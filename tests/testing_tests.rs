@@ -0,0 +1,152 @@
+#![deny(warnings)]
+#![cfg(feature = "testing")]
+
+use bytes::BytesMut;
+use std::io::{Error, ErrorKind};
+use tokio_util::codec::Encoder;
+
+use tokio_scgi::client::{SCGICodec as ClientCodec, SCGIRequest as ClientRequest};
+use tokio_scgi::listener::PeerAddr;
+use tokio_scgi::request_id;
+use tokio_scgi::runtime::{Request, Server};
+use tokio_scgi::testing::TestClient;
+
+/// Responds with the request method and body, and the client's address in a header.
+async fn describe(request: Request) -> Result<Vec<u8>, Error> {
+    let mut response = format!(
+        "Content-Type: text/plain\r\nX-Peer: {}\r\n\r\n{} ",
+        request.peer,
+        request.header("REQUEST_METHOD").unwrap_or("-")
+    )
+    .into_bytes();
+    response.extend_from_slice(&request.body);
+    Ok(response)
+}
+
+fn headers(method: &str) -> Vec<(String, String)> {
+    vec![("REQUEST_METHOD".to_string(), method.to_string())]
+}
+
+#[tokio::test]
+async fn parses_response() {
+    let client = TestClient::new(describe).peer(PeerAddr::Tcp("192.0.2.1:1234".parse().unwrap()));
+    let response = client.request(headers("POST"), b"hello").await.unwrap();
+    assert_eq!(200, response.status);
+    assert_eq!(Some("text/plain"), response.header("content-type"));
+    assert_eq!(Some("192.0.2.1:1234"), response.header("X-Peer"));
+    assert_eq!(b"POST hello".to_vec(), response.body);
+    assert!(response.raw.starts_with(b"Content-Type: text/plain\r\n"));
+}
+
+#[tokio::test]
+async fn fragmented_requests_match_unfragmented() {
+    let body = b"a body which is long enough to split in several places";
+    let whole = TestClient::new(describe)
+        .request(headers("PUT"), body)
+        .await
+        .unwrap();
+
+    // Every split of the netstring length, the headers and the body, one byte at a time.
+    let bytewise = TestClient::new(describe)
+        .split_every(1)
+        .request(headers("PUT"), body)
+        .await
+        .unwrap();
+    assert_eq!(whole.raw, bytewise.raw);
+
+    // Splits around the netstring length, the ':' and the ',' between headers and body. Offsets
+    // past the end of the request are ignored.
+    let chosen = TestClient::new(describe)
+        .split_at(&[1, 2, 3, 40, 10_000])
+        .request(headers("PUT"), body)
+        .await
+        .unwrap();
+    assert_eq!(whole.raw, chosen.raw);
+}
+
+#[tokio::test]
+async fn fragments_are_read_separately() {
+    let body = b"a body which is long enough to split in several places";
+    let mut request = BytesMut::new();
+    ClientCodec::new()
+        .required_headers()
+        .encode(
+            ClientRequest::Request(headers("PUT"), body[..].into()),
+            &mut request,
+        )
+        .unwrap();
+
+    let whole = TestClient::new(describe)
+        .request(headers("PUT"), body)
+        .await
+        .unwrap();
+    assert_eq!(1, whole.reads);
+
+    // Each fragment is delivered by its own read, however quickly the server reads.
+    let bytewise = TestClient::new(describe)
+        .split_every(1)
+        .request(headers("PUT"), body)
+        .await
+        .unwrap();
+    assert_eq!(request.len(), bytewise.reads);
+
+    let chosen = TestClient::new(describe)
+        .split_at(&[1, 2, 3, 40, 10_000])
+        .request(headers("PUT"), body)
+        .await
+        .unwrap();
+    assert_eq!(5, chosen.reads);
+}
+
+#[tokio::test]
+async fn early_response_to_large_request() {
+    // The server rejects the request without reading its body, which the client never finishes
+    // sending.
+    let server = Server::new(describe).max_body_size(1024);
+    let body = vec![b'x'; 1024 * 1024];
+    let response = TestClient::with_server(server)
+        .request(headers("POST"), &body)
+        .await
+        .unwrap();
+    assert_eq!(413, response.status);
+    assert!(response.reads < 10, "{}", response.reads);
+}
+
+#[tokio::test]
+async fn server_settings_apply() {
+    let server = Server::new(describe).request_id(request_id::DEFAULT_HEADER);
+    let mut headers = headers("GET");
+    headers.push((request_id::DEFAULT_HEADER.to_string(), "abc123".to_string()));
+    let response = TestClient::with_server(server)
+        .request(headers, b"")
+        .await
+        .unwrap();
+    assert_eq!(Some("abc123"), response.header(request_id::RESPONSE_HEADER));
+}
+
+#[tokio::test]
+async fn errors_become_responses() {
+    let client =
+        TestClient::new(|_: Request| async { Err::<Vec<u8>, _>(Error::other("handler failed")) });
+    assert_eq!(500, client.request(vec![], b"").await.unwrap().status);
+
+    // A CONTENT_LENGTH which isn't a number is rejected before reaching the handler.
+    let bad_length = vec![("CONTENT_LENGTH".to_string(), "five".to_string())];
+    let response = TestClient::new(describe)
+        .request(bad_length, b"")
+        .await
+        .unwrap();
+    assert_eq!(400, response.status);
+}
+
+#[tokio::test]
+async fn truncated_request() {
+    // The body is shorter than CONTENT_LENGTH, so the server sees EOF while waiting for the rest,
+    // and gives up without responding.
+    let headers = vec![("CONTENT_LENGTH".to_string(), "100".to_string())];
+    let err = TestClient::new(describe)
+        .request(headers, b"hello")
+        .await
+        .unwrap_err();
+    assert_eq!(ErrorKind::UnexpectedEof, err.kind(), "{}", err);
+}
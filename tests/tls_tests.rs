@@ -0,0 +1,122 @@
+#![deny(warnings)]
+#![cfg(feature = "rustls")]
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+use std::io::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::Framed;
+
+use tokio_scgi::client::{SCGICodec as ClientCodec, SCGIRequest as ClientRequest};
+use tokio_scgi::listener::Listener;
+use tokio_scgi::runtime::{Request, Server};
+use tokio_scgi::tls;
+use tokio_scgi::tls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use tokio_scgi::tls::rustls::RootCertStore;
+
+struct Certs {
+    roots: RootCertStore,
+    server: (CertificateDer<'static>, PrivateKeyDer<'static>),
+    client: (CertificateDer<'static>, PrivateKeyDer<'static>),
+}
+
+/// Generates a CA, and server and client certificates signed by that CA.
+fn generate_certs() -> Certs {
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+    let mut roots = RootCertStore::empty();
+    roots.add(ca.der().clone()).unwrap();
+
+    let signed = |name: &str| {
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec![name.to_string()])
+            .unwrap()
+            .signed_by(&key, &ca)
+            .unwrap();
+        (
+            cert.der().clone(),
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+        )
+    };
+    Certs {
+        roots,
+        server: signed("localhost"),
+        client: signed("frontend.example"),
+    }
+}
+
+/// Responds with the DER of the client's certificate.
+async fn echo_client_cert(request: Request) -> Result<Vec<u8>, Error> {
+    Ok(request
+        .peer_certificates
+        .first()
+        .cloned()
+        .unwrap_or_default())
+}
+
+async fn query<C>(conn: C) -> Vec<u8>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut framed = Framed::new(conn, ClientCodec::new());
+    if framed
+        .send(ClientRequest::Request(Vec::new(), BytesMut::new()))
+        .await
+        .is_err()
+    {
+        return Vec::new();
+    }
+    let mut response = Vec::new();
+    while let Some(Ok(chunk)) = framed.next().await {
+        if chunk.is_empty() {
+            break;
+        }
+        response.extend_from_slice(&chunk);
+    }
+    response
+}
+
+#[tokio::test]
+async fn mutual_tls() {
+    let certs = generate_certs();
+    let server_config = tls::server_config(
+        vec![certs.server.0.clone()],
+        certs.server.1.clone_key(),
+        Some(certs.roots.clone()),
+    )
+    .unwrap();
+
+    let listener = Listener::bind_tcp("127.0.0.1:0").await.unwrap();
+    let addr = match &listener {
+        Listener::Tcp(tcp) => tcp.local_addr().unwrap(),
+        Listener::Unix(_) => unreachable!(),
+    };
+    let server = Server::new(echo_client_cert).tls(server_config);
+    let serve = tokio::spawn(async move { server.serve(&listener).await });
+
+    // A client with a certificate signed by the CA is passed through to the handler.
+    let client_config = tls::client_config(
+        certs.roots.clone(),
+        Some((vec![certs.client.0.clone()], certs.client.1.clone_key())),
+    )
+    .unwrap();
+    let conn = tls::connect(addr, "localhost", client_config)
+        .await
+        .unwrap();
+    assert_eq!(certs.client.0.to_vec(), query(conn).await);
+
+    // A client without a certificate is rejected.
+    let client_config = tls::client_config(certs.roots.clone(), None).unwrap();
+    if let Ok(conn) = tls::connect(addr, "localhost", client_config).await {
+        assert!(query(conn).await.is_empty());
+    }
+
+    // A client which doesn't speak TLS only gets a TLS alert record back (content type 21).
+    let conn = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let response = query(conn).await;
+    assert!(response.is_empty() || response[0] == 21, "{:?}", response);
+
+    serve.abort();
+}
@@ -0,0 +1,161 @@
+#![deny(warnings)]
+#![cfg(all(feature = "runtime", feature = "tracing"))]
+
+use bytes::BytesMut;
+use futures::{SinkExt, StreamExt};
+use std::io::{Error, Write};
+use std::sync::{Arc, Mutex};
+use tokio_util::codec::{Decoder, Framed};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+
+use tokio_scgi::client::{SCGICodec as ClientCodec, SCGIRequest as ClientRequest};
+use tokio_scgi::listener::PeerAddr;
+use tokio_scgi::runtime::{Request, Server};
+use tokio_scgi::server::SCGICodec;
+
+/// Collects formatted trace output in memory.
+#[derive(Clone, Default)]
+struct Output(Arc<Mutex<Vec<u8>>>);
+
+impl Output {
+    fn take(&self) -> String {
+        String::from_utf8(std::mem::take(&mut *self.0.lock().unwrap())).unwrap()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Output {
+    type Writer = Output;
+
+    fn make_writer(&'a self) -> Output {
+        self.clone()
+    }
+}
+
+fn subscribe(output: &Output) -> tracing::subscriber::DefaultGuard {
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(LevelFilter::TRACE)
+        .with_writer(output.clone())
+        .with_ansi(false)
+        .finish();
+    tracing::subscriber::set_default(subscriber)
+}
+
+#[test]
+fn decoder_traces_state_transitions_and_errors() {
+    let output = Output::default();
+    let _guard = subscribe(&output);
+
+    let mut codec = SCGICodec::new();
+    let mut buf = BytesMut::from(&b"8:KEY\0VAL\0,"[..]);
+    codec.decode(&mut buf).unwrap().unwrap();
+    let logged = output.take();
+    assert!(logged.contains("from=HeaderSize to=Headers"), "{}", logged);
+    assert!(logged.contains("from=Headers to=Content"), "{}", logged);
+    assert!(
+        logged.contains("Decoded SCGI request headers headers=1"),
+        "{}",
+        logged
+    );
+
+    let mut codec = SCGICodec::new();
+    let mut buf = BytesMut::from(&b"01:"[..]);
+    assert!(codec.decode(&mut buf).is_err());
+    let logged = output.take();
+    assert!(logged.contains("Malformed SCGI request"), "{}", logged);
+}
+
+#[tokio::test]
+async fn connection_span_records_request() {
+    let output = Output::default();
+    let _guard = subscribe(&output);
+
+    let (client, server_conn) = tokio::io::duplex(1024);
+    let server = Server::new(|request: Request| async move { Ok(request.body.to_vec()) });
+    let serve = tokio::spawn(async move {
+        server
+            .serve_connection(server_conn, PeerAddr::Unix(None))
+            .await
+    });
+
+    let mut framed = Framed::new(client, ClientCodec::new());
+    let headers = vec![
+        ("CONTENT_LENGTH".to_string(), "5".to_string()),
+        ("REQUEST_METHOD".to_string(), "POST".to_string()),
+        ("REQUEST_URI".to_string(), "/submit".to_string()),
+    ];
+    framed
+        .send(ClientRequest::Request(
+            headers,
+            BytesMut::from(&b"hello"[..]),
+        ))
+        .await
+        .unwrap();
+    let response = framed.next().await.unwrap().unwrap();
+    assert_eq!(&b"hello"[..], &response[..]);
+    serve.await.unwrap().unwrap();
+
+    let logged = output.take();
+    assert!(
+        logged.contains("Encoded SCGI request headers=3"),
+        "{}",
+        logged
+    );
+    let finished = logged
+        .lines()
+        .find(|line| line.contains("Connection finished"))
+        .unwrap_or_else(|| panic!("{}", logged));
+    for field in &[
+        "peer=unix",
+        "method=\"POST\"",
+        "uri=\"/submit\"",
+        "header_bytes=",
+        "body_bytes=5",
+        "outcome=\"ok\"",
+    ] {
+        assert!(
+            finished.contains(field),
+            "{} missing from {}",
+            field,
+            finished
+        );
+    }
+}
+
+async fn fail(_request: Request) -> Result<Vec<u8>, Error> {
+    Err(Error::other("broken"))
+}
+
+#[tokio::test]
+async fn handler_errors_are_traced() {
+    let output = Output::default();
+    let _guard = subscribe(&output);
+
+    let (client, server_conn) = tokio::io::duplex(1024);
+    let serve = tokio::spawn(async move {
+        Server::new(fail)
+            .serve_connection(server_conn, PeerAddr::Unix(None))
+            .await
+    });
+    let mut framed = Framed::new(client, ClientCodec::new());
+    framed
+        .send(ClientRequest::Request(Vec::new(), BytesMut::new()))
+        .await
+        .unwrap();
+    assert!(serve.await.unwrap().is_err());
+
+    let logged = output.take();
+    assert!(logged.contains("Handler failed error=broken"), "{}", logged);
+    assert!(logged.contains("outcome=\"handler_error\""), "{}", logged);
+}
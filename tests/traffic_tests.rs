@@ -0,0 +1,130 @@
+#![deny(warnings)]
+#![cfg(feature = "proptest")]
+
+use bytes::BytesMut;
+use proptest::prelude::*;
+use tokio_util::codec::Decoder;
+
+use tokio_scgi::server::{SCGICodec, SCGIError, SCGIErrorKind, SCGIRequest};
+use tokio_scgi::traffic::{strategy, Mutation, NearValidRequest, Schedule, ValidRequest};
+
+type Decoded = Option<(Vec<(String, String)>, Vec<u8>)>;
+
+/// Decodes the data delivered according to the schedule, returning the headers and body, or the
+/// kind of the first error.
+fn decode(data: &[u8], schedule: &Schedule) -> Result<Decoded, SCGIErrorKind> {
+    let mut decoder = SCGICodec::new();
+    let mut buf = BytesMut::new();
+    let mut headers = None;
+    let mut body = Vec::new();
+    for chunk in schedule.chunks(data) {
+        buf.extend_from_slice(chunk);
+        loop {
+            match decoder.decode(&mut buf) {
+                Ok(Some(SCGIRequest::Request(h, b))) => {
+                    assert!(headers.is_none(), "Headers decoded twice");
+                    headers = Some(h);
+                    body.extend_from_slice(&b);
+                }
+                Ok(Some(SCGIRequest::BodyFragment(b))) => body.extend_from_slice(&b),
+                Ok(None) => break,
+                Err(e) => return Err(SCGIError::from_io(&e).unwrap().kind()),
+            }
+        }
+    }
+    Ok(headers.map(|headers| (headers, body)))
+}
+
+#[test]
+fn schedule_chunks() {
+    let schedule = Schedule(vec![2, 0, 3, 100]);
+    assert_eq!(
+        vec![&b"ab"[..], b"", b"cde", b"fg"],
+        schedule.chunks(b"abcdefg")
+    );
+    assert_eq!(vec![2, 2, 5], schedule.offsets(7));
+    assert_eq!(vec![&b"abc"[..]], Schedule::default().chunks(b"abc"));
+}
+
+#[test]
+fn mutations_of_sample() {
+    let request = ValidRequest::new(
+        vec![
+            ("REQUEST_METHOD".to_string(), "GET".to_string()),
+            ("SCGI".to_string(), "2".to_string()),
+            ("BAD\0KEY".to_string(), "".to_string()),
+        ],
+        b"hi".to_vec(),
+    );
+    // Headers which would make the request invalid are dropped.
+    assert_eq!(
+        b"43:CONTENT_LENGTH\x002\x00SCGI\x001\x00REQUEST_METHOD\x00GET\x00,hi".to_vec(),
+        request.encode()
+    );
+    assert_eq!(
+        b"043:CONTENT_LENGTH\x002\x00SCGI\x001\x00REQUEST_METHOD\x00GET\x00,hi".to_vec(),
+        Mutation::LeadingZero.apply(&request)
+    );
+    assert_eq!(
+        b"43:CONTENT_LENGTH\x002\x00SCGI\x001\x00REQUEST_METHOD\x00GET\x00.hi".to_vec(),
+        Mutation::MissingComma.apply(&request)
+    );
+    assert_eq!(
+        b"44:CONTENT_LENGTH\x002x\x00SCGI\x001\x00REQUEST_METHOD\x00GET\x00,hi".to_vec(),
+        Mutation::InvalidContentLength.apply(&request)
+    );
+    assert_eq!(
+        b"28:CONTENT_LENGTH\x002\x00SCGI\x001\x00REQUEST_METHOD\x00GET\x00,hi".to_vec(),
+        Mutation::SizeTooSmall(3).apply(&request)
+    );
+}
+
+#[test]
+fn decoder_rejects_string_past_header_size() {
+    // The declared size ends in the middle of the "REQUEST_METHOD" key.
+    let data = b"20:CONTENT_LENGTH\x000\x00REQUEST_METHOD\x00GET\x00,";
+    assert_eq!(
+        Err(SCGIErrorKind::MissingSeparator),
+        decode(data, &Schedule::default())
+    );
+}
+
+proptest! {
+    #[test]
+    fn valid_requests_decode(request in strategy::valid_request(), schedule in strategy::schedule()) {
+        let decoded = decode(&request.encode(), &schedule).unwrap().unwrap();
+        prop_assert_eq!(request.headers(), &decoded.0[..]);
+        prop_assert_eq!(request.body(), &decoded.1[..]);
+    }
+
+    #[test]
+    fn near_valid_requests_fail_as_expected(request in any::<NearValidRequest>(), schedule in any::<Schedule>()) {
+        // Whatever happens, the decoder doesn't panic or return the original request.
+        let result = decode(&request.encode(), &schedule);
+        match request.mutation.expected_error() {
+            // Only the runtime checks CONTENT_LENGTH.
+            Some(SCGIErrorKind::InvalidContentLength) => prop_assert!(result.is_ok()),
+            Some(kind) => prop_assert_eq!(Err(kind), result),
+            None => {
+                let original = (request.request.headers().to_vec(), request.request.body().to_vec());
+                prop_assert_ne!(Ok(Some(original)), result);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+#[test]
+fn arbitrary_requests_are_valid() {
+    use arbitrary::Unstructured;
+
+    let data: Vec<u8> = (0..4096u32).map(|i| (i * 7919 % 251) as u8).collect();
+    let mut u = Unstructured::new(&data);
+    while !u.is_empty() {
+        let request = u.arbitrary::<ValidRequest>().unwrap();
+        let schedule = u.arbitrary::<Schedule>().unwrap();
+        let decoded = decode(&request.encode(), &schedule).unwrap().unwrap();
+        assert_eq!(request.headers(), &decoded.0[..]);
+        assert_eq!(request.body(), &decoded.1[..]);
+    }
+}